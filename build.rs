@@ -0,0 +1,36 @@
+//! Bakes the git revision into the firmware, see `identity::GIT_HASH`, and
+//! puts `storage.x` where the linker can find it
+
+use std::{env, fs, path::PathBuf, process::Command};
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+fn main() {
+    // Building from a source tarball, without git, leaves it unset
+    if let Some(hash) = git(&["rev-parse", "--short=8", "HEAD"]) {
+        let dirty = match git(&["status", "--porcelain", "--untracked-files=no"]) {
+            Some(status) if !status.is_empty() => "-dirty",
+            _ => "",
+        };
+        println!("cargo:rustc-env=FLEET_CLOCK_GIT_HASH={}{}", hash, dirty);
+    }
+
+    // Keeps the image out of the pages reserved for storage, linked with
+    // `-Tstorage.x` in .cargo/config.toml
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::copy("storage.x", out.join("storage.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=storage.x");
+
+    // New commits and checkouts move HEAD or the index
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+}
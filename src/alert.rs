@@ -0,0 +1,196 @@
+//! CO2 alert levels
+//!
+//! Levels are raised as soon as a threshold is crossed, but only lowered
+//! once the concentration has dropped a margin below it, so a reading
+//! hovering around a threshold doesn't flap between levels.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum AlertLevel {
+    Normal,
+    Elevated,
+    High,
+    Critical,
+}
+
+impl AlertLevel {
+    const RAISED: [AlertLevel; 3] = [AlertLevel::Elevated, AlertLevel::High, AlertLevel::Critical];
+
+    /// 0 for normal, up to 3 for critical
+    pub fn severity(self) -> u8 {
+        self as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct AlertConfig {
+    /// Thresholds for the elevated, high and critical levels, in ppm.
+    /// Must be in ascending order.
+    pub thresholds_ppm: [u16; 3],
+
+    /// How far below a threshold the concentration must drop before the
+    /// level is lowered again, in ppm
+    pub hysteresis_ppm: u16,
+
+    /// Sound the buzzer (if one is fitted) while alerting
+    pub buzzer_enabled: bool,
+
+    /// Hours of the day (0..=23) during which the buzzer stays silent. The
+    /// range may wrap around midnight. Equal start and end disables quiet
+    /// hours.
+    pub quiet_start_hour: u8,
+    pub quiet_end_hour: u8,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            thresholds_ppm: [1000, 1400, 2000],
+            hysteresis_ppm: 100,
+            buzzer_enabled: false,
+            quiet_start_hour: 22,
+            quiet_end_hour: 7,
+        }
+    }
+}
+
+impl AlertConfig {
+    pub fn is_valid(&self) -> bool {
+        let [a, b, c] = self.thresholds_ppm;
+        a < b && b < c && self.quiet_start_hour < 24 && self.quiet_end_hour < 24
+    }
+
+    /// Is the given hour (0..=23) within quiet hours?
+    pub fn is_quiet(&self, hour: u8) -> bool {
+        let (start, end) = (self.quiet_start_hour, self.quiet_end_hour);
+        if start <= end {
+            (start..end).contains(&hour)
+        } else {
+            hour >= start || hour < end
+        }
+    }
+
+    fn level_at(&self, co2_ppm: u16, margin: u16) -> AlertLevel {
+        self.thresholds_ppm
+            .iter()
+            .zip(AlertLevel::RAISED.iter())
+            .rev()
+            .find(|(threshold, _)| co2_ppm >= threshold.saturating_sub(margin))
+            .map(|(_, level)| *level)
+            .unwrap_or(AlertLevel::Normal)
+    }
+}
+
+pub struct Co2Alarm {
+    level: AlertLevel,
+}
+
+impl Default for Co2Alarm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Co2Alarm {
+    pub const fn new() -> Self {
+        Self {
+            level: AlertLevel::Normal,
+        }
+    }
+
+    pub fn level(&self) -> AlertLevel {
+        self.level
+    }
+
+    /// Feed in a new reading, returning the new level if it changed
+    pub fn update(&mut self, cfg: &AlertConfig, co2_ppm: u16) -> Option<AlertLevel> {
+        let rising = cfg.level_at(co2_ppm, 0);
+        let falling = cfg.level_at(co2_ppm, cfg.hysteresis_ppm);
+
+        let new = if rising >= self.level {
+            rising
+        } else {
+            falling.min(self.level)
+        };
+
+        if new != self.level {
+            self.level = new;
+            Some(new)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rising() {
+        let cfg = AlertConfig::default();
+        let mut alarm = Co2Alarm::new();
+
+        assert_eq!(alarm.update(&cfg, 999), None);
+        assert_eq!(alarm.update(&cfg, 1000), Some(AlertLevel::Elevated));
+        assert_eq!(alarm.update(&cfg, 1399), None);
+        assert_eq!(alarm.update(&cfg, 1400), Some(AlertLevel::High));
+        assert_eq!(alarm.update(&cfg, 1999), None);
+        assert_eq!(alarm.update(&cfg, 2000), Some(AlertLevel::Critical));
+        assert_eq!(alarm.update(&cfg, 5000), None);
+        assert_eq!(alarm.level(), AlertLevel::Critical);
+
+        // Straight past the lower levels
+        let mut alarm = Co2Alarm::new();
+        assert_eq!(alarm.update(&cfg, 2500), Some(AlertLevel::Critical));
+    }
+
+    #[test]
+    fn falling() {
+        let cfg = AlertConfig::default();
+        let mut alarm = Co2Alarm::new();
+        alarm.update(&cfg, 2500);
+
+        // Inside the band below each threshold, the level holds
+        assert_eq!(alarm.update(&cfg, 1999), None);
+        assert_eq!(alarm.update(&cfg, 1900), None);
+        assert_eq!(alarm.update(&cfg, 1899), Some(AlertLevel::High));
+        assert_eq!(alarm.update(&cfg, 1300), None);
+        assert_eq!(alarm.update(&cfg, 1299), Some(AlertLevel::Elevated));
+        assert_eq!(alarm.update(&cfg, 900), None);
+        assert_eq!(alarm.update(&cfg, 899), Some(AlertLevel::Normal));
+
+        // Back up into a band doesn't raise until the threshold itself
+        assert_eq!(alarm.update(&cfg, 950), None);
+        assert_eq!(alarm.update(&cfg, 1000), Some(AlertLevel::Elevated));
+
+        // Straight down past the lower levels
+        alarm.update(&cfg, 2500);
+        assert_eq!(alarm.update(&cfg, 500), Some(AlertLevel::Normal));
+    }
+
+    #[test]
+    fn quiet_hours() {
+        // 22:00 to 07:00, over midnight
+        let cfg = AlertConfig::default();
+        let quiet: Vec<u8> = (0..24).filter(|h| cfg.is_quiet(*h)).collect();
+        assert_eq!(quiet, [0, 1, 2, 3, 4, 5, 6, 22, 23]);
+
+        let cfg = AlertConfig {
+            quiet_start_hour: 1,
+            quiet_end_hour: 5,
+            ..AlertConfig::default()
+        };
+        let quiet: Vec<u8> = (0..24).filter(|h| cfg.is_quiet(*h)).collect();
+        assert_eq!(quiet, [1, 2, 3, 4]);
+
+        // Disabled
+        let cfg = AlertConfig {
+            quiet_start_hour: 5,
+            quiet_end_hour: 5,
+            ..AlertConfig::default()
+        };
+        assert!((0..24).all(|h| !cfg.is_quiet(h)));
+    }
+}
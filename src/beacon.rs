@@ -0,0 +1,139 @@
+//! Sensor readings broadcast in BLE advertisements
+//!
+//! Scanners can collect the latest reading from every clock in range
+//! without connecting. The reading goes in the manufacturer specific data
+//! of the advertisement, all little endian:
+//!
+//! | offset | field                               |
+//! |--------|-------------------------------------|
+//! | 0..2   | company ID                          |
+//! | 2      | format version                      |
+//! | 3..5   | CO2, ppm, u16                       |
+//! | 5..7   | temperature, 0.01 degC, i16         |
+//! | 7      | relative humidity, 0.5 %, u8        |
+//! | 8..12  | uptime, minutes, u32                |
+
+use crate::{history::Sample, record::rh_to_wire};
+
+/// AD type for manufacturer specific data
+pub const AD_TYPE: u8 = 0xFF;
+
+/// Reserved by the Bluetooth SIG for testing, until we have our own
+pub const COMPANY_ID: u16 = 0xFFFF;
+
+pub const VERSION: u8 = 1;
+
+/// Length of the manufacturer specific data, company ID included
+pub const DATA_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Beacon {
+    pub co2_ppm: u16,
+    pub temp_cdeg: i16,
+    /// Relative humidity in 0.01 %, only sent to the nearest 0.5 %
+    pub rh_cpct: u16,
+    pub uptime_min: u32,
+}
+
+impl Beacon {
+    pub fn new(sample: &Sample, uptime_min: u32) -> Self {
+        Self {
+            co2_ppm: sample.co2_ppm,
+            temp_cdeg: sample.temp_cdeg,
+            rh_cpct: sample.rh_cpct,
+            uptime_min,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; DATA_LEN] {
+        let mut buf = [0; DATA_LEN];
+        buf[0..2].copy_from_slice(&COMPANY_ID.to_le_bytes());
+        buf[2] = VERSION;
+        buf[3..5].copy_from_slice(&self.co2_ppm.to_le_bytes());
+        buf[5..7].copy_from_slice(&self.temp_cdeg.to_le_bytes());
+        buf[7] = rh_to_wire(self.rh_cpct);
+        buf[8..12].copy_from_slice(&self.uptime_min.to_le_bytes());
+        buf
+    }
+
+    /// Returns `None` for other companies' data, or other versions
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != DATA_LEN
+            || u16::from_le_bytes([data[0], data[1]]) != COMPANY_ID
+            || data[2] != VERSION
+        {
+            return None;
+        }
+
+        Some(Self {
+            co2_ppm: u16::from_le_bytes([data[3], data[4]]),
+            temp_cdeg: i16::from_le_bytes([data[5], data[6]]),
+            rh_cpct: data[7] as u16 * 50,
+            uptime_min: u32::from_le_bytes([data[8], data[9], data[10], data[11]]),
+        })
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::record::quantize_rh;
+    use proptest::prelude::*;
+
+    #[test]
+    fn golden() {
+        let sample = Sample {
+            timestamp: 1_613_947_000,
+            co2_ppm: 612,
+            temp_cdeg: -1234,
+            rh_cpct: 4560,
+        };
+        let beacon = Beacon::new(&sample, 90_000);
+        let data = beacon.encode();
+        assert_eq!(
+            data,
+            [0xFF, 0xFF, 1, 0x64, 0x02, 0x2E, 0xFB, 91, 0x90, 0x5F, 0x01, 0x00]
+        );
+
+        let decoded = Beacon::decode(&data).unwrap();
+        assert_eq!(decoded.rh_cpct, 4550);
+        assert_eq!(
+            decoded,
+            Beacon {
+                rh_cpct: 4550,
+                ..beacon
+            }
+        );
+    }
+
+    #[test]
+    fn foreign_data() {
+        let data = Beacon::new(&Sample::default(), 0).encode();
+        assert!(Beacon::decode(&data).is_some());
+
+        let mut company = data;
+        company[0] = 0x59;
+        assert_eq!(Beacon::decode(&company), None);
+
+        let mut version = data;
+        version[2] = VERSION + 1;
+        assert_eq!(Beacon::decode(&version), None);
+
+        assert_eq!(Beacon::decode(&data[..DATA_LEN - 1]), None);
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(
+            co2_ppm in any::<u16>(),
+            temp_cdeg in any::<i16>(),
+            rh_cpct in 0u16..=10_000,
+            uptime_min in any::<u32>(),
+        ) {
+            let beacon = Beacon { co2_ppm, temp_cdeg, rh_cpct, uptime_min };
+            let expected = Beacon { rh_cpct: quantize_rh(rh_cpct), ..beacon };
+            prop_assert_eq!(Beacon::decode(&beacon.encode()), Some(expected));
+        }
+    }
+}
@@ -13,20 +13,30 @@ use nrf52840_hal::{
     self as hal,
     clocks::LfOscConfiguration,
     gpio::{p0::Parts as P0Parts, p1::Parts as P1Parts, Level},
-    pac::{Peripherals, SPIM0, SPIS1, TIMER2, UARTE0},
+    pac::{interrupt, Interrupt, Peripherals, SPIM0, SPIS1, TIMER2, UARTE0},
     ppi::{Parts as PpiParts, Ppi0},
     spim::{Frequency, Pins as SpimPins, Spim, MODE_0, Error as SpimError},
     spis::{Mode, Pins as SpisPins, Spis, Transfer},
     timer::{Instance as TimerInstance, Periodic, Timer},
     twim::{Frequency as TwimFreq, Instance as TwimInstance, Pins as TwimPins, Twim},
     uarte::{Baudrate, Parity, Pins},
-    wdt::{count::One as OneDog, Watchdog},
 };
 use sensor_scd30::Scd30;
 use shared_bus::BusManagerSimple;
 use spark_ser7seg::{i2c::SevSegI2c, PunctuationFlags, SevenSegInterface};
 
-use fleet_clock as _; // global logger + panicking-behavior + memory layout
+// global logger + panicking-behavior + memory layout
+use fleet_clock::{
+    boot::BootInfo,
+    calendar::DateTime,
+    config::Config,
+    crash,
+    epd::{self, Plane},
+    identity::Identity,
+    monotonic::{self, Duration, Instant},
+    nvmc::Nvmc,
+    watchdog::{Task, Watchdog},
+};
 
 const IL0373_PANEL_SETTING: u8 = 0x00;
 const IL0373_POWER_SETTING: u8 = 0x01;
@@ -59,6 +69,9 @@ const IL0373_PARTIAL_EXIT: u8 = 0x92;
 const EPD_RAM_BW: u8 = IL0373_DTM1;
 const EPD_RAM_RED: u8 = IL0373_DTM2;
 
+/// How long the panel is busy after a refresh is started
+const REFRESH_TIME: Duration = Duration::from_secs(20);
+
 // il0373_default_init_code
 
 
@@ -125,22 +138,22 @@ fn main() -> ! {
     let clocks = clocks.set_lfclk_src_external(LfOscConfiguration::NoExternalNoBypass);
     clocks.start_lfclk();
 
-    // Obtain the watchdog, or try to recover it (if already
-    // active/running), or just spin and wait for the dog to bite.
-    let mut wdh = Watchdog::try_new(board.WDT)
-        .map(|mut wdt| {
-            wdt.set_lfosc_ticks(32768 * 30);
-            wdt.activate::<OneDog>()
-        })
-        .or_else(Watchdog::try_recover::<OneDog>)
-        .unwrap_or_else(|_| loop {
-            cortex_m::asm::wfi()
-        })
-        .handles
-        .0;
-
-    // Good doggy.
-    wdh.pet();
+    // Timestamps for the log
+    monotonic::init(board.RTC1);
+    unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::RTC1) };
+
+    let crash = crash::take();
+    if let Some(crash) = &crash {
+        crash.log();
+    }
+
+    let mut nvmc = Nvmc::new(board.NVMC);
+    BootInfo::read(&board.POWER, crash.is_some(), &mut nvmc).log();
+    let config = Config::load_or_default(&mut nvmc);
+    Identity::read(&board.FICR, &config.identity).log();
+
+    // One watchdog channel per task, as on the seven segment clock
+    let mut watchdog = Watchdog::start(board.WDT, &config.system);
 
     let gpio0 = P0Parts::new(board.P0);
     let gpio1 = P1Parts::new(board.P1);
@@ -167,7 +180,7 @@ fn main() -> ! {
     let mut spim = Spim::new(
         board.SPIM3,
         spim_pins,
-        Frequency::M4,
+        config.system.spim_frequency(),
         MODE_0,
         0
     );
@@ -181,10 +194,11 @@ fn main() -> ! {
             scl: scl.into_floating_input().degrade(),
             sda: sda.into_floating_input().degrade(),
         },
-        TwimFreq::K400,
+        config.system.twim_frequency(),
     );
 
-    let _bus = BusManagerSimple::new(twim);
+    let bus = BusManagerSimple::new(twim);
+    let mut ds3231 = Ds323x::new_ds3231(bus.acquire_i2c());
 
     let mut timer = Timer::new(board.TIMER0);
 
@@ -292,6 +306,97 @@ fn main() -> ! {
     //TODO: Guess
     timer.delay_ms(100u32);
 
+    // Redraw whenever the minute changes. A refresh takes about 20s, the
+    // rest of the minute is spent waiting.
+    let mut shown = None;
+    let mut refreshing: Option<Instant> = None;
+    loop {
+        // Only tasks that checked in recently are vouched for
+        watchdog.feed();
+
+        // There is no sensor or console on this board, nothing to wait on
+        watchdog.check_in(Task::Sensor);
+        watchdog.check_in(Task::Comms);
+
+        let now = match ds3231.get_datetime() {
+            Ok(dt) => dt.timestamp() as u32,
+            Err(_) => {
+                defmt::warn!("Failed to read RTC!");
+                timer.delay_ms(1000u32);
+                continue;
+            }
+        };
+        watchdog.check_in(Task::Clock);
+
+        let local = DateTime::from_unix(config.time.local(now));
+        match refreshing {
+            // Wait out the refresh a pass at a time, so the watchdog is
+            // still fed
+            Some(started) if started.elapsed() >= REFRESH_TIME => {
+                power_down(&mut display);
+                refreshing = None;
+            }
+            Some(_) => {}
+            None if shown != Some((local.hour, local.minute)) => {
+                // Eight characters at double size just fit across, e.g.
+                // "12:05 AM"
+                let mut black = Plane::new();
+                let text = config.units.clock.text(local.hour, local.minute);
+                black.draw_text(5, (epd::HEIGHT - 14) / 2, 2, &text);
+                start_refresh(&mut display, &mut timer, &black, &Plane::new());
+                refreshing = Some(monotonic::now());
+                shown = Some((local.hour, local.minute));
+            }
+            None => {}
+        }
+        watchdog.check_in(Task::Display);
+
+        timer.delay_ms(1000u32);
+    }
+}
+
+struct Display<S, G>
+where
+    S: SpimWrite<u8>,
+    G: OutputPin,
+{
+    spim: S,
+    tft_dc: G,
+    tft_cs: G,
+}
+
+impl<S, G> Display<S, G>
+where
+    S: SpimWrite<u8>,
+    G: OutputPin,
+{
+    fn command(
+        &mut self,
+        command: u8,
+        data: Option<&[u8]>,
+    ) -> Result<(), S::Error> {
+        self.tft_cs.set_high().ok();
+        self.tft_dc.set_low().ok();
+        self.tft_cs.set_low().ok();
+        self.spim.write(&[command])?;
+        self.tft_dc.set_high().ok();
+        if let Some(data) = data {
+            self.spim.write(data)?;
+        }
+        self.tft_cs.set_high().ok();
+        Ok(())
+    }
+}
+
+/// Power up the panel and start showing the two colour planes. The
+/// panel is busy for `REFRESH_TIME` afterwards, then wants `power_down`.
+fn start_refresh<S, G, D>(display: &mut Display<S, G>, timer: &mut D, black: &Plane, red: &Plane)
+where
+    S: SpimWrite<u8>,
+    S::Error: core::fmt::Debug,
+    G: OutputPin,
+    D: DelayMs<u32>,
+{
     // -----
     // This is roughly "power up"
     defmt::info!("Power Up");
@@ -348,80 +453,32 @@ fn main() -> ! {
         ]),
     ).unwrap();
 
-    // TODO: FILL AND SHOW THE DISPLAY
-    defmt::info!("Filling display...");
-
-    let mut buf2 = [0xFFu8; 2756];
-
-    for x in &mut buf2[..(13 * 70)] {
-        *x = 0x00;
-    }
-
     // writeRAMFramebufferToEPD(buffer1, buffer1_size, 0);
-    display.command(EPD_RAM_BW, Some(&buf2)).unwrap();
+    display.command(EPD_RAM_BW, Some(black.as_bytes())).unwrap();
 
     timer.delay_ms(2u32);
 
-    for x in &mut buf2[..(13 * 70)] {
-        *x = 0xFF;
-    }
-    for x in &mut  buf2[(13 * 70)..(13 * 70 * 2)] {
-        *x = 0x00;
-    }
-
     // writeRAMFramebufferToEPD(buffer2, buffer2_size, 1);
-    display.command(EPD_RAM_RED, Some(&buf2)).unwrap();
+    display.command(EPD_RAM_RED, Some(red.as_bytes())).unwrap();
 
     // update();
-    defmt::info!("Refresh and wait 20s...");
+    defmt::info!("Refresh...");
     display.command(IL0373_DISPLAY_REFRESH, None).unwrap();
-    timer.delay_ms(20_000u32);
+}
 
+/// Power the panel down once a refresh has finished
+fn power_down<S, G>(display: &mut Display<S, G>)
+where
+    S: SpimWrite<u8>,
+    S::Error: core::fmt::Debug,
+    G: OutputPin,
+{
     // -----
     // This is roughly "power down"
     defmt::info!("Power down...");
     display.command(IL0373_CDI, Some(&[0x17])).unwrap();
     display.command(IL0373_VCM_DC_SETTING, None).unwrap(); // TODO, MAAAYBE send a 0?
     display.command(IL0373_POWER_OFF, None).unwrap();
-
-
-    // SORRY
-
-    defmt::warn!("WAIT TO REFLASH...");
-    fleet_clock::exit()
-}
-
-struct Display<S, G>
-where
-    S: SpimWrite<u8>,
-    G: OutputPin,
-{
-    spim: S,
-    tft_dc: G,
-    tft_cs: G,
-}
-
-impl<S, G> Display<S, G>
-where
-    S: SpimWrite<u8>,
-    G: OutputPin,
-{
-    fn command(
-        &mut self,
-        command: u8,
-        data: Option<&[u8]>,
-    ) -> Result<(), S::Error> {
-        self.tft_cs.set_high().ok();
-        self.tft_dc.set_low().ok();
-        self.tft_cs.set_low().ok();
-        self.spim.write(&[command])?;
-        self.tft_dc.set_high().ok();
-        if let Some(data) = data {
-            self.spim.write(data)?;
-        }
-        self.tft_cs.set_high().ok();
-        Ok(())
-    }
 }
 
 // fn command<T: SpimWrite<U, Error=SpimError>, U>(
@@ -436,24 +493,7 @@ where
 //     tft_cs.set_high().ok();
 // }
 
-fn time2bytes(h: ds323x::Hours, m: u8) -> [u8; 4] {
-    let hour = match h {
-        ds323x::Hours::AM(am) => am,
-        ds323x::Hours::PM(pm) => pm + 12,
-        ds323x::Hours::H24(h24) => h24,
-    };
-
-    num2bytes(((hour as u32 * 100) as u16 + m as u16) as u16)
-}
-
-fn num2bytes(mut num: u16) -> [u8; 4] {
-    num = num.min(9999);
-
-    let data: [u8; 4] = [
-        (num / 1000) as u8,
-        ((num % 1000) / 100) as u8,
-        ((num % 100) / 10) as u8,
-        (num % 10) as u8,
-    ];
-    data
+#[interrupt]
+fn RTC1() {
+    monotonic::on_interrupt();
 }
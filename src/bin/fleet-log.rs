@@ -0,0 +1,59 @@
+//! Decode a raw dump of the QSPI flash log into CSV, or JSON lines
+//!
+//! ```console
+//! $ cargo fleet-log dump.bin > log.csv
+//! $ cargo fleet-log --json dump.bin > log.jsonl
+//! ```
+
+use std::{
+    env, fs,
+    io::{self, Write},
+    process,
+};
+
+use fleet_clock::{
+    datalog::parse_image,
+    record::{
+        export::{write_csv, write_json},
+        CSV_HEADER,
+    },
+};
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let json = args.iter().any(|a| a == "--json");
+    args.retain(|a| a != "--json");
+
+    let path = match args.as_slice() {
+        [path] => path.clone(),
+        _ => {
+            eprintln!("usage: fleet-log [--json] <image.bin>");
+            process::exit(1);
+        }
+    };
+
+    let image = match fs::read(&path) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    let records = parse_image(&image);
+    eprintln!("{} records", records.len());
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let res = if json {
+        records.iter().try_for_each(|r| write_json(&mut out, r))
+    } else {
+        writeln!(out, "{}", CSV_HEADER)
+            .and_then(|_| records.iter().try_for_each(|r| write_csv(&mut out, r)))
+    };
+
+    if let Err(e) = res {
+        eprintln!("failed to write output: {}", e);
+        process::exit(1);
+    }
+}
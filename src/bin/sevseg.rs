@@ -2,29 +2,94 @@
 #![no_std]
 #![allow(unused_imports)]
 
-use ds323x::{Ds323x, NaiveDate, NaiveDateTime, NaiveTime, Rtcc};
-use embedded_hal::blocking::{
-    delay::{DelayMs, DelayUs},
-    i2c::{Read, Write},
+use core::fmt::{self, Write as _};
+
+use ds323x::{Ds323x, NaiveDateTime, Rtcc};
+use embedded_hal::{
+    blocking::{
+        delay::{DelayMs, DelayUs},
+        i2c::{Read, Write},
+    },
+    digital::v2::InputPin,
 };
 use nrf52840_hal::{
     self as hal,
     clocks::LfOscConfiguration,
     gpio::{p0::Parts as P0Parts, p1::Parts as P1Parts, Level},
-    pac::{Peripherals, SPIM0, SPIS1, TIMER2, UARTE0},
+    pac::{interrupt, Interrupt, Peripherals, PWM0, SPIM0, SPIS1, TIMER1, TIMER2},
     ppi::{Parts as PpiParts, Ppi0},
     spim::{Frequency, Pins as SpimPins, Spim, MODE_0},
     spis::{Mode, Pins as SpisPins, Spis, Transfer},
     timer::{Instance as TimerInstance, Periodic, Timer},
     twim::{Frequency as TwimFreq, Instance as TwimInstance, Pins as TwimPins, Twim},
-    uarte::{Baudrate, Parity, Pins},
-    wdt::{count::One as OneDog, Watchdog},
+    uarte::{self, Baudrate, Instance as UarteInstance, Parity, Pins, Uarte},
 };
-use sensor_scd30::Scd30;
+use sensor_scd30::{Measurement, Scd30};
 use shared_bus::BusManagerSimple;
 use spark_ser7seg::{i2c::SevSegI2c, PunctuationFlags, SevenSegInterface};
 
-use fleet_clock as _; // global logger + panicking-behavior + memory layout
+// global logger + panicking-behavior + memory layout
+use fleet_clock::{
+    alert::{AlertConfig, AlertLevel, Co2Alarm},
+    ble,
+    boot::{BootInfo, Cause},
+    button::{Button, Press},
+    buzzer::Buzzer,
+    calendar::DateTime,
+    comfort::Comfort,
+    config::{Calibration, Config, Key, SystemConfig},
+    console::{self, Command, Input, LineBuffer},
+    crash::{self, Crash, Kind as CrashKind},
+    cts::{self, SyncSchedule, TimeConfig, TimeReference},
+    datalog::DataLog,
+    digits::{self, Digits},
+    flash::Flash,
+    history::{Field, History, Sample},
+    i2c_recovery::{self, RecoverableTwim},
+    i2c_scan::{self, Device},
+    identity::Identity,
+    monotonic,
+    nvmc::Nvmc,
+    qspi::{self, Pins as QspiPins, QspiFlash},
+    record::{Csv, EventKind, Record, CSV_HEADER},
+    scd30::Scd30Config,
+    trend::{Direction, Trend},
+    units::{ClockFormat, Meridiem, UnitsConfig},
+    uptime::{self, OperatingTime, Unit},
+    usb_serial::{self, UsbClocks},
+    watchdog::{Task, Watchdog},
+};
+
+/// Period of the main loop, in milliseconds
+const LOOP_MS: u32 = 100;
+
+/// Holding the button this long forces a recalibration of the SCD30
+const LONG_PRESS_MS: u32 = 5000;
+
+/// The most screens one pass through the main loop shows: the measurement
+/// and one more group, or the screens for a button press in their place
+const MAX_PAGES: u32 = max(
+    MEASUREMENT_PAGES + EXTRA_PAGES,
+    max(DIAGNOSTICS_PAGES, SENSOR_DIAGNOSTICS_PAGES),
+);
+
+/// The biggest of the groups that take turns after the measurement
+const EXTRA_PAGES: u32 = max(
+    max(COMFORT_PAGES, TREND_PAGES + DAILY_MAX_PAGES),
+    UPTIME_PAGES,
+);
+
+// The watchdog timeout is worked out from the config's page budget, so a
+// rotation that outgrows it mustn't build
+const _: [(); 1] = [(); (MAX_PAGES <= SystemConfig::MAX_PAGES_PER_PASS) as usize];
+
+const fn max(a: u32, b: u32) -> u32 {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
 
 #[cortex_m_rt::entry]
 fn main() -> ! {
@@ -36,176 +101,457 @@ fn main() -> ! {
     let clocks = hal::clocks::Clocks::new(board.CLOCK);
     let clocks = clocks.enable_ext_hfosc();
     let clocks = clocks.set_lfclk_src_external(LfOscConfiguration::NoExternalNoBypass);
-    clocks.start_lfclk();
-
-    // Obtain the watchdog, or try to recover it (if already
-    // active/running), or just spin and wait for the dog to bite.
-    let mut wdh = Watchdog::try_new(board.WDT)
-        .map(|mut wdt| {
-            wdt.set_lfosc_ticks(32768 * 30);
-            wdt.activate::<OneDog>()
-        })
-        .or_else(Watchdog::try_recover::<OneDog>)
-        .unwrap_or_else(|_| loop {
-            cortex_m::asm::wfi()
-        })
-        .handles
-        .0;
-
-    // Good doggy.
-    wdh.pet();
+    let clocks: &'static UsbClocks =
+        cortex_m::singleton!(: UsbClocks = clocks.start_lfclk()).unwrap();
+
+    // Timestamps for the log, and time since boot
+    monotonic::init(board.RTC1);
+    unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::RTC1) };
+
+    // Left behind if the last reset was a crash
+    let crash = crash::take();
+    if let Some(crash) = &crash {
+        crash.log();
+    }
+
+    // Per-device settings, kept in internal flash across reflashes
+    let mut nvmc = Nvmc::new(board.NVMC);
+
+    // Why we're here, and how often we've been here before
+    let boot_info = BootInfo::read(&board.POWER, crash.is_some(), &mut nvmc);
+    boot_info.log();
+
+    // Total time running, over all boots
+    let mut operating = OperatingTime::load(&mut nvmc).unwrap_or_default();
+    defmt::info!("operating_mins: {=u32}", operating.total_min(0));
+
+    let mut config = match Config::load(&mut nvmc) {
+        Ok(Some(config)) => config,
+        _ => {
+            defmt::warn!("No valid config stored, using defaults");
+            Config::default()
+        }
+    };
+    defmt::info!("{:?}", config);
+    let mut identity = Identity::read(&board.FICR, &config.identity);
+    identity.log();
+    let mut dwell_ms = config.system.page_dwell_ms as u32;
+
+    // One watchdog channel per task, each of which has to check in. The
+    // timeout only takes effect after a power cycle or watchdog reset, as
+    // a running WDT can't be reconfigured.
+    let mut watchdog = Watchdog::start(board.WDT, &config.system);
 
     let gpio0 = P0Parts::new(board.P0);
-    let _gpio1 = P1Parts::new(board.P1);
+    let gpio1 = P1Parts::new(board.P1);
+
+    // The "USER" switch on the feather
+    let user_sw = gpio1.p1_02.into_pullup_input();
+    let mut button = Button::new(LONG_PRESS_MS);
+
+    // Command console on the TX/RX pins, mirrored on the USB serial port.
+    // UART input is only received while the main loop is waiting between
+    // passes, so anything typed there while a screen is being shown is lost.
+    let uarte = Uarte::new(
+        board.UARTE0,
+        Pins {
+            txd: gpio0.p0_25.into_push_pull_output(Level::High).degrade(),
+            rxd: gpio0.p0_24.into_floating_input().degrade(),
+            cts: None,
+            rts: None,
+        },
+        Parity::EXCLUDED,
+        Baudrate::BAUD115200,
+    );
+    let mut term = Terminal { uarte };
+    let mut console_timer = Timer::new(board.TIMER1);
+    let mut line = LineBuffer::new();
+    let mut streaming = false;
+
+    usb_serial::init(board.USBD, clocks, identity.device_id);
+    unsafe { cortex_m::peripheral::NVIC::unmask(Interrupt::USBD) };
+
+    // Environmental sensing over BLE, for the facilities dashboard
+    ble::init(board.RADIO, board.TIMER0, &board.FICR, &identity);
+    unsafe {
+        cortex_m::peripheral::NVIC::unmask(Interrupt::RADIO);
+        cortex_m::peripheral::NVIC::unmask(Interrupt::TIMER0);
+    }
+
+    // Piezo buzzer on D11, if fitted
+    let buzzer_pin = gpio0.p0_06.into_push_pull_output(Level::Low).degrade();
+    let mut buzzer = Buzzer::new(board.PWM0, buzzer_pin);
+
+    // Sensor and event log, on the QSPI flash chip
+    let qspi_flash = QspiFlash::new(
+        board.QSPI,
+        QspiPins {
+            sck: gpio0.p0_19.into_push_pull_output(Level::High).degrade(),
+            csn: gpio0.p0_20.into_push_pull_output(Level::High).degrade(),
+            io0: gpio0.p0_17.into_push_pull_output(Level::High).degrade(),
+            io1: gpio0.p0_22.into_push_pull_output(Level::High).degrade(),
+            io2: gpio0.p0_23.into_push_pull_output(Level::High).degrade(),
+            io3: gpio0.p0_21.into_push_pull_output(Level::High).degrade(),
+        },
+    );
+    let log_sectors = qspi::CAPACITY / QspiFlash::SECTOR_SIZE;
+    let mut datalog = match DataLog::open(qspi_flash, 0, log_sectors) {
+        Ok(log) => Some(log),
+        Err(e) => {
+            defmt::error!("Failed to open data log: {:?}", e);
+            None
+        }
+    };
 
     let scl = gpio0.p0_11;
     let sda = gpio0.p0_12;
 
-    let twim = Twim::new(
+    let twim = RecoverableTwim::new(
         board.TWIM0,
         TwimPins {
             scl: scl.into_floating_input().degrade(),
             sda: sda.into_floating_input().degrade(),
         },
-        TwimFreq::K400,
+        config.system.twim_frequency(),
     );
 
     let bus = BusManagerSimple::new(twim);
 
-    let mut timer = Timer::new(board.TIMER0);
+    // TIMER0 belongs to the BLE link layer
+    let mut timer = Timer::new(board.TIMER3);
+
+    // See what's actually fitted to this unit
+    let inventory = i2c_scan::scan(&mut bus.acquire_i2c());
+    inventory.log();
 
-    let mut sevseg = SevSegI2c::new(bus.acquire_i2c(), None);
+    if inventory.has(Device::Ht16k33) && !inventory.has(Device::SparkfunSevSeg) {
+        defmt::warn!("HT16K33 displays are not yet supported, running headless");
+    }
+
+    // The RTC is the one device we can't do without
+    if !inventory.has(Device::Ds3231) {
+        defmt::panic!("No RTC found!");
+    }
+
+    let mut sevseg = if inventory.has(Device::SparkfunSevSeg) {
+        Some(SevSegI2c::new(bus.acquire_i2c(), None))
+    } else {
+        None
+    };
     let mut ds3231 = Ds323x::new_ds3231(bus.acquire_i2c());
-    let mut scd30 = Scd30::new(bus.acquire_i2c()).unwrap();
+    let mut scd30_cfg = if inventory.has(Device::Scd30) {
+        Some(Scd30Config::new(bus.acquire_i2c()))
+    } else {
+        None
+    };
+    let mut scd30 = if let Some(scd30_cfg) = scd30_cfg.as_mut() {
+        if scd30_cfg.apply(&config.scd30, &mut timer).is_err() {
+            defmt::error!("Failed to configure SCD30!");
+        }
+        match scd30_cfg.verify(&config.scd30, &mut timer) {
+            Ok(true) => defmt::info!("SCD30 settings verified"),
+            Ok(false) => defmt::error!("SCD30 settings don't match config!"),
+            Err(_) => defmt::error!("Failed to read back SCD30 settings!"),
+        }
 
-    let date = NaiveDate::from_ymd(2021, 2, 21);
-    let time = NaiveTime::from_hms(22, 36, 40);
-    let base_now = NaiveDateTime::new(date, time);
+        Scd30::new(bus.acquire_i2c()).ok()
+    } else {
+        None
+    };
+
+    let base_now = NaiveDateTime::from_timestamp(config.system.base_datetime as i64, 0);
 
     let dt = ds3231.get_datetime().unwrap();
 
     let mut time_sep = true;
 
-    // HACK FOR TIME INIT
+    // Until the clock is synced, from the console or over BLE, start from
+    // the configured base time rather than 2000-01-01
     if dt < base_now {
         defmt::warn!("Setting clock!");
         ds3231.set_datetime(&base_now).unwrap();
         timer.delay_ms(10u32);
     }
 
-    let mut hours = ds3231.get_hours().unwrap();
-    let mut mins = ds3231.get_minutes().unwrap();
-    let mut secs = ds3231.get_seconds().unwrap();
+    let (mut hours, mut mins, mut secs) = local_time(&mut ds3231, &config.time).unwrap();
 
-    sevseg.set_cursor(0).unwrap();
-    timer.delay_us(15u32);
+    if let Some(sevseg) = sevseg.as_mut() {
+        write_time(sevseg, &mut timer, config.units.clock, hours, mins);
+        timer.delay_us(100u32);
+        sevseg
+            .write_punctuation(PunctuationFlags::DOTS_COLON)
+            .unwrap();
+    }
 
-    sevseg.write_digits(&time2bytes(hours, mins)).unwrap();
-    timer.delay_us(100u32);
-    sevseg
-        .write_punctuation(PunctuationFlags::DOTS_COLON)
-        .unwrap();
+    if let Some(timestamp) = unix_time(&mut ds3231) {
+        let boot = Record::Event {
+            timestamp,
+            kind: EventKind::Boot,
+            arg: boot_info.cause as u16,
+        };
+        log_record(&mut datalog, &boot);
 
-    let mut min_uptime = 0u32;
+        if let Some(crash) = &crash {
+            let event = Record::Event {
+                timestamp,
+                kind: EventKind::Crash,
+                arg: crash.code(),
+            };
+            log_record(&mut datalog, &event);
+        }
+    }
+
+    write!(term, "{}\r\n{}\r\n", identity, boot_info).ok();
+    if let Some(crash) = &crash {
+        write!(
+            term,
+            "last reset: {:?} after {}s, code {}: {}\r\n",
+            crash.kind,
+            crash.uptime_s,
+            crash.code(),
+            crash.message.as_str()
+        )
+        .ok();
+        if let Some(sevseg) = sevseg.as_mut() {
+            show_crash(sevseg, &mut timer, dwell_ms, crash);
+        }
+    }
+    write!(term, "{}", console::PROMPT).ok();
+
+    let mut alarm = Co2Alarm::new();
+    let mut history = History::new();
+    let mut trend = None;
+    // Last logged, so only changes are logged
+    let mut occupancy = None;
+    let mut time_sync = SyncSchedule::new();
+    // Where the log has got to, while dumping it
+    let mut dumping = None;
+    // The second half of the diagnostics is due
+    let mut sensor_diagnostics = false;
+    let mut i2c_recoveries = i2c_recovery::recovery_count();
 
     loop {
-        let new_hours = ds3231.get_hours().unwrap();
-        let new_mins = ds3231.get_minutes().unwrap();
-        let new_secs = ds3231.get_seconds().unwrap();
+        // Only tasks that checked in recently are vouched for
+        watchdog.feed();
+
+        // A failed read is retried on the next pass. Repeated bus errors
+        // will trigger a bus recovery in `RecoverableTwim`.
+        let (new_hours, new_mins, new_secs) = match local_time(&mut ds3231, &config.time) {
+            Some(time) => time,
+            None => {
+                defmt::warn!("Failed to read RTC!");
+                timer.delay_ms(LOOP_MS);
+                continue;
+            }
+        };
+        watchdog.check_in(Task::Clock);
 
-        // Pet the dog.
-        wdh.pet();
+        // Screens shown for the button take the place of this pass's
+        // rotation, to keep the pass inside the watchdog timeout
+        let mut button_screens = false;
+        match button.update(user_sw.is_low().unwrap_or(false), monotonic::now()) {
+            Some(Press::Short) => {
+                if let Some(sevseg) = sevseg.as_mut() {
+                    show_diagnostics(sevseg, &mut timer, dwell_ms, &identity, &boot_info);
+                    button_screens = true;
+                    sensor_diagnostics = true;
+                }
+            }
+            Some(Press::Long) => {
+                if let Some(scd30_cfg) = scd30_cfg.as_mut() {
+                    let reference_ppm = config.scd30.frc_reference_ppm;
+                    let now = unix_time(&mut ds3231).unwrap_or(0);
+                    let ok = recalibrate(
+                        scd30_cfg,
+                        &mut timer,
+                        reference_ppm,
+                        now,
+                        &mut config,
+                        &mut nvmc,
+                        &mut datalog,
+                    );
+
+                    if let Some(sevseg) = sevseg.as_mut() {
+                        let text = if ok { b" cAL" } else { b" Err" };
+                        show_text(sevseg, &mut timer, dwell_ms, text);
+                        button_screens = true;
+                    }
+                }
+            }
+            // The rest of the diagnostics follow on the next pass
+            None if sensor_diagnostics => {
+                if let Some(sevseg) = sevseg.as_mut() {
+                    let now = unix_time(&mut ds3231).unwrap_or(0);
+                    show_sensor_diagnostics(sevseg, &mut timer, dwell_ms, &config, now);
+                    button_screens = true;
+                }
+                sensor_diagnostics = false;
+            }
+            None => {}
+        }
 
-        // TODO: End of hour report?
+        // Ask for the time over BLE while a sync is due, and apply it once
+        // a gateway writes it
+        let uptime_s = monotonic::now().since_start().as_secs();
+        ble::request_time(time_sync.is_due(uptime_s));
+        if let Some(reference) = ble::take_time() {
+            sync_time(&mut ds3231, reference, &mut config, &mut nvmc, &mut datalog);
+            time_sync.synced(uptime_s);
+        }
 
         if mins != new_mins {
-            min_uptime += 1;
+            let min_uptime = uptime_min();
+            if operating.checkpoint(&mut nvmc, min_uptime).is_err() {
+                defmt::warn!("Failed to store the operating time");
+            }
 
-            defmt::info!("Checking SCD...");
-            if scd30.data_ready().unwrap() {
-                sevseg.set_cursor(0).unwrap();
-                timer.delay_us(100u32);
-                sevseg.write_punctuation(PunctuationFlags::NONE).unwrap();
-                timer.delay_us(100u32);
+            let now = unix_time(&mut ds3231);
 
-                sevseg.send(b" co2").unwrap();
-                timer.delay_ms(2000u32);
+            if hours != new_hours {
+                if let Some(now) = now {
+                    hourly_report(&history, now);
+                }
+            }
 
-                let meas = scd30.read_data().unwrap();
-                defmt::info!("co2: {:?}", meas.co2);
-                sevseg.write_digits(&num2bytes(meas.co2 as u16)).unwrap();
-                timer.delay_ms(2000u32);
+            // The sensor is alive as long as it answers, whether or not
+            // there is a new reading yet
+            let meas = match scd30.as_mut() {
+                Some(scd30) => {
+                    defmt::info!("Checking SCD...");
+                    match scd30.data_ready() {
+                        Ok(ready) => {
+                            watchdog.check_in(Task::Sensor);
+                            if ready {
+                                scd30.read_data().ok()
+                            } else {
+                                None
+                            }
+                        }
+                        Err(_) => None,
+                    }
+                }
+                None => {
+                    watchdog.check_in(Task::Sensor);
+                    None
+                }
+            };
 
+            if let Some(meas) = &meas {
+                defmt::info!("co2: {:?}", meas.co2);
                 defmt::info!("temp: {:?}", meas.temp);
-                sevseg
-                    .write_digits(&num2bytes((meas.temp * 100.0) as u16))
-                    .ok();
-                timer.delay_us(100u32);
-                sevseg
-                    .write_punctuation(PunctuationFlags::DOT_BETWEEN_2_AND_3)
-                    .unwrap();
-                timer.delay_us(100u32);
-                sevseg.set_cursor(3).unwrap();
-                timer.delay_us(100u32);
-                sevseg.send(b"C").unwrap();
-                timer.delay_ms(2000u32);
+                defmt::info!("rh: {:?}", meas.rh);
+                if let Some(comfort) = Comfort::from_f32(meas.temp, meas.rh) {
+                    defmt::info!("comfort: {:?}", comfort);
+                }
 
-                sevseg.write_punctuation(PunctuationFlags::NONE).unwrap();
-                timer.delay_us(100u32);
+                let level = alarm.update(&config.alert, meas.co2 as u16);
+                if let Some(level) = level {
+                    defmt::warn!("CO2 alert level: {:?}", level);
+                }
 
-                defmt::info!("rh: {:?}", meas.rh);
-                sevseg
-                    .write_digits(&num2bytes((meas.rh * 100.0) as u16))
-                    .ok();
-                timer.delay_us(100u32);
-                sevseg.set_cursor(2).unwrap();
-                timer.delay_us(100u32);
-                sevseg.send(b"rh").unwrap();
-                timer.delay_ms(2000u32);
-
-                defmt::info!("uptime_mins: {:?}", min_uptime);
-
-                let hours_up = (min_uptime as f32) / 60.0;
-                let days_up = (min_uptime as f32) / 1440.0;
-
-                let updata = if hours_up <= 99.9f32 {
-                    let show = (hours_up * 100f32) as u16;
-                    let dot = PunctuationFlags::DOT_BETWEEN_2_AND_3;
-                    let unit = b"h";
-                    Some((show, dot, unit))
-                } else if hours_up <= 999.5f32 {
-                    let show = (hours_up * 10f32) as u16;
-                    let dot = PunctuationFlags::NONE;
-                    let unit = b"h";
-                    Some((show, dot, unit))
-                } else if days_up <= 99.9f32 {
-                    let show = (days_up * 100f32) as u16;
-                    let dot = PunctuationFlags::DOT_BETWEEN_2_AND_3;
-                    let unit = b"d";
-                    Some((show, dot, unit))
-                } else if days_up <= 999.5f32 {
-                    let show = (days_up * 10f32) as u16;
-                    let dot = PunctuationFlags::NONE;
-                    let unit = b"d";
-                    Some((show, dot, unit))
-                } else if days_up > 999.5f32 {
-                    None
-                } else {
-                    defmt::warn!("Floats suck.");
-                    None
-                };
+                if let Some(now) = now {
+                    let sample = Sample::from_f32(now, meas.co2, meas.temp, meas.rh);
+                    history.push(sample);
+                    log_record(&mut datalog, &Record::Sample(sample));
+                    ble::update(&sample, min_uptime);
+                    if streaming {
+                        write!(term, "{}\r\n", Csv(&Record::Sample(sample))).ok();
+                    }
 
-                if let Some((show, dot, unit)) = updata {
-                    sevseg.write_digits(&num2bytes(show)).ok();
-                    timer.delay_us(100u32);
-                    sevseg.write_punctuation(dot).unwrap();
-                    timer.delay_us(100u32);
-                    sevseg.set_cursor(3).unwrap();
-                    timer.delay_us(100u32);
-                    sevseg.send(unit).unwrap();
-                    timer.delay_ms(2000u32);
-                    sevseg.write_punctuation(PunctuationFlags::NONE).unwrap();
-                    timer.delay_us(100u32);
+                    if let Some(level) = level {
+                        let event = Record::Event {
+                            timestamp: now,
+                            kind: EventKind::AlertLevel,
+                            arg: level.severity() as u16,
+                        };
+                        log_record(&mut datalog, &event);
+                    }
+                }
+            }
+
+            trend = now.and_then(|now| Trend::analyse(&history, now));
+            if let Some(trend) = &trend {
+                defmt::info!("co2 trend: {:?}", trend);
+            }
+            if let (Some(trend), Some(now)) = (&trend, now) {
+                let people = config.room.occupancy(trend);
+                if occupancy != Some(people) {
+                    defmt::info!("occupancy: {:?}", people);
+                    let event = Record::Event {
+                        timestamp: now,
+                        kind: EventKind::Occupancy,
+                        arg: people,
+                    };
+                    log_record(&mut datalog, &event);
+                    occupancy = Some(people);
+                }
+            }
+
+            let new_recoveries = i2c_recovery::recovery_count();
+            defmt::info!("uptime_mins: {:?}", min_uptime);
+            defmt::info!("operating_mins: {:?}", operating.total_min(min_uptime));
+            defmt::info!("i2c_recoveries: {:?}", new_recoveries);
+
+            if new_recoveries != i2c_recoveries {
+                if let Some(now) = now {
+                    let event = Record::Event {
+                        timestamp: now,
+                        kind: EventKind::I2cRecovery,
+                        arg: new_recoveries.min(u16::MAX as u32) as u16,
+                    };
+                    log_record(&mut datalog, &event);
+                }
+                i2c_recoveries = new_recoveries;
+            }
+
+            let alerting = alarm.level() != AlertLevel::Normal;
+            let quiet = config.alert.is_quiet(new_hours);
+            if alerting && config.alert.buzzer_enabled && !quiet {
+                buzzer.chirp(&mut timer, alarm.level().severity());
+            }
+
+            if let Some(sevseg) = sevseg.as_mut() {
+                match &meas {
+                    // Alerts replace the normal rotation
+                    Some(meas) if alerting => {
+                        show_alert(sevseg, &mut timer, meas.co2 as u16);
+                    }
+                    _ if button_screens => {}
+                    _ => {
+                        if let Some(meas) = &meas {
+                            show_measurement(sevseg, &mut timer, dwell_ms, &config.units, meas);
+                        }
+
+                        // Then one more group, taking turns by the minute, so
+                        // the rotation stays well inside the watchdog timeout
+                        match new_mins % 3 {
+                            0 => {
+                                if let Some(meas) = &meas {
+                                    show_comfort(sevseg, &mut timer, dwell_ms, &config.units, meas);
+                                }
+                            }
+                            1 => {
+                                if let Some(trend) = &trend {
+                                    show_trend(sevseg, &mut timer, dwell_ms, trend, &config.alert);
+                                }
+
+                                // Today's peak, since local midnight
+                                let today = now.and_then(|now| {
+                                    let midnight = config.time.start_of_day(now);
+                                    history.stats(Field::Co2, midnight, now + 1)
+                                });
+                                if let Some(today) = today {
+                                    show_daily_max(sevseg, &mut timer, dwell_ms, today.max as u16);
+                                }
+                            }
+                            _ => {
+                                show_uptime(sevseg, &mut timer, dwell_ms, min_uptime);
+                                show_text(sevseg, &mut timer, dwell_ms, b"totL");
+                                let total = operating.total_min(min_uptime);
+                                show_uptime(sevseg, &mut timer, dwell_ms, total);
+                            }
+                        }
+                    }
                 }
             }
         } else if new_secs != secs {
@@ -214,7 +560,7 @@ fn main() -> ! {
                 | PunctuationFlags::DOT_BETWEEN_3_AND_4
                 | PunctuationFlags::DOT_RIGHT_OF_4;
 
-            let punc = all_dots
+            let mut punc = all_dots
                 ^ if secs < 15 {
                     PunctuationFlags::DOT_BETWEEN_1_AND_2
                 } else if secs < 30 {
@@ -224,38 +570,783 @@ fn main() -> ! {
                 } else {
                     PunctuationFlags::DOT_RIGHT_OF_4
                 };
+            // In 12 hour time the last dot is lit for PM, rather than
+            // counting off the seconds
+            if let (_, Some(meridiem)) = config.units.clock.hour(new_hours) {
+                punc.set(PunctuationFlags::DOT_RIGHT_OF_4, meridiem == Meridiem::Pm);
+            }
 
             time_sep = !time_sep;
-            if time_sep {
-                sevseg
-                    .write_punctuation(PunctuationFlags::DOTS_COLON | punc)
-                    .unwrap();
-            } else {
-                sevseg
-                    .write_punctuation(PunctuationFlags::NONE | punc)
-                    .unwrap();
+            if let Some(sevseg) = sevseg.as_mut() {
+                if time_sep {
+                    sevseg
+                        .write_punctuation(PunctuationFlags::DOTS_COLON | punc)
+                        .ok();
+                } else {
+                    sevseg
+                        .write_punctuation(PunctuationFlags::NONE | punc)
+                        .ok();
+                }
+                timer.delay_us(100u32);
             }
-            timer.delay_us(100u32);
         }
 
         hours = new_hours;
         mins = new_mins;
         secs = new_secs;
 
-        sevseg.write_digits(&time2bytes(hours, mins)).unwrap();
+        let shown = match sevseg.as_mut() {
+            Some(sevseg) => write_time(sevseg, &mut timer, config.units.clock, hours, mins),
+            None => true,
+        };
+        if shown {
+            watchdog.check_in(Task::Display);
+        }
+
+        // A full log takes a good few minutes to send, so it goes a sector
+        // per pass, with every task checking in as usual in between
+        if let (Some(cursor), Some(log)) = (dumping.as_mut(), datalog.as_mut()) {
+            let result = log.for_each_in_sector(cursor, |record| {
+                write!(term, "{}\r\n", Csv(&record)).ok();
+                Ok::<(), qspi::QspiError>(())
+            });
+            match result {
+                Ok(true) => {}
+                Ok(false) => dumping = None,
+                Err(_) => {
+                    write!(term, "error: failed to read log\r\n").ok();
+                    dumping = None;
+                }
+            }
+            if dumping.is_none() {
+                write!(term, "{}", console::PROMPT).ok();
+            }
+        }
+
+        // Waiting for console input doubles as the delay between passes
+        let mut rx = [0u8; 32];
+        let uart_rx = &mut rx[..16];
+        let uart_result = term
+            .uarte
+            .read_timeout(uart_rx, &mut console_timer, LOOP_MS * 1_000);
+        let mut received = match uart_result {
+            Ok(()) => uart_rx.len(),
+            Err(uarte::Error::Timeout(n)) => n,
+            Err(_) => 0,
+        };
+        received += usb_serial::read(&mut rx[received..]);
+
+        for byte in rx[..received].iter().copied() {
+            let cmd = match line.push(byte) {
+                Input::Line(text) => console::parse(text),
+                Input::Echo => {
+                    term.write_bytes(&[byte]);
+                    continue;
+                }
+                Input::Ignored => continue,
+            };
+            write!(term, "\r\n").ok();
+
+            let cmd = match cmd {
+                Ok(Some(cmd)) => cmd,
+                Ok(None) => {
+                    write!(term, "{}", console::PROMPT).ok();
+                    continue;
+                }
+                Err(e) => {
+                    write!(term, "error: {}\r\n{}", e, console::PROMPT).ok();
+                    continue;
+                }
+            };
+            defmt::info!("console: {:?}", cmd);
+
+            match cmd {
+                Command::Help => {
+                    for text in console::HELP.lines() {
+                        write!(term, "{}\r\n", text).ok();
+                    }
+                }
+                Command::Info => {
+                    let min_uptime = uptime_min();
+                    write!(
+                        term,
+                        "{}\r\n{}\r\nuptime_min={} operating_min={}\r\n",
+                        identity,
+                        boot_info,
+                        min_uptime,
+                        operating.total_min(min_uptime)
+                    )
+                    .ok();
+                }
+                Command::TimeGet => {
+                    match unix_time(&mut ds3231) {
+                        Some(now) => write!(term, "{}\r\n", DateTime::from_unix(now)),
+                        None => write!(term, "error: failed to read RTC\r\n"),
+                    }
+                    .ok();
+                }
+                Command::TimeSet(timestamp) => {
+                    let dt = NaiveDateTime::from_timestamp(timestamp as i64, 0);
+                    match ds3231.set_datetime(&dt) {
+                        Ok(()) => write!(term, "ok\r\n"),
+                        Err(_) => write!(term, "error: failed to set RTC\r\n"),
+                    }
+                    .ok();
+                }
+                Command::SensorRead => {
+                    // A fresh reading if there is one, otherwise the last
+                    let fresh = scd30.as_mut().and_then(|scd30| match scd30.data_ready() {
+                        Ok(true) => scd30.read_data().ok(),
+                        _ => None,
+                    });
+                    let sample = match (fresh, unix_time(&mut ds3231)) {
+                        (Some(meas), Some(now)) => {
+                            Some(Sample::from_f32(now, meas.co2, meas.temp, meas.rh))
+                        }
+                        _ => history.latest().copied(),
+                    };
+                    match sample {
+                        Some(sample) => write!(
+                            term,
+                            "{}\r\n{}\r\n",
+                            CSV_HEADER,
+                            Csv(&Record::Sample(sample))
+                        ),
+                        None => write!(term, "error: no reading available\r\n"),
+                    }
+                    .ok();
+                }
+                Command::ConfigGet(Some(key)) => {
+                    write!(term, "{} = {}\r\n", key.name(), config.get(key)).ok();
+                }
+                Command::ConfigGet(None) => {
+                    for key in Key::ALL.iter() {
+                        write!(term, "{} = {}\r\n", key.name(), config.get(*key)).ok();
+                    }
+                }
+                Command::ConfigSet(key, value) => {
+                    if !config.set(key, value) {
+                        write!(term, "error: value out of range\r\n").ok();
+                    } else if config.store(&mut nvmc).is_err() {
+                        write!(term, "error: failed to store config\r\n").ok();
+                    } else {
+                        if key.is_scd30() {
+                            if let Some(scd30_cfg) = scd30_cfg.as_mut() {
+                                if scd30_cfg.apply(&config.scd30, &mut timer).is_err() {
+                                    write!(term, "error: failed to configure SCD30\r\n").ok();
+                                }
+                            }
+                        }
+                        dwell_ms = config.system.page_dwell_ms as u32;
+                        identity.name = config.identity.name;
+                        identity.location = config.identity.location;
 
-        timer.delay_ms(100u32);
+                        let note = if key.needs_reset() {
+                            ", takes effect after reset"
+                        } else {
+                            ""
+                        };
+                        write!(term, "ok{}\r\n", note).ok();
+                    }
+                }
+                Command::CalibrateCo2(reference_ppm) => {
+                    let reference_ppm = reference_ppm.unwrap_or(config.scd30.frc_reference_ppm);
+                    let now = unix_time(&mut ds3231).unwrap_or(0);
+                    let ok = scd30_cfg.as_mut().map(|scd30_cfg| {
+                        recalibrate(
+                            scd30_cfg,
+                            &mut timer,
+                            reference_ppm,
+                            now,
+                            &mut config,
+                            &mut nvmc,
+                            &mut datalog,
+                        )
+                    });
+                    match ok {
+                        Some(true) => write!(term, "ok, calibrated to {}ppm\r\n", reference_ppm),
+                        Some(false) => write!(term, "error: recalibration failed\r\n"),
+                        None => write!(term, "error: no SCD30 fitted\r\n"),
+                    }
+                    .ok();
+                }
+                Command::CalibrateAsc(enabled) => {
+                    let result = scd30_cfg
+                        .as_mut()
+                        .map(|scd30_cfg| scd30_cfg.set_asc(enabled, &mut timer));
+                    match result {
+                        Some(Ok(())) => {
+                            config.scd30.asc_enabled = enabled;
+                            if config.store(&mut nvmc).is_err() {
+                                defmt::error!("Failed to store config!");
+                            }
+                            write!(term, "ok\r\n")
+                        }
+                        Some(Err(_)) => write!(term, "error: failed to set ASC\r\n"),
+                        None => write!(term, "error: no SCD30 fitted\r\n"),
+                    }
+                    .ok();
+                }
+                Command::I2cScan => {
+                    let inventory = i2c_scan::scan(&mut bus.acquire_i2c());
+                    for address in inventory.addresses() {
+                        match Device::at(address) {
+                            Some(device) => write!(term, "0x{:02x} {:?}\r\n", address, device),
+                            None => write!(term, "0x{:02x} unknown\r\n", address),
+                        }
+                        .ok();
+                    }
+                }
+                Command::LogDump => match datalog.as_ref() {
+                    Some(log) => {
+                        write!(term, "{}\r\n", CSV_HEADER).ok();
+                        // Sent a sector per pass, the prompt follows at the
+                        // end
+                        dumping = Some(log.cursor());
+                        continue;
+                    }
+                    None => {
+                        write!(term, "error: no data log\r\n").ok();
+                    }
+                },
+                Command::Stream(enabled) => {
+                    streaming = enabled;
+                    if enabled {
+                        write!(term, "{}\r\n", CSV_HEADER).ok();
+                    } else {
+                        write!(term, "ok\r\n").ok();
+                    }
+                }
+                Command::Reset => {
+                    write!(term, "resetting\r\n").ok();
+                    // Don't lose the minutes since the last checkpoint
+                    operating.store(&mut nvmc, uptime_min()).ok();
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+            }
+
+            write!(term, "{}", console::PROMPT).ok();
+        }
+        watchdog.check_in(Task::Comms);
+    }
+}
+
+/// Console output, sent to both the UART and the USB serial port
+struct Terminal<T: UarteInstance> {
+    uarte: Uarte<T>,
+}
+
+impl<T: UarteInstance> Terminal<T> {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.uarte.write(bytes).ok();
+        usb_serial::write(bytes);
+    }
+}
+
+impl<T: UarteInstance> fmt::Write for Terminal<T> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.uarte.write_str(s)?;
+        usb_serial::write(s.as_bytes());
+        Ok(())
     }
 }
 
-fn time2bytes(h: ds323x::Hours, m: u8) -> [u8; 4] {
-    let hour = match h {
-        ds323x::Hours::AM(am) => am,
-        ds323x::Hours::PM(pm) => pm + 12,
-        ds323x::Hours::H24(h24) => h24,
+#[interrupt]
+fn RTC1() {
+    monotonic::on_interrupt();
+}
+
+#[interrupt]
+fn USBD() {
+    usb_serial::poll();
+}
+
+#[interrupt]
+fn RADIO() {
+    ble::radio_interrupt();
+}
+
+#[interrupt]
+fn TIMER0() {
+    ble::timer_interrupt();
+}
+
+/// Force a recalibration of the SCD30, recording it in the config and the
+/// data log. Returns whether it worked.
+fn recalibrate<I, E, D, F>(
+    scd30_cfg: &mut Scd30Config<I>,
+    delay: &mut D,
+    reference_ppm: u16,
+    now: u32,
+    config: &mut Config,
+    nvmc: &mut Nvmc,
+    datalog: &mut Option<DataLog<F>>,
+) -> bool
+where
+    I: Write<Error = E> + Read<Error = E>,
+    D: DelayMs<u32>,
+    F: Flash,
+{
+    defmt::info!("Recalibrating SCD30 to {:?}ppm", reference_ppm);
+
+    if scd30_cfg.force_recalibration(reference_ppm, delay).is_err() {
+        defmt::error!("SCD30 recalibration failed!");
+        return false;
+    }
+
+    config.calibration = Calibration {
+        reference_ppm,
+        timestamp: now,
     };
+    if config.store(nvmc).is_err() {
+        defmt::error!("Failed to store calibration!");
+    }
+
+    let event = Record::Event {
+        timestamp: now,
+        kind: EventKind::Recalibrated,
+        arg: reference_ppm,
+    };
+    log_record(datalog, &event);
+    true
+}
 
-    num2bytes(((hour as u32 * 100) as u16 + m as u16) as u16)
+fn log_record<F: Flash>(datalog: &mut Option<DataLog<F>>, record: &Record) {
+    if let Some(log) = datalog.as_mut() {
+        if log.append(record).is_err() {
+            defmt::error!("Failed to write to data log!");
+        }
+    }
+}
+
+/// Set the RTC from a time reference if it has drifted, recording the
+/// correction in the data log. The RTC keeps UTC, the offset the reference
+/// came with is kept in the config.
+fn sync_time<R: Rtcc, F: Flash>(
+    rtc: &mut R,
+    reference: TimeReference,
+    config: &mut Config,
+    nvmc: &mut Nvmc,
+    datalog: &mut Option<DataLog<F>>,
+) {
+    // A new offset, e.g. for daylight saving, applies whether or not the
+    // RTC needs correcting
+    if let Some(offset) = reference.utc_offset_s {
+        if offset != config.time.utc_offset_s {
+            config.time.utc_offset_s = offset;
+            if config.store(nvmc).is_err() {
+                defmt::error!("Failed to store the UTC offset!");
+            }
+            defmt::info!("UTC offset now {:?}s", offset);
+        }
+    }
+
+    let reference = match reference.to_utc(&config.time) {
+        Some(reference) => reference,
+        None => {
+            defmt::warn!("Time reference out of range!");
+            return;
+        }
+    };
+    let rtc_now = match unix_time(rtc) {
+        Some(now) => now,
+        None => {
+            defmt::warn!("Failed to read RTC for time sync!");
+            return;
+        }
+    };
+
+    let correction = match cts::correction(rtc_now, reference) {
+        Some(correction) => correction,
+        None => {
+            defmt::info!("Clock is within {:?}s of the reference", cts::DRIFT_THRESHOLD_S);
+            return;
+        }
+    };
+
+    let dt = NaiveDateTime::from_timestamp(reference as i64, 0);
+    if rtc.set_datetime(&dt).is_err() {
+        defmt::error!("Failed to set RTC!");
+        return;
+    }
+    defmt::info!("Clock corrected by {:?}s", correction);
+
+    let event = Record::Event {
+        timestamp: reference,
+        kind: EventKind::TimeSync,
+        arg: correction.unsigned_abs().min(u16::MAX as u64) as u16,
+    };
+    log_record(datalog, &event);
+}
+
+/// The current time from the RTC, in seconds since the unix epoch
+fn unix_time<R: Rtcc>(rtc: &mut R) -> Option<u32> {
+    rtc.get_datetime().ok().map(|dt| dt.timestamp() as u32)
+}
+
+/// The local time of day, as hours (0..=23), minutes and seconds
+fn local_time<R: Rtcc>(rtc: &mut R, time: &TimeConfig) -> Option<(u8, u8, u8)> {
+    let local = DateTime::from_unix(time.local(unix_time(rtc)?));
+    Some((local.hour, local.minute, local.second))
+}
+
+fn show_text<S, D>(sevseg: &mut S, timer: &mut D, dwell_ms: u32, text: &[u8; 4])
+where
+    S: SevenSegInterface,
+    D: DelayMs<u32> + DelayUs<u32>,
+{
+    sevseg.set_cursor(0).ok();
+    timer.delay_us(100u32);
+    sevseg.write_punctuation(PunctuationFlags::NONE).ok();
+    timer.delay_us(100u32);
+    sevseg.send(text).ok();
+    timer.delay_ms(dwell_ms);
+}
+
+/// Screens shown by [`show_diagnostics`]
+const DIAGNOSTICS_PAGES: u32 = 8;
+
+/// Which unit this is and how it has been resetting, shown on a short
+/// button press. [`show_sensor_diagnostics`] follows on the next pass.
+fn show_diagnostics<S, D>(
+    sevseg: &mut S,
+    timer: &mut D,
+    dwell_ms: u32,
+    identity: &Identity,
+    boot_info: &BootInfo,
+) where
+    S: SevenSegInterface,
+    D: DelayMs<u32> + DelayUs<u32>,
+{
+    // The last four digits of the device ID are enough to tell the clocks
+    // in one room apart, `info` on the console gives the rest
+    show_text(sevseg, timer, dwell_ms, b"  id");
+    let id = identity.device_id.to_hex();
+    let mut short = [0; 4];
+    short.copy_from_slice(&id[id.len() - 4..]);
+    show_text(sevseg, timer, dwell_ms, &short);
+
+    // Why the last reset happened, how many boots there have been and how
+    // many of those were watchdog bites, crashes or lockups
+    show_text(sevseg, timer, dwell_ms, b" rSt");
+    let cause = match boot_info.cause {
+        Cause::PowerOn => b"Pon ",
+        Cause::Pin => b"PIn ",
+        Cause::Watchdog => b" dog",
+        Cause::Crash => b"CrSH",
+        Cause::SoftReset => b"SOFt",
+        Cause::Lockup => b"LOCK",
+        Cause::Wakeup => b"UAkE",
+        Cause::Debug => b"dEbg",
+    };
+    show_text(sevseg, timer, dwell_ms, cause);
+    show_text(sevseg, timer, dwell_ms, b"boot");
+    let boots = boot_info.counts.total.min(9999) as u16;
+    sevseg.write_digits(&num2bytes(boots)).ok();
+    timer.delay_ms(dwell_ms);
+    show_text(sevseg, timer, dwell_ms, b"bAd ");
+    let unexpected = boot_info.counts.unexpected().min(9999) as u16;
+    sevseg.write_digits(&num2bytes(unexpected)).ok();
+    timer.delay_ms(dwell_ms);
+}
+
+/// Screens shown by [`show_sensor_diagnostics`]
+const SENSOR_DIAGNOSTICS_PAGES: u32 = 5;
+
+/// Calibration status and bus health, the second half of the diagnostics
+fn show_sensor_diagnostics<S, D>(
+    sevseg: &mut S,
+    timer: &mut D,
+    dwell_ms: u32,
+    config: &Config,
+    now: u32,
+) where
+    S: SevenSegInterface,
+    D: DelayMs<u32> + DelayUs<u32>,
+{
+    show_text(sevseg, timer, dwell_ms, b" cAL");
+    match config.calibration.age_days(now) {
+        Some(days) => {
+            sevseg.write_digits(&num2bytes(days.min(999) as u16)).ok();
+            timer.delay_us(100u32);
+            sevseg.set_cursor(3).ok();
+            timer.delay_us(100u32);
+            sevseg.send(b"d").ok();
+            timer.delay_ms(dwell_ms);
+        }
+        None => show_text(sevseg, timer, dwell_ms, b"----"),
+    }
+
+    let asc = if config.scd30.asc_enabled { b"AS 1" } else { b"AS 0" };
+    show_text(sevseg, timer, dwell_ms, asc);
+
+    show_text(sevseg, timer, dwell_ms, b" i2c");
+    let recoveries = i2c_recovery::recovery_count().min(9999) as u16;
+    sevseg.write_digits(&num2bytes(recoveries)).ok();
+    timer.delay_ms(dwell_ms);
+}
+
+/// The crash that caused the last reset: what it was, then its code
+fn show_crash<S, D>(sevseg: &mut S, timer: &mut D, dwell_ms: u32, crash: &Crash)
+where
+    S: SevenSegInterface,
+    D: DelayMs<u32> + DelayUs<u32>,
+{
+    let code = crash.code();
+    match crash.kind {
+        CrashKind::Panic => {
+            show_text(sevseg, timer, dwell_ms, b"PAnC");
+            sevseg.write_digits(&num2bytes(code)).ok();
+            timer.delay_ms(dwell_ms);
+        }
+        CrashKind::HardFault => {
+            // The code is part of an address, so show it in hex
+            show_text(sevseg, timer, dwell_ms, b"FLt ");
+            let mut hex = [0; 4];
+            for (i, digit) in hex.iter_mut().enumerate() {
+                let nibble = (code >> ((3 - i) * 4)) as u8 & 0xF;
+                *digit = match nibble {
+                    0..=9 => b'0' + nibble,
+                    _ => b'a' + nibble - 10,
+                };
+            }
+            show_text(sevseg, timer, dwell_ms, &hex);
+        }
+    }
+}
+
+/// Screens shown by [`show_measurement`]
+const MEASUREMENT_PAGES: u32 = 5;
+
+fn show_measurement<S, D>(
+    sevseg: &mut S,
+    timer: &mut D,
+    dwell_ms: u32,
+    units: &UnitsConfig,
+    meas: &Measurement,
+) where
+    S: SevenSegInterface,
+    D: DelayMs<u32> + DelayUs<u32>,
+{
+    show_text(sevseg, timer, dwell_ms, b" co2");
+    let co2 = digits::render(digits::centi(meas.co2), 2, 0, b"");
+    show_digits(sevseg, timer, dwell_ms, &co2);
+
+    let unit = units.temperature;
+    let temp = unit.from_centi_celsius(digits::centi(meas.temp));
+    let temp = digits::render(temp, 2, 1, unit.symbol());
+    show_digits(sevseg, timer, dwell_ms, &temp);
+
+    // No room for the label next to 100.0
+    show_text(sevseg, timer, dwell_ms, b"  rh");
+    let rh = digits::render(digits::centi(meas.rh), 2, 1, b"");
+    show_digits(sevseg, timer, dwell_ms, &rh);
+}
+
+/// Screens shown by [`show_comfort`]
+const COMFORT_PAGES: u32 = 6;
+
+/// Dew point, absolute humidity and heat index, worked out from a reading
+fn show_comfort<S, D>(
+    sevseg: &mut S,
+    timer: &mut D,
+    dwell_ms: u32,
+    units: &UnitsConfig,
+    meas: &Measurement,
+) where
+    S: SevenSegInterface,
+    D: DelayMs<u32> + DelayUs<u32>,
+{
+    let comfort = match Comfort::from_f32(meas.temp, meas.rh) {
+        Some(comfort) => comfort,
+        None => return,
+    };
+    let unit = units.temperature;
+
+    show_text(sevseg, timer, dwell_ms, b"dEUP");
+    let dew = unit.from_centi_celsius(comfort.dew_point_cdeg as i32);
+    let dew = digits::render(dew, 2, 1, unit.symbol());
+    show_digits(sevseg, timer, dwell_ms, &dew);
+
+    // Grams of water per cubic metre
+    show_text(sevseg, timer, dwell_ms, b" AbS");
+    let abs = digits::render(comfort.abs_humidity_cg_m3 as i32, 2, 1, b"");
+    show_digits(sevseg, timer, dwell_ms, &abs);
+
+    show_text(sevseg, timer, dwell_ms, b"HEAt");
+    let heat = unit.from_centi_celsius(comfort.heat_index_cdeg as i32);
+    let heat = digits::render(heat, 2, 1, unit.symbol());
+    show_digits(sevseg, timer, dwell_ms, &heat);
+}
+
+/// A number laid out by `digits::render`
+fn show_digits<S, D>(sevseg: &mut S, timer: &mut D, dwell_ms: u32, digits: &Digits)
+where
+    S: SevenSegInterface,
+    D: DelayMs<u32> + DelayUs<u32>,
+{
+    let dot = match digits.dot {
+        Some(0) => PunctuationFlags::DOT_BETWEEN_1_AND_2,
+        Some(1) => PunctuationFlags::DOT_BETWEEN_2_AND_3,
+        Some(2) => PunctuationFlags::DOT_BETWEEN_3_AND_4,
+        Some(_) => PunctuationFlags::DOT_RIGHT_OF_4,
+        None => PunctuationFlags::NONE,
+    };
+
+    sevseg.set_cursor(0).ok();
+    timer.delay_us(100u32);
+    sevseg.write_punctuation(dot).ok();
+    timer.delay_us(100u32);
+    sevseg.send(&digits.text).ok();
+    timer.delay_ms(dwell_ms);
+
+    sevseg.write_punctuation(PunctuationFlags::NONE).ok();
+    timer.delay_us(100u32);
+}
+
+/// Screens shown by [`show_trend`], at most
+const TREND_PAGES: u32 = 4;
+
+/// Which way CO2 is heading, in ppm/min, and how long until the next
+/// alert threshold if that is soon
+fn show_trend<S, D>(
+    sevseg: &mut S,
+    timer: &mut D,
+    dwell_ms: u32,
+    trend: &Trend,
+    alert: &AlertConfig,
+) where
+    S: SevenSegInterface,
+    D: DelayMs<u32> + DelayUs<u32>,
+{
+    let text = match trend.direction() {
+        Direction::Rising => b"  UP",
+        Direction::Steady => b"StdY",
+        Direction::Falling => b"  dn",
+    };
+    show_text(sevseg, timer, dwell_ms, text);
+    let rate = digits::render(trend.rate_cppm_min, 2, 1, b"");
+    show_digits(sevseg, timer, dwell_ms, &rate);
+
+    // "OPEn" "in12", for open a window in about 12 minutes
+    if let Some(projection) = trend.projection(alert) {
+        show_text(sevseg, timer, dwell_ms, b"OPEn");
+        let mut text = digits::render(projection.minutes as i32, 0, 0, b"").text;
+        text[..2].copy_from_slice(b"in");
+        show_text(sevseg, timer, dwell_ms, &text);
+    }
+}
+
+/// Log statistics for the hour that just ended
+fn hourly_report(history: &History, now: u32) {
+    let from = now.saturating_sub(3600);
+    let fields = [
+        ("co2", Field::Co2),
+        ("temp", Field::Temperature),
+        ("rh", Field::Humidity),
+    ];
+
+    for (name, field) in fields.iter() {
+        if let Some(st) = history.stats(*field, from, now + 1) {
+            let p95 = history.percentile(*field, from, now + 1, 95).unwrap_or(st.max);
+            defmt::info!(
+                "hourly {=str}: min {:?} max {:?} mean {:?} p95 {:?} (n={:?})",
+                name,
+                st.min,
+                st.max,
+                st.mean,
+                p95,
+                st.count
+            );
+        }
+    }
+}
+
+/// Screens shown by [`show_daily_max`]
+const DAILY_MAX_PAGES: u32 = 2;
+
+fn show_daily_max<S, D>(sevseg: &mut S, timer: &mut D, dwell_ms: u32, co2_ppm: u16)
+where
+    S: SevenSegInterface,
+    D: DelayMs<u32> + DelayUs<u32>,
+{
+    show_text(sevseg, timer, dwell_ms, b"  hi");
+    sevseg.write_digits(&num2bytes(co2_ppm)).ok();
+    timer.delay_ms(dwell_ms);
+}
+
+/// Alternate between "co2" and the current value, flashing the display
+fn show_alert<S, D>(sevseg: &mut S, timer: &mut D, co2_ppm: u16)
+where
+    S: SevenSegInterface,
+    D: DelayMs<u32> + DelayUs<u32>,
+{
+    sevseg.write_punctuation(PunctuationFlags::NONE).ok();
+    timer.delay_us(100u32);
+
+    for _ in 0..4 {
+        sevseg.set_cursor(0).ok();
+        timer.delay_us(100u32);
+        sevseg.send(b" co2").ok();
+        timer.delay_ms(500u32);
+        sevseg.write_digits(&num2bytes(co2_ppm)).ok();
+        timer.delay_ms(500u32);
+        sevseg.clear().ok();
+        timer.delay_ms(250u32);
+    }
+}
+
+/// Minutes since boot. Unlike counting minutes on the RTC, this doesn't
+/// jump when the clock is set.
+fn uptime_min() -> u32 {
+    (monotonic::now().since_start().as_secs() / 60) as u32
+}
+
+/// Screens in the uptime group: uptime, "totL" and the total
+const UPTIME_PAGES: u32 = 3;
+
+/// A number of minutes, in hours or days depending on how many there are
+fn show_uptime<S, D>(sevseg: &mut S, timer: &mut D, dwell_ms: u32, minutes: u32)
+where
+    S: SevenSegInterface,
+    D: DelayMs<u32> + DelayUs<u32>,
+{
+    if let Some(readout) = uptime::readout(minutes) {
+        let dot = if readout.dot {
+            PunctuationFlags::DOT_BETWEEN_2_AND_3
+        } else {
+            PunctuationFlags::NONE
+        };
+        let unit = match readout.unit {
+            Unit::Hours => b"h",
+            Unit::Days => b"d",
+        };
+
+        sevseg.write_digits(&num2bytes(readout.digits)).ok();
+        timer.delay_us(100u32);
+        sevseg.write_punctuation(dot).ok();
+        timer.delay_us(100u32);
+        sevseg.set_cursor(3).ok();
+        timer.delay_us(100u32);
+        sevseg.send(unit).ok();
+        timer.delay_ms(dwell_ms);
+        sevseg.write_punctuation(PunctuationFlags::NONE).ok();
+        timer.delay_us(100u32);
+    }
+}
+
+/// The time of day, in the configured format. Returns whether it was
+/// shown.
+fn write_time<S, D>(sevseg: &mut S, timer: &mut D, clock: ClockFormat, h: u8, m: u8) -> bool
+where
+    S: SevenSegInterface,
+    D: DelayUs<u32>,
+{
+    if sevseg.set_cursor(0).is_err() {
+        return false;
+    }
+    timer.delay_us(15u32);
+    sevseg.send(&clock.digits(h, m)).is_ok()
 }
 
 fn num2bytes(mut num: u16) -> [u8; 4] {
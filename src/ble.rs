@@ -0,0 +1,384 @@
+//! BLE peripheral, advertising the clock and serving the Environmental
+//! Sensing, Current Time and Device Information services from `gatt`
+//!
+//! The link layer has hard timing requirements, so like the USB serial port
+//! everything runs from interrupts: the caller must unmask RADIO and
+//! TIMER0, and call [`radio_interrupt`] and [`timer_interrupt`] from them.
+//! The main loop only hands over new readings with [`update`], asks for the
+//! time with [`request_time`], and picks up the time written by a gateway
+//! with [`take_time`].
+//!
+//! While nobody is connected the advertisements double as a beacon, see
+//! `beacon`, so they are restarted with fresh data on every reading.
+
+use core::cell::RefCell;
+
+use cortex_m::interrupt::{self, Mutex};
+use nrf52840_hal::pac::{FICR, RADIO, TIMER0};
+use rubble::{
+    att::{AttUuid, Attribute, AttributeAccessPermissions, AttributeProvider, Handle, HandleRange},
+    config::Config,
+    l2cap::{BleChannelMap, L2CAPState},
+    link::{
+        ad_structure::{AdStructure, ServiceUuids},
+        queue::{PacketQueue, SimpleQueue},
+        LinkLayer, Responder, MIN_PDU_BUF,
+    },
+    security::NoSecurity,
+    time::{Duration, Instant, Timer},
+    uuid::Uuid16,
+    Error,
+};
+use rubble_nrf5x::{
+    radio::{BleRadio, PacketBuffer},
+    timer::BleTimer,
+    utils::get_device_address,
+};
+
+use crate::{
+    beacon::{self, Beacon},
+    cts::TimeReference,
+    gatt::{self, Database, LAST_HANDLE},
+    history::Sample,
+    identity::Identity,
+};
+
+/// Name in the advertising data
+const DEVICE_NAME: &str = "fleet-clock";
+
+/// Advertising is also the beacon, so keep the duty cycle low
+const ADVERTISING_INTERVAL_MS: u32 = 1000;
+
+/// AD type for the 16-bit UUIDs of services the device would like a
+/// central to provide
+const AD_SERVICE_SOLICITATION_16: u8 = 0x14;
+
+pub enum BleConfig {}
+
+impl Config for BleConfig {
+    type Timer = BleTimer<TIMER0>;
+    type Transmitter = BleRadio;
+    type ChannelMapper = BleChannelMap<Attributes, NoSecurity>;
+    type PacketQueue = &'static mut SimpleQueue;
+}
+
+/// Kept apart from the rest of the state so it survives the responder
+/// being replaced when advertising restarts
+static DB: Mutex<RefCell<Database>> = Mutex::new(RefCell::new(Database::new()));
+
+fn with_db<R>(f: impl FnOnce(&mut Database) -> R) -> R {
+    interrupt::free(|cs| f(&mut DB.borrow(cs).borrow_mut()))
+}
+
+/// Adapts the database to the ATT server
+pub struct Attributes {
+    /// The ATT server wants to borrow the attributes that end each service
+    /// group, rather than just know their handles
+    group_ends: [Attribute<gatt::Value>; 3],
+}
+
+impl Attributes {
+    fn new() -> Self {
+        let end = |handle| {
+            let end = Database::group_end(handle).unwrap();
+            convert(with_db(|db| db.attribute(end)).unwrap())
+        };
+        Self {
+            group_ends: [
+                end(gatt::ESS_HANDLE),
+                end(gatt::CTS_HANDLE),
+                end(gatt::DIS_HANDLE),
+            ],
+        }
+    }
+}
+
+fn convert(attr: gatt::Attribute) -> Attribute<gatt::Value> {
+    Attribute::new(
+        AttUuid::Uuid16(Uuid16(attr.uuid)),
+        Handle::from_raw(attr.handle),
+        attr.value,
+    )
+}
+
+impl AttributeProvider for Attributes {
+    fn for_attrs_in_range(
+        &mut self,
+        range: HandleRange,
+        mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let start = range.start().as_u16().max(gatt::ESS_HANDLE);
+        let end = range.end().as_u16().min(LAST_HANDLE);
+        for handle in start..=end {
+            if let Some(attr) = with_db(|db| db.attribute(handle)) {
+                f(self, &convert(attr))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+        uuid == AttUuid::Uuid16(Uuid16(gatt::uuid::PRIMARY_SERVICE))
+    }
+
+    fn group_end(&self, handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
+        let end = Database::group_end(handle.as_u16())?;
+        self.group_ends
+            .iter()
+            .find(|attr| attr.handle.as_u16() == end)
+            .map(|attr| attr as &Attribute<dyn AsRef<[u8]>>)
+    }
+
+    fn attr_access_permissions(&self, handle: Handle) -> AttributeAccessPermissions {
+        let writable = [
+            gatt::uuid::CLIENT_CONFIG,
+            gatt::uuid::CURRENT_TIME,
+            gatt::uuid::LOCAL_TIME_INFO,
+        ];
+        match with_db(|db| db.attribute(handle.as_u16())) {
+            Some(attr) if writable.contains(&attr.uuid) => {
+                AttributeAccessPermissions::ReadableAndWriteable
+            }
+            _ => AttributeAccessPermissions::Readable,
+        }
+    }
+
+    fn write_attr(&mut self, handle: Handle, data: &[u8]) -> Result<(), Error> {
+        with_db(|db| db.write(handle.as_u16(), data)).map_err(|e| {
+            defmt::warn!("Rejected GATT write to {:?}: {:?}", handle.as_u16(), e);
+            Error::InvalidValue
+        })
+    }
+}
+
+// Packet queues between the link layer and the responder. The link layer
+// only gives its halves up when it is handed new ones, so there are two
+// pairs and each restart of advertising takes the other, see `advertise`.
+static mut TX_QUEUES: [SimpleQueue; 2] = [SimpleQueue::new(), SimpleQueue::new()];
+static mut RX_QUEUES: [SimpleQueue; 2] = [SimpleQueue::new(), SimpleQueue::new()];
+
+struct State {
+    radio: BleRadio,
+    ll: LinkLayer<BleConfig>,
+    responder: Responder<BleConfig>,
+    /// Which pair of packet queues is in use
+    queues: usize,
+    /// The latest reading, for the beacon
+    beacon: Option<Beacon>,
+    /// The last time written, and when it arrived
+    time: Option<(TimeReference, Instant)>,
+    /// Solicit the Current Time Service, see `cts`
+    solicit_time: bool,
+}
+
+static STATE: Mutex<RefCell<Option<State>>> = Mutex::new(RefCell::new(None));
+
+/// Start advertising
+pub fn init(radio: RADIO, timer: TIMER0, ficr: &FICR, identity: &Identity) {
+    with_db(|db| db.set_identity(identity));
+
+    let tx_buf = cortex_m::singleton!(: PacketBuffer = [0; MIN_PDU_BUF]).unwrap();
+    let rx_buf = cortex_m::singleton!(: PacketBuffer = [0; MIN_PDU_BUF]).unwrap();
+
+    let mut radio = BleRadio::new(radio, ficr, tx_buf, rx_buf);
+    let mut ll = LinkLayer::<BleConfig>::new(get_device_address(), BleTimer::init(timer));
+    let responder = advertise(&mut ll, &mut radio, 0, None, false);
+
+    interrupt::free(|cs| {
+        STATE.borrow(cs).replace(Some(State {
+            radio,
+            ll,
+            responder,
+            queues: 0,
+            beacon: None,
+            time: None,
+            solicit_time: false,
+        }));
+    });
+}
+
+/// (Re)start advertising on the given pair of queues, returning the
+/// responder for the next connection. Must not be called while connected.
+fn advertise(
+    ll: &mut LinkLayer<BleConfig>,
+    radio: &mut BleRadio,
+    queues: usize,
+    beacon: Option<&Beacon>,
+    solicit_time: bool,
+) -> Responder<BleConfig> {
+    // SAFETY: each pair is only split once per use. The last use of this
+    // pair was two starts ago: its responder has been replaced since, and
+    // the link layer dropped its halves when it was last started. Start
+    // empty, a lost connection may have left packets behind.
+    let (tx_queue, rx_queue) = unsafe {
+        TX_QUEUES[queues] = SimpleQueue::new();
+        RX_QUEUES[queues] = SimpleQueue::new();
+        (&mut TX_QUEUES[queues], &mut RX_QUEUES[queues])
+    };
+    let (tx, tx_cons) = tx_queue.split();
+    let (rx_prod, rx) = rx_queue.split();
+
+    let responder = Responder::new(
+        tx,
+        rx,
+        L2CAPState::new(BleChannelMap::with_attributes(Attributes::new())),
+    );
+
+    // Without flags, the full name and the beacon data just fit in the 31
+    // bytes available. While the time is wanted, the solicitation takes
+    // the place of the service list, which is the same size.
+    let interval = Duration::from_millis(ADVERTISING_INTERVAL_MS);
+    let services = [Uuid16(gatt::uuid::ENVIRONMENTAL_SENSING)];
+    let solicited = gatt::uuid::CURRENT_TIME_SERVICE.to_le_bytes();
+    let service_uuids = if solicit_time {
+        AdStructure::Unknown {
+            ty: AD_SERVICE_SOLICITATION_16,
+            data: &solicited,
+        }
+    } else {
+        AdStructure::ServiceUuids16(ServiceUuids::from_uuids(true, &services))
+    };
+    let name = AdStructure::CompleteLocalName(DEVICE_NAME);
+    let next_update = match beacon.map(Beacon::encode) {
+        Some(data) => {
+            let beacon = AdStructure::Unknown {
+                ty: beacon::AD_TYPE,
+                data: &data,
+            };
+            let ad = [service_uuids, name, beacon];
+            ll.start_advertise(interval, &ad, radio, tx_cons, rx_prod)
+        }
+        None => {
+            let ad = [service_uuids, name];
+            ll.start_advertise(interval, &ad, radio, tx_cons, rx_prod)
+        }
+    }
+    .unwrap();
+    ll.timer().configure_interrupt(next_update);
+
+    // A new connection starts with notifications off
+    with_db(Database::reset_notifications);
+
+    responder
+}
+
+/// Service the radio. Call this from the RADIO interrupt.
+pub fn radio_interrupt() {
+    with_state(|state| {
+        let now = state.ll.timer().now();
+        if let Some(cmd) = state.radio.recv_interrupt(now, &mut state.ll) {
+            state.radio.configure_receiver(cmd.radio);
+            state.ll.timer().configure_interrupt(cmd.next_update);
+            if cmd.queued_work {
+                process(state);
+            }
+        }
+    });
+}
+
+/// Service the link layer timer. Call this from the TIMER0 interrupt.
+pub fn timer_interrupt() {
+    with_state(|state| {
+        let timer = state.ll.timer();
+        if !timer.is_interrupt_pending() {
+            return;
+        }
+        timer.clear_interrupt();
+
+        let cmd = state.ll.update_timer(&mut state.radio);
+        state.radio.configure_receiver(cmd.radio);
+        state.ll.timer().configure_interrupt(cmd.next_update);
+        if cmd.queued_work {
+            process(state);
+        }
+
+        // The link layer goes idle once a connection is lost
+        if !state.ll.is_connected() && !state.ll.is_advertising() {
+            defmt::info!("BLE disconnected");
+            restart_advertising(state);
+        }
+    });
+}
+
+/// Publish a new reading. A connected client is notified if it asked to be,
+/// otherwise the beacon is updated.
+pub fn update(sample: &Sample, uptime_min: u32) {
+    let db = with_db(|db| {
+        db.update(sample);
+        db.clone()
+    });
+
+    with_state(|state| {
+        state.beacon = Some(Beacon::new(sample, uptime_min));
+
+        if state.ll.is_connected() {
+            for (handle, value) in db.notifications() {
+                state
+                    .responder
+                    .l2cap()
+                    .att()
+                    .notify_raw(Handle::from_raw(handle), &value);
+            }
+        } else {
+            restart_advertising(state);
+        }
+    });
+}
+
+/// Ask time sources to connect and set the clock, while `wanted`. Only
+/// takes effect once nobody is connected.
+pub fn request_time(wanted: bool) {
+    with_state(|state| {
+        if state.solicit_time != wanted {
+            state.solicit_time = wanted;
+            if !state.ll.is_connected() {
+                restart_advertising(state);
+            }
+        }
+    });
+}
+
+fn restart_advertising(state: &mut State) {
+    state.queues ^= 1;
+    state.responder = advertise(
+        &mut state.ll,
+        &mut state.radio,
+        state.queues,
+        state.beacon.as_ref(),
+        state.solicit_time,
+    );
+}
+
+/// The time last written by a gateway, allowing for how long ago it was
+/// written
+pub fn take_time() -> Option<TimeReference> {
+    let mut time = None;
+    with_state(|state| {
+        time = state.time.take().map(|(mut reference, written)| {
+            let elapsed = state.ll.timer().now().duration_since(written);
+            reference.local = reference.local.saturating_add(elapsed.whole_secs());
+            reference
+        });
+    });
+    time
+}
+
+fn process(state: &mut State) {
+    while state.responder.has_work() {
+        if state.responder.process_one().is_err() {
+            defmt::warn!("BLE responder error");
+        }
+    }
+
+    if let Some(reference) = with_db(Database::take_time) {
+        state.time = Some((reference, state.ll.timer().now()));
+    }
+}
+
+fn with_state(f: impl FnOnce(&mut State)) {
+    interrupt::free(|cs| {
+        if let Some(state) = STATE.borrow(cs).borrow_mut().as_mut() {
+            f(state);
+        }
+    });
+}
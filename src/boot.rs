@@ -0,0 +1,381 @@
+//! Why the clock started, and how often it has
+//!
+//! POWER.RESETREAS says what caused the last reset. The bits stick until
+//! cleared, so they are read and cleared once at boot and turned into a
+//! single [`Cause`]. A crash dump left behind by the last run (see
+//! `crash`) takes precedence, as the reset that follows a crash shows up
+//! as a soft reset, a lockup or a watchdog bite depending on the build.
+//! Brown-outs reset the chip the same way as powering it on, so they count
+//! as power-on too.
+//!
+//! The number of boots, in total and per cause, is kept in a page of
+//! internal flash so units that keep rebooting can be spotted. Every boot
+//! appends a new copy of the counters to the page rather than erasing it,
+//! so the page is only erased once every [`SLOTS`] boots. If power is lost
+//! while the page is being rewritten the counters start over.
+
+use core::fmt;
+
+use crate::{crc::crc32, flash::Flash};
+
+/// Number of distinct causes
+pub const CAUSES: usize = 8;
+
+/// Bits of POWER.RESETREAS
+const RESETPIN: u32 = 1 << 0;
+const DOG: u32 = 1 << 1;
+const SREQ: u32 = 1 << 2;
+const LOCKUP: u32 = 1 << 3;
+const OFF: u32 = 1 << 16;
+const LPCOMP: u32 = 1 << 17;
+const DIF: u32 = 1 << 18;
+const NFC: u32 = 1 << 19;
+const VBUS: u32 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Cause {
+    /// Power was applied, or came back after a brown-out
+    PowerOn = 0,
+    /// The reset pin
+    Pin = 1,
+    Watchdog = 2,
+    /// A panic or HardFault, see `crash`
+    Crash = 3,
+    /// Requested by the firmware, e.g. the console `reset` command
+    SoftReset = 4,
+    /// The CPU locked up
+    Lockup = 5,
+    /// Woken from System OFF, by a GPIO, LPCOMP, NFC or USB
+    Wakeup = 6,
+    /// The debugger
+    Debug = 7,
+}
+
+impl Cause {
+    pub const ALL: [Cause; CAUSES] = [
+        Cause::PowerOn,
+        Cause::Pin,
+        Cause::Watchdog,
+        Cause::Crash,
+        Cause::SoftReset,
+        Cause::Lockup,
+        Cause::Wakeup,
+        Cause::Debug,
+    ];
+
+    /// Work out the cause from POWER.RESETREAS. More than one bit can be
+    /// set if they weren't cleared last time, the most serious one wins.
+    pub fn from_resetreas(bits: u32, crashed: bool) -> Self {
+        if crashed {
+            Cause::Crash
+        } else if bits & DOG != 0 {
+            Cause::Watchdog
+        } else if bits & LOCKUP != 0 {
+            Cause::Lockup
+        } else if bits & SREQ != 0 {
+            Cause::SoftReset
+        } else if bits & RESETPIN != 0 {
+            Cause::Pin
+        } else if bits & (OFF | LPCOMP | NFC | VBUS) != 0 {
+            Cause::Wakeup
+        } else if bits & DIF != 0 {
+            Cause::Debug
+        } else {
+            Cause::PowerOn
+        }
+    }
+
+    /// Read the cause of the last reset, and clear the register for next
+    /// time
+    #[cfg(feature = "firmware")]
+    pub fn read(power: &nrf52840_hal::pac::POWER, crashed: bool) -> Self {
+        let bits = power.resetreas.read().bits();
+        // Write one to clear
+        power.resetreas.write(|w| unsafe { w.bits(bits) });
+        Self::from_resetreas(bits, crashed)
+    }
+
+    /// Resets nobody asked for
+    pub fn is_unexpected(self) -> bool {
+        matches!(self, Cause::Watchdog | Cause::Crash | Cause::Lockup)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Cause::PowerOn => "power-on",
+            Cause::Pin => "pin",
+            Cause::Watchdog => "watchdog",
+            Cause::Crash => "crash",
+            Cause::SoftReset => "soft",
+            Cause::Lockup => "lockup",
+            Cause::Wakeup => "wakeup",
+            Cause::Debug => "debug",
+        }
+    }
+}
+
+/// "BOOT", little endian
+const MAGIC: u32 = 0x544F_4F42;
+
+/// Magic, total, one count per cause, CRC, padded to a multiple of four
+/// words
+const SLOT_LEN: usize = 48;
+
+/// Copies of the counters that fit in the page
+pub const SLOTS: usize = 4096 / SLOT_LEN;
+
+/// Boots since the counters were last reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct BootCounts {
+    pub total: u32,
+    by_cause: [u32; CAUSES],
+}
+
+impl BootCounts {
+    /// Address of the flash page holding the counters
+    pub const PAGE: u32 = 0x000F_D000;
+
+    pub fn get(&self, cause: Cause) -> u32 {
+        self.by_cause[cause as usize]
+    }
+
+    /// Watchdog bites, crashes and lockups
+    pub fn unexpected(&self) -> u32 {
+        Cause::ALL
+            .iter()
+            .filter(|cause| cause.is_unexpected())
+            .map(|cause| self.get(*cause))
+            .sum()
+    }
+
+    pub fn count(&mut self, cause: Cause) {
+        self.total = self.total.wrapping_add(1);
+        let n = &mut self.by_cause[cause as usize];
+        *n = n.wrapping_add(1);
+    }
+
+    fn to_bytes(self) -> [u8; SLOT_LEN] {
+        let mut buf = [0; SLOT_LEN];
+        buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.total.to_le_bytes());
+        for (i, n) in self.by_cause.iter().enumerate() {
+            buf[8 + i * 4..][..4].copy_from_slice(&n.to_le_bytes());
+        }
+        let crc_at = 8 + CAUSES * 4;
+        let crc = crc32(&buf[..crc_at]);
+        buf[crc_at..][..4].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; SLOT_LEN]) -> Option<Self> {
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+
+        let crc_at = 8 + CAUSES * 4;
+        if u32_at(0) != MAGIC || u32_at(crc_at) != crc32(&buf[..crc_at]) {
+            return None;
+        }
+        let mut counts = Self {
+            total: u32_at(4),
+            ..Self::default()
+        };
+        for (i, n) in counts.by_cause.iter_mut().enumerate() {
+            *n = u32_at(8 + i * 4);
+        }
+        Some(counts)
+    }
+
+    /// The newest stored counters, if any, and the first free slot
+    fn find<F: Flash>(flash: &mut F) -> Result<(Option<Self>, Option<usize>), F::Error> {
+        let mut newest = None;
+        let mut buf = [0; SLOT_LEN];
+        for slot in 0..SLOTS {
+            flash.read(Self::PAGE + (slot * SLOT_LEN) as u32, &mut buf)?;
+            if buf.iter().all(|b| *b == 0xFF) {
+                return Ok((newest, Some(slot)));
+            }
+            // A torn write is skipped, the copy before it still counts
+            if let Some(counts) = Self::from_bytes(&buf) {
+                newest = Some(counts);
+            }
+        }
+        Ok((newest, None))
+    }
+
+    /// The stored counters, all zero if there aren't any
+    pub fn load<F: Flash>(flash: &mut F) -> Result<Self, F::Error> {
+        Ok(Self::find(flash)?.0.unwrap_or_default())
+    }
+
+    /// Count a boot and store the new counters
+    pub fn record<F: Flash>(flash: &mut F, cause: Cause) -> Result<Self, F::Error> {
+        let (newest, free) = Self::find(flash)?;
+        let mut counts = newest.unwrap_or_default();
+        counts.count(cause);
+
+        let slot = match free {
+            Some(slot) => slot,
+            None => {
+                flash.erase(Self::PAGE)?;
+                0
+            }
+        };
+        flash.write(Self::PAGE + (slot * SLOT_LEN) as u32, &counts.to_bytes())?;
+        Ok(counts)
+    }
+}
+
+/// `key=value` pairs: the total, then every cause
+impl fmt::Display for BootCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "boots={}", self.total)?;
+        for cause in Cause::ALL.iter() {
+            write!(f, " {}={}", cause.name(), self.get(*cause))?;
+        }
+        Ok(())
+    }
+}
+
+/// This boot: why it happened, and the counters including it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct BootInfo {
+    pub cause: Cause,
+    pub counts: BootCounts,
+}
+
+impl BootInfo {
+    /// Read the cause of the last reset and count this boot. Call this
+    /// once, early at boot.
+    #[cfg(feature = "firmware")]
+    pub fn read<F: Flash>(power: &nrf52840_hal::pac::POWER, crashed: bool, flash: &mut F) -> Self {
+        let cause = Cause::read(power, crashed);
+        let counts = BootCounts::record(flash, cause).unwrap_or_else(|_| {
+            defmt::warn!("Failed to store the boot counters");
+            let mut counts = BootCounts::default();
+            counts.count(cause);
+            counts
+        });
+        Self { cause, counts }
+    }
+
+    /// Report over defmt
+    #[cfg(feature = "firmware")]
+    pub fn log(&self) {
+        defmt::info!(
+            "reset by {=str}, boot {=u32}, {=u32} unexpected",
+            self.cause.name(),
+            self.counts.total,
+            self.counts.unexpected()
+        );
+        for cause in Cause::ALL.iter() {
+            defmt::info!("  {=str}: {=u32}", cause.name(), self.counts.get(*cause));
+        }
+    }
+}
+
+impl fmt::Display for BootInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "reset={} {}", self.cause.name(), self.counts)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::flash::MemFlash;
+
+    fn blank() -> MemFlash {
+        MemFlash::new(vec![0xFFu8; 0x0010_0000])
+    }
+
+    #[test]
+    fn causes() {
+        assert_eq!(Cause::from_resetreas(0, false), Cause::PowerOn);
+        assert_eq!(Cause::from_resetreas(RESETPIN, false), Cause::Pin);
+        assert_eq!(Cause::from_resetreas(DOG, false), Cause::Watchdog);
+        assert_eq!(Cause::from_resetreas(SREQ, false), Cause::SoftReset);
+        assert_eq!(Cause::from_resetreas(LOCKUP, false), Cause::Lockup);
+        assert_eq!(Cause::from_resetreas(VBUS, false), Cause::Wakeup);
+        assert_eq!(Cause::from_resetreas(DIF, false), Cause::Debug);
+
+        // Left over from an earlier reset
+        assert_eq!(
+            Cause::from_resetreas(RESETPIN | DOG, false),
+            Cause::Watchdog
+        );
+        // A panic followed by `sys_reset`
+        assert_eq!(Cause::from_resetreas(SREQ, true), Cause::Crash);
+
+        for (i, cause) in Cause::ALL.iter().enumerate() {
+            assert_eq!(*cause as usize, i);
+        }
+        assert!(Cause::Crash.is_unexpected());
+        assert!(!Cause::Pin.is_unexpected());
+    }
+
+    #[test]
+    fn counting() {
+        let mut flash = blank();
+        assert_eq!(BootCounts::load(&mut flash).unwrap(), BootCounts::default());
+
+        BootCounts::record(&mut flash, Cause::PowerOn).unwrap();
+        BootCounts::record(&mut flash, Cause::Watchdog).unwrap();
+        let counts = BootCounts::record(&mut flash, Cause::Watchdog).unwrap();
+        assert_eq!(counts.total, 3);
+        assert_eq!(counts.get(Cause::PowerOn), 1);
+        assert_eq!(counts.get(Cause::Watchdog), 2);
+        assert_eq!(counts.get(Cause::Pin), 0);
+        assert_eq!(counts.unexpected(), 2);
+        assert_eq!(BootCounts::load(&mut flash).unwrap(), counts);
+
+        let info = BootInfo {
+            cause: Cause::Watchdog,
+            counts,
+        };
+        assert_eq!(
+            info.to_string(),
+            "reset=watchdog boots=3 power-on=1 pin=0 watchdog=2 crash=0 soft=0 lockup=0 \
+             wakeup=0 debug=0"
+        );
+    }
+
+    #[test]
+    fn wraps_around_the_page() {
+        let mut flash = blank();
+        for _ in 0..SLOTS {
+            BootCounts::record(&mut flash, Cause::Pin).unwrap();
+        }
+        let (_, free) = BootCounts::find(&mut flash).unwrap();
+        assert_eq!(free, None);
+
+        // The page is erased and starts over with the running totals
+        let counts = BootCounts::record(&mut flash, Cause::Crash).unwrap();
+        assert_eq!(counts.total, SLOTS as u32 + 1);
+        assert_eq!(counts.get(Cause::Crash), 1);
+        let (newest, free) = BootCounts::find(&mut flash).unwrap();
+        assert_eq!(newest, Some(counts));
+        assert_eq!(free, Some(1));
+    }
+
+    #[test]
+    fn torn_writes_are_skipped() {
+        let mut flash = blank();
+        BootCounts::record(&mut flash, Cause::PowerOn).unwrap();
+        let before = BootCounts::record(&mut flash, Cause::PowerOn).unwrap();
+
+        // Power lost part way through writing the third copy
+        let torn = BootCounts::PAGE + 2 * SLOT_LEN as u32;
+        flash
+            .write(torn, &[0x42, 0x4F, 0x4F, 0x54, 3, 0, 0, 0])
+            .unwrap();
+        assert_eq!(BootCounts::load(&mut flash).unwrap(), before);
+
+        let after = BootCounts::record(&mut flash, Cause::Pin).unwrap();
+        assert_eq!(after.total, 3);
+        let (_, free) = BootCounts::find(&mut flash).unwrap();
+        assert_eq!(free, Some(4));
+    }
+}
@@ -0,0 +1,106 @@
+//! Short/long press detection for a single push button
+//!
+//! The button is sampled from the main loop, so this only needs to know
+//! whether it is pressed, and when it was sampled. Passes through the loop
+//! vary a lot in length, so the press is timed against the monotonic clock
+//! rather than by counting samples.
+
+use crate::monotonic::{Duration, Instant};
+
+/// Presses shorter than this are ignored as bounce
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum Press {
+    /// Pressed and released before the long press time
+    Short,
+    /// Held for at least the long press time. Reported while the button
+    /// is still held, and only once per press.
+    Long,
+}
+
+pub struct Button {
+    long: Duration,
+    /// When the current press was first seen
+    pressed_at: Option<Instant>,
+    long_reported: bool,
+}
+
+impl Button {
+    pub const fn new(long_ms: u32) -> Self {
+        Self {
+            long: Duration::from_millis(long_ms),
+            pressed_at: None,
+            long_reported: false,
+        }
+    }
+
+    /// Feed in the current state of the button, sampled at `now`
+    pub fn update(&mut self, pressed: bool, now: Instant) -> Option<Press> {
+        if pressed {
+            let since = *self.pressed_at.get_or_insert(now);
+            if !self.long_reported && now.duration_since(since) >= self.long {
+                self.long_reported = true;
+                return Some(Press::Long);
+            }
+            return None;
+        }
+
+        let press = match self.pressed_at.take() {
+            Some(since) if !self.long_reported && now.duration_since(since) >= DEBOUNCE => {
+                Some(Press::Short)
+            }
+            _ => None,
+        };
+        self.long_reported = false;
+        press
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    fn at(ms: u32) -> Instant {
+        Instant::from_ticks(0) + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn short_press() {
+        let mut button = Button::new(5000);
+        assert_eq!(button.update(false, at(0)), None);
+        assert_eq!(button.update(true, at(100)), None);
+        assert_eq!(button.update(true, at(200)), None);
+        assert_eq!(button.update(false, at(300)), Some(Press::Short));
+        assert_eq!(button.update(false, at(400)), None);
+
+        // Only the time held counts, however few samples there were
+        assert_eq!(button.update(true, at(10_000)), None);
+        assert_eq!(button.update(false, at(14_000)), Some(Press::Short));
+    }
+
+    #[test]
+    fn long_press_reported_once() {
+        let mut button = Button::new(5000);
+        assert_eq!(button.update(true, at(1000)), None);
+        assert_eq!(button.update(true, at(5999)), None);
+        assert_eq!(button.update(true, at(6000)), Some(Press::Long));
+        assert_eq!(button.update(true, at(9000)), None);
+        // Letting go after a long press isn't also a short press
+        assert_eq!(button.update(false, at(9100)), None);
+
+        // A slow pass still times the press from when it was first seen
+        assert_eq!(button.update(true, at(20_000)), None);
+        assert_eq!(button.update(true, at(40_000)), Some(Press::Long));
+    }
+
+    #[test]
+    fn bounce_rejected() {
+        let mut button = Button::new(5000);
+        assert_eq!(button.update(true, at(100)), None);
+        assert_eq!(button.update(false, at(140)), None);
+        assert_eq!(button.update(true, at(200)), None);
+        assert_eq!(button.update(false, at(260)), Some(Press::Short));
+    }
+}
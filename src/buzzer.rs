@@ -0,0 +1,47 @@
+//! Piezo buzzer, driven by one of the PWM peripherals
+
+use embedded_hal::blocking::delay::DelayMs;
+use nrf52840_hal::{
+    gpio::{Output, Pin, PushPull},
+    pwm::{Channel, Instance, Pwm},
+    time::U32Ext,
+};
+
+/// Roughly the resonant frequency of common piezo discs
+const TONE_HZ: u32 = 2_700;
+
+const CHIRP_MS: u32 = 100;
+
+pub struct Buzzer<T: Instance> {
+    pwm: Pwm<T>,
+}
+
+impl<T: Instance> Buzzer<T> {
+    pub fn new(pwm: T, pin: Pin<Output<PushPull>>) -> Self {
+        let pwm = Pwm::new(pwm);
+        pwm.set_output_pin(Channel::C0, &pin);
+        pwm.set_period(TONE_HZ.hz());
+        pwm.disable();
+        Self { pwm }
+    }
+
+    pub fn on(&mut self) {
+        // 50% duty cycle is the loudest for a piezo
+        self.pwm.set_duty_on_common(self.pwm.max_duty() / 2);
+        self.pwm.enable();
+    }
+
+    pub fn off(&mut self) {
+        self.pwm.disable();
+    }
+
+    /// Blocking: sound `count` short chirps
+    pub fn chirp<D: DelayMs<u32>>(&mut self, delay: &mut D, count: u8) {
+        for _ in 0..count {
+            self.on();
+            delay.delay_ms(CHIRP_MS);
+            self.off();
+            delay.delay_ms(CHIRP_MS);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Conversion between unix time and calendar dates
+//!
+//! Only covers what the clock needs: UTC, years 1970..=2105, so every
+//! timestamp fits in a `u32`.
+
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct DateTime {
+    pub year: u16,
+    /// 1..=12
+    pub month: u8,
+    /// 1..=31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    pub fn from_unix(timestamp: u32) -> Self {
+        let days = timestamp / 86_400;
+        let secs = timestamp % 86_400;
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year,
+            month,
+            day,
+            hour: (secs / 3600) as u8,
+            minute: ((secs / 60) % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+
+    /// Seconds since the unix epoch, or `None` if the date is invalid or
+    /// out of range
+    pub fn to_unix(&self) -> Option<u32> {
+        let valid = (1970..=2105).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && (1..=days_in_month(self.year, self.month)).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60;
+        if !valid {
+            return None;
+        }
+
+        let days = days_from_civil(self.year, self.month, self.day);
+        let secs = (self.hour as u64 * 3600) + (self.minute as u64 * 60) + self.second as u64;
+        let total = (days as u64 * 86_400) + secs;
+        if total <= u32::MAX as u64 {
+            Some(total as u32)
+        } else {
+            None
+        }
+    }
+
+    /// Parse `YYYY-MM-DD` and `HH:MM:SS`
+    pub fn parse(date: &str, time: &str) -> Option<Self> {
+        let mut d = date.split('-');
+        let mut t = time.split(':');
+
+        let dt = Self {
+            year: d.next()?.parse().ok()?,
+            month: d.next()?.parse().ok()?,
+            day: d.next()?.parse().ok()?,
+            hour: t.next()?.parse().ok()?,
+            minute: t.next()?.parse().ok()?,
+            second: t.next()?.parse().ok()?,
+        };
+        if d.next().is_some() || t.next().is_some() {
+            return None;
+        }
+        dt.to_unix().map(|_| dt)
+    }
+
+    /// Day of the week, 1 (Monday) to 7 (Sunday)
+    pub fn weekday(&self) -> u8 {
+        // 1970-01-01 was a Thursday
+        let days = days_from_civil(self.year, self.month, self.day);
+        (((days + 3) % 7) + 1) as u8
+    }
+}
+
+/// ISO 8601, e.g. `2021-02-21T22:36:40Z`
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        // Let the calendar maths take care of leap years
+        2 => (days_from_civil(year, 3, 1) - days_from_civil(year, 2, 1)) as u8,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// The two conversions below are Howard Hinnant's `days_from_civil` and
+// `civil_from_days`, restricted to dates after the epoch.
+
+fn days_from_civil(year: u16, month: u8, day: u8) -> u32 {
+    let y = year as u32 - (month <= 2) as u32;
+    let era = y / 400;
+    let yoe = y - (era * 400);
+    let m = month as u32;
+    let doy = ((153 * (if m > 2 { m - 3 } else { m + 9 })) + 2) / 5 + day as u32 - 1;
+    let doe = (yoe * 365) + (yoe / 4) - (yoe / 100) + doy;
+    (era * 146_097) + doe - 719_468
+}
+
+fn civil_from_days(days: u32) -> (u16, u8, u8) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - (era * 146_097);
+    let yoe = (doe - (doe / 1460) + (doe / 36_524) - (doe / 146_096)) / 365;
+    let doy = doe - ((365 * yoe) + (yoe / 4) - (yoe / 100));
+    let mp = ((5 * doy) + 2) / 153;
+    let day = (doy - (((153 * mp) + 2) / 5) + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = ((era * 400) + yoe + (month <= 2) as u32) as u16;
+    (year, month, day)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn known_dates() {
+        let dt = DateTime::from_unix(1_613_947_000);
+        assert_eq!(
+            dt,
+            DateTime {
+                year: 2021,
+                month: 2,
+                day: 21,
+                hour: 22,
+                minute: 36,
+                second: 40,
+            }
+        );
+        assert_eq!(dt.to_string(), "2021-02-21T22:36:40Z");
+        assert_eq!(dt.weekday(), 7);
+
+        assert_eq!(DateTime::from_unix(0).to_string(), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            DateTime::from_unix(951_782_400).to_string(),
+            "2000-02-29T00:00:00Z"
+        );
+        assert_eq!(
+            DateTime::from_unix(u32::MAX).to_string(),
+            "2106-02-07T06:28:15Z"
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let dt = DateTime::parse("2021-02-21", "22:36:40").unwrap();
+        assert_eq!(dt.to_unix(), Some(1_613_947_000));
+
+        assert_eq!(DateTime::parse("2021-02-29", "00:00:00"), None);
+        assert_eq!(DateTime::parse("2021-13-01", "00:00:00"), None);
+        assert_eq!(DateTime::parse("2021-257-01", "00:00:00"), None);
+        assert_eq!(DateTime::parse("2021-01-01", "24:00:00"), None);
+        assert_eq!(DateTime::parse("2021-01-01", "12:00"), None);
+        assert_eq!(DateTime::parse("1969-12-31", "23:59:59"), None);
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(timestamp in 0..=u32::MAX) {
+            let dt = DateTime::from_unix(timestamp);
+            prop_assert!(dt.year <= 2106);
+            if dt.year <= 2105 {
+                prop_assert_eq!(dt.to_unix(), Some(timestamp));
+            }
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Comfort metrics derived from temperature and relative humidity
+//!
+//! Nothing here is measured or stored, it is all worked out from a
+//! reading when it is shown or exported:
+//!
+//! - Dew point, from the Magnus formula with Sonntag's constants, good to
+//!   about 0.1 degrees between -45 and 60 degrees C
+//! - Absolute humidity, the mass of water vapour in a cubic metre of air
+//! - Heat index, what the temperature feels like, following the US
+//!   National Weather Service: Steadman's simple formula in mild weather,
+//!   Rothfusz's regression with its corrections above 80 degrees F
+//!
+//! There is no `libm` in `core`, so the few transcendental functions
+//! needed are approximated here, to well within the sensor's accuracy.
+
+use crate::history::Sample;
+
+/// Magnus formula constants, over water
+const MAGNUS_A: f32 = 17.62;
+const MAGNUS_B: f32 = 243.12;
+/// Saturation vapour pressure at 0 degrees C, in hPa
+const MAGNUS_E0: f32 = 6.112;
+
+/// Specific gas constant of water vapour, for hPa to g/m^3
+const GRAMS_PER_HPA_KELVIN: f32 = 216.7;
+
+/// Derived from one reading, in hundredths
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Comfort {
+    /// Dew point, in 0.01 degrees C
+    pub dew_point_cdeg: i16,
+    /// Absolute humidity, in 0.01 g/m^3
+    pub abs_humidity_cg_m3: u16,
+    /// Heat index, in 0.01 degrees C
+    pub heat_index_cdeg: i16,
+}
+
+impl Comfort {
+    /// `None` if there is no moisture at all, which has no dew point
+    pub fn from_f32(temp: f32, rh: f32) -> Option<Self> {
+        if rh.is_nan() || rh <= 0.0 || temp.is_nan() {
+            return None;
+        }
+        let rh = rh.min(100.0);
+        let abs_humidity = round(absolute_humidity(temp, rh) * 100.0);
+
+        Some(Self {
+            dew_point_cdeg: cdeg(dew_point(temp, rh)),
+            abs_humidity_cg_m3: abs_humidity.clamp(0, u16::MAX as i32) as u16,
+            heat_index_cdeg: cdeg(heat_index(temp, rh)),
+        })
+    }
+
+    pub fn of(sample: &Sample) -> Option<Self> {
+        Self::from_f32(
+            sample.temp_cdeg as f32 / 100.0,
+            sample.rh_cpct as f32 / 100.0,
+        )
+    }
+}
+
+/// Dew point in degrees C, from degrees C and %RH
+pub fn dew_point(temp: f32, rh: f32) -> f32 {
+    let gamma = ln(rh / 100.0) + MAGNUS_A * temp / (MAGNUS_B + temp);
+    MAGNUS_B * gamma / (MAGNUS_A - gamma)
+}
+
+/// Absolute humidity in g/m^3, from degrees C and %RH
+pub fn absolute_humidity(temp: f32, rh: f32) -> f32 {
+    let vapour_hpa = rh / 100.0 * MAGNUS_E0 * exp(MAGNUS_A * temp / (MAGNUS_B + temp));
+    GRAMS_PER_HPA_KELVIN * vapour_hpa / (temp + 273.15)
+}
+
+/// Heat index in degrees C, from degrees C and %RH
+pub fn heat_index(temp: f32, rh: f32) -> f32 {
+    let t = temp * 9.0 / 5.0 + 32.0;
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let hi = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let rothfusz = -42.379 + 2.049_015_2 * t + 10.143_331 * rh
+            - 0.224_755_4 * t * rh
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh
+            - 0.000_001_99 * t * t * rh * rh;
+
+        let dry = if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            -(13.0 - rh) / 4.0 * sqrt((17.0 - (t - 95.0).abs()) / 17.0)
+        } else {
+            0.0
+        };
+        let humid = if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            (rh - 85.0) / 10.0 * (87.0 - t) / 5.0
+        } else {
+            0.0
+        };
+        rothfusz + dry + humid
+    };
+
+    (hi - 32.0) * 5.0 / 9.0
+}
+
+fn cdeg(celsius: f32) -> i16 {
+    round(celsius * 100.0).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+fn round(x: f32) -> i32 {
+    if x < 0.0 {
+        (x - 0.5) as i32
+    } else {
+        (x + 0.5) as i32
+    }
+}
+
+/// e^x, to about 1e-6 relative
+fn exp(x: f32) -> f32 {
+    // e^x = 2^k * e^r, with |r| <= ln(2) / 2
+    let k = round(x * core::f32::consts::LOG2_E).clamp(-126, 127);
+    let r = x - k as f32 * core::f32::consts::LN_2;
+
+    let mut term = 1.0;
+    let mut sum = 1.0;
+    for n in 1..=7 {
+        term *= r / n as f32;
+        sum += term;
+    }
+    sum * f32::from_bits(((k + 127) as u32) << 23)
+}
+
+/// ln(x) for normal positive x, to about 1e-6
+fn ln(x: f32) -> f32 {
+    // x = m * 2^e, with 1 <= m < 2
+    let bits = x.to_bits();
+    let e = ((bits >> 23) & 0xFF) as i32 - 127;
+    let m = f32::from_bits((bits & 0x007F_FFFF) | 0x3F80_0000);
+
+    // ln(m) = 2 * atanh(s), with s = (m - 1) / (m + 1) < 1/3
+    let s = (m - 1.0) / (m + 1.0);
+    let s2 = s * s;
+    let mut term = s;
+    let mut sum = 0.0;
+    for n in (1..=11).step_by(2) {
+        sum += term / n as f32;
+        term *= s2;
+    }
+    2.0 * sum + e as f32 * core::f32::consts::LN_2
+}
+
+fn sqrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        0.0
+    } else {
+        exp(ln(x) / 2.0)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn approximations() {
+        let mut x = -20.0f32;
+        while x <= 20.0 {
+            assert!((exp(x) / x.exp() - 1.0).abs() < 1e-5, "exp({})", x);
+            x += 0.01;
+        }
+        let mut x = 0.001f32;
+        while x <= 1000.0 {
+            assert!((ln(x) - x.ln()).abs() < 1e-5, "ln({})", x);
+            x *= 1.01;
+        }
+        assert!((sqrt(2.0) - 2f32.sqrt()).abs() < 1e-5);
+        assert_eq!(sqrt(0.0), 0.0);
+    }
+
+    #[test]
+    fn dew_points() {
+        // (degrees C, %RH, dew point), as given to one decimal by
+        // psychrometric tables
+        let table = [
+            (0.0, 100.0, 0.0),
+            (20.0, 50.0, 9.3),
+            (25.0, 60.0, 16.7),
+            (30.0, 80.0, 26.2),
+            (10.0, 30.0, -6.8),
+            (-10.0, 80.0, -12.8),
+            (40.0, 20.0, 12.8),
+        ];
+        for (temp, rh, expected) in table.iter() {
+            let dew = dew_point(*temp, *rh);
+            assert!((dew - expected).abs() <= 0.05, "{} {}: {}", temp, rh, dew);
+        }
+    }
+
+    #[test]
+    fn absolute_humidities() {
+        // (degrees C, %RH, g/m^3), from tables of saturation vapour
+        // density, which Magnus agrees with to within 0.5%
+        let table = [
+            (0.0, 100.0, 4.85),
+            (10.0, 100.0, 9.40),
+            (20.0, 100.0, 17.30),
+            (20.0, 50.0, 8.65),
+            (25.0, 100.0, 23.05),
+            (30.0, 100.0, 30.38),
+            (40.0, 100.0, 51.19),
+        ];
+        for (temp, rh, expected) in table.iter() {
+            let ah = absolute_humidity(*temp, *rh);
+            assert!(
+                (ah / expected - 1.0).abs() < 0.005,
+                "{} {}: {}",
+                temp,
+                rh,
+                ah
+            );
+        }
+    }
+
+    #[test]
+    fn heat_indices() {
+        // (degrees F, %RH, heat index in F) from the NWS heat index chart
+        let table = [
+            (80.0, 40.0, 80.0),
+            (90.0, 50.0, 95.0),
+            (100.0, 40.0, 109.0),
+            (96.0, 65.0, 121.0),
+            (84.0, 90.0, 98.0),
+            (104.0, 10.0, 98.0),
+            (110.0, 40.0, 136.0),
+        ];
+        for (f, rh, expected) in table.iter() {
+            let hi = heat_index((f - 32.0) * 5.0 / 9.0, *rh) * 9.0 / 5.0 + 32.0;
+            assert!((hi - expected).abs() <= 1.0, "{} {}: {}", f, rh, hi);
+        }
+
+        // Below 80F it follows the temperature, give or take a little
+        let hi = heat_index(20.0, 50.0);
+        assert!((hi - 19.4).abs() < 0.1, "{}", hi);
+    }
+
+    #[test]
+    fn samples() {
+        let sample = Sample {
+            timestamp: 0,
+            co2_ppm: 600,
+            temp_cdeg: 2000,
+            rh_cpct: 5000,
+        };
+        let comfort = Comfort::of(&sample).unwrap();
+        assert_eq!(comfort.dew_point_cdeg, 926);
+        assert_eq!(comfort.abs_humidity_cg_m3, 862);
+
+        assert_eq!(Comfort::from_f32(20.0, 0.0), None);
+        assert_eq!(Comfort::from_f32(20.0, f32::NAN), None);
+        assert_eq!(Comfort::from_f32(f32::NAN, 50.0), None);
+        // Supersaturated readings are taken as 100%
+        assert_eq!(Comfort::from_f32(0.0, 104.0).unwrap().dew_point_cdeg, 0);
+    }
+}
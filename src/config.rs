@@ -0,0 +1,947 @@
+//! Persistent per-device configuration
+//!
+//! The configuration is kept in two reserved pages of internal flash, so it
+//! survives reflashing the application. Each copy is framed by a magic
+//! word, a format version, a sequence number and a CRC. A store always
+//! goes to the page *not* holding the newest copy, so a reset part way
+//! through leaves the previous copy intact. On load the valid copy with
+//! the highest sequence number wins, and if there isn't one the defaults
+//! are used instead.
+
+use core::fmt;
+
+use crate::{
+    alert::AlertConfig,
+    crc::crc32,
+    cts::TimeConfig,
+    flash::Flash,
+    identity::{IdentityConfig, Label, LABEL_LEN},
+    occupancy::RoomConfig,
+    units::{ClockFormat, TemperatureUnit, UnitsConfig},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Config {
+    pub scd30: Scd30Settings,
+
+    /// Status of the last forced recalibration of the SCD30
+    pub calibration: Calibration,
+
+    pub alert: AlertConfig,
+
+    pub system: SystemConfig,
+
+    /// The clock's name and location, see `identity`
+    pub identity: IdentityConfig,
+
+    /// Fahrenheit or Celsius, 12 or 24 hour time
+    pub units: UnitsConfig,
+
+    /// The room, for estimating its occupancy, see `occupancy`
+    pub room: RoomConfig,
+
+    /// The local time zone, set by the time sync, see `cts`
+    pub time: TimeConfig,
+}
+
+/// Per-device sensor settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Scd30Settings {
+    /// Continuous measurement interval in seconds (2..=1800)
+    pub interval_s: u16,
+
+    /// Height above sea level in meters, used for pressure compensation
+    /// when no ambient pressure is given
+    pub altitude_m: u16,
+
+    /// Ambient pressure in mbar (700..=1400), or 0 to disable. Takes
+    /// precedence over `altitude_m` when set.
+    pub pressure_mbar: u16,
+
+    /// Self-heating offset in 0.01 degrees C, subtracted from readings
+    pub temp_offset_cdeg: u16,
+
+    /// Automatic self-calibration. This assumes the sensor sees fresh air
+    /// (~400ppm) for at least an hour a day, over several days.
+    pub asc_enabled: bool,
+
+    /// Reference CO2 concentration used for forced recalibration, in ppm
+    /// (400..=2000). 420ppm is about right for outdoor air.
+    pub frc_reference_ppm: u16,
+}
+
+impl Default for Scd30Settings {
+    // These match the sensor's factory defaults
+    fn default() -> Self {
+        Self {
+            interval_s: 2,
+            altitude_m: 0,
+            pressure_mbar: 0,
+            temp_offset_cdeg: 0,
+            asc_enabled: false,
+            frc_reference_ppm: 420,
+        }
+    }
+}
+
+impl Scd30Settings {
+    pub fn is_valid(&self) -> bool {
+        let interval = (2..=1800).contains(&self.interval_s);
+        let pressure = self.pressure_mbar == 0 || (700..=1400).contains(&self.pressure_mbar);
+        let frc = FRC_RANGE.contains(&self.frc_reference_ppm);
+        interval && pressure && frc
+    }
+}
+
+/// Range of reference values accepted for forced recalibration, in ppm
+pub const FRC_RANGE: core::ops::RangeInclusive<u16> = 400..=2000;
+
+/// The outcome of the last forced recalibration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Calibration {
+    /// Reference value the sensor was calibrated against, in ppm
+    pub reference_ppm: u16,
+
+    /// When the calibration happened, in seconds since the unix epoch
+    /// (as read from the RTC), or 0 if the sensor has never been
+    /// calibrated by us
+    pub timestamp: u32,
+}
+
+impl Calibration {
+    pub fn is_calibrated(&self) -> bool {
+        self.timestamp != 0
+    }
+
+    /// Whole days elapsed since the calibration, if there was one
+    pub fn age_days(&self, now: u32) -> Option<u32> {
+        if self.is_calibrated() {
+            Some(now.saturating_sub(self.timestamp) / 86_400)
+        } else {
+            None
+        }
+    }
+}
+
+/// Board level settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct SystemConfig {
+    /// Watchdog timeout, in seconds (10..=3600). Must cover the longest
+    /// pass through the main loop, see
+    /// [`longest_pass_ms`](Self::longest_pass_ms).
+    pub watchdog_timeout_s: u16,
+
+    /// I2C clock in kHz: 100, 250 or 400
+    pub twim_khz: u16,
+
+    /// SPI clock in kHz: 125, 250, 500, 1000, 2000, 4000 or 8000
+    pub spim_khz: u16,
+
+    /// How long each screen is shown for, in milliseconds (250..=10000)
+    pub page_dwell_ms: u16,
+
+    /// If the RTC reads earlier than this at boot (e.g. after losing its
+    /// backup battery), it is set to this time. Seconds since the unix
+    /// epoch.
+    pub base_datetime: u32,
+}
+
+impl Default for SystemConfig {
+    fn default() -> Self {
+        Self {
+            watchdog_timeout_s: 30,
+            twim_khz: 400,
+            spim_khz: 4000,
+            page_dwell_ms: 2000,
+            // 2021-02-21 22:36:40
+            base_datetime: 1_613_947_000,
+        }
+    }
+}
+
+impl SystemConfig {
+    const TWIM_KHZ: [u16; 3] = [100, 250, 400];
+    const SPIM_KHZ: [u16; 7] = [125, 250, 500, 1000, 2000, 4000, 8000];
+
+    /// The most screens the display shows in one pass through the main
+    /// loop, e.g. the rotation on a new minute. The screens in `sevseg`
+    /// are checked against this when it is built.
+    pub const MAX_PAGES_PER_PASS: u32 = 12;
+
+    /// Time allowed in one pass for everything but the screens, e.g.
+    /// reading the sensor, flashing a CO2 alert or dumping a sector of the
+    /// log, in milliseconds
+    pub const PASS_OVERHEAD_MS: u32 = 5000;
+
+    pub fn is_valid(&self) -> bool {
+        (10..=3600).contains(&self.watchdog_timeout_s)
+            && Self::TWIM_KHZ.contains(&self.twim_khz)
+            && Self::SPIM_KHZ.contains(&self.spim_khz)
+            && (250..=10_000).contains(&self.page_dwell_ms)
+            && self.watchdog_timeout_s >= self.min_watchdog_timeout_s()
+    }
+
+    /// The longest a pass through the main loop can take, in milliseconds.
+    /// The watchdog is only fed once a pass.
+    pub fn longest_pass_ms(&self) -> u32 {
+        self.page_dwell_ms as u32 * Self::MAX_PAGES_PER_PASS + Self::PASS_OVERHEAD_MS
+    }
+
+    /// The shortest watchdog timeout that covers the longest pass
+    pub fn min_watchdog_timeout_s(&self) -> u16 {
+        ((self.longest_pass_ms() - 1) / 1000 + 1) as u16
+    }
+
+    /// Watchdog timeout in ticks of the 32.768kHz low frequency clock
+    pub fn watchdog_ticks(&self) -> u32 {
+        self.watchdog_timeout_s as u32 * 32_768
+    }
+
+    #[cfg(feature = "firmware")]
+    pub fn twim_frequency(&self) -> nrf52840_hal::twim::Frequency {
+        use nrf52840_hal::twim::Frequency;
+        match self.twim_khz {
+            100 => Frequency::K100,
+            250 => Frequency::K250,
+            _ => Frequency::K400,
+        }
+    }
+
+    #[cfg(feature = "firmware")]
+    pub fn spim_frequency(&self) -> nrf52840_hal::spim::Frequency {
+        use nrf52840_hal::spim::Frequency;
+        match self.spim_khz {
+            125 => Frequency::K125,
+            250 => Frequency::K250,
+            500 => Frequency::K500,
+            1000 => Frequency::M1,
+            2000 => Frequency::M2,
+            8000 => Frequency::M8,
+            _ => Frequency::M4,
+        }
+    }
+}
+
+/// A setting that can be read and changed by name, e.g. from the console
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum Key {
+    Scd30Interval,
+    Scd30Altitude,
+    Scd30Pressure,
+    Scd30TempOffset,
+    Scd30Asc,
+    Scd30FrcReference,
+    AlertElevated,
+    AlertHigh,
+    AlertCritical,
+    AlertHysteresis,
+    AlertBuzzer,
+    AlertQuietStart,
+    AlertQuietEnd,
+    WatchdogTimeout,
+    TwimKhz,
+    SpimKhz,
+    PageDwell,
+    BaseDatetime,
+    Name,
+    Location,
+    Fahrenheit,
+    Clock12h,
+    RoomVolume,
+    RoomAirChanges,
+    RoomOutdoor,
+}
+
+impl Key {
+    pub const ALL: [Key; 25] = [
+        Key::Scd30Interval,
+        Key::Scd30Altitude,
+        Key::Scd30Pressure,
+        Key::Scd30TempOffset,
+        Key::Scd30Asc,
+        Key::Scd30FrcReference,
+        Key::AlertElevated,
+        Key::AlertHigh,
+        Key::AlertCritical,
+        Key::AlertHysteresis,
+        Key::AlertBuzzer,
+        Key::AlertQuietStart,
+        Key::AlertQuietEnd,
+        Key::WatchdogTimeout,
+        Key::TwimKhz,
+        Key::SpimKhz,
+        Key::PageDwell,
+        Key::BaseDatetime,
+        Key::Name,
+        Key::Location,
+        Key::Fahrenheit,
+        Key::Clock12h,
+        Key::RoomVolume,
+        Key::RoomAirChanges,
+        Key::RoomOutdoor,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Key::Scd30Interval => "scd30.interval_s",
+            Key::Scd30Altitude => "scd30.altitude_m",
+            Key::Scd30Pressure => "scd30.pressure_mbar",
+            Key::Scd30TempOffset => "scd30.temp_offset_cdeg",
+            Key::Scd30Asc => "scd30.asc",
+            Key::Scd30FrcReference => "scd30.frc_reference_ppm",
+            Key::AlertElevated => "alert.elevated_ppm",
+            Key::AlertHigh => "alert.high_ppm",
+            Key::AlertCritical => "alert.critical_ppm",
+            Key::AlertHysteresis => "alert.hysteresis_ppm",
+            Key::AlertBuzzer => "alert.buzzer",
+            Key::AlertQuietStart => "alert.quiet_start_hour",
+            Key::AlertQuietEnd => "alert.quiet_end_hour",
+            Key::WatchdogTimeout => "system.watchdog_timeout_s",
+            Key::TwimKhz => "system.twim_khz",
+            Key::SpimKhz => "system.spim_khz",
+            Key::PageDwell => "system.page_dwell_ms",
+            Key::BaseDatetime => "system.base_datetime",
+            Key::Name => "identity.name",
+            Key::Location => "identity.location",
+            Key::Fahrenheit => "units.fahrenheit",
+            Key::Clock12h => "units.clock_12h",
+            Key::RoomVolume => "room.volume_m3",
+            Key::RoomAirChanges => "room.air_changes_dph",
+            Key::RoomOutdoor => "room.outdoor_ppm",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|k| k.name() == name)
+    }
+
+    /// Changes to these settings only take effect after a reset
+    pub fn needs_reset(self) -> bool {
+        matches!(
+            self,
+            Key::WatchdogTimeout | Key::TwimKhz | Key::SpimKhz | Key::BaseDatetime
+        )
+    }
+
+    /// Settings that hold text rather than a number
+    pub fn is_text(self) -> bool {
+        matches!(self, Key::Name | Key::Location)
+    }
+
+    /// Settings that are written to the SCD30 itself
+    pub fn is_scd30(self) -> bool {
+        matches!(
+            self,
+            Key::Scd30Interval
+                | Key::Scd30Altitude
+                | Key::Scd30Pressure
+                | Key::Scd30TempOffset
+                | Key::Scd30Asc
+                | Key::Scd30FrcReference
+        )
+    }
+}
+
+/// The value of a setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum Value {
+    /// Numbers, and switches as 0 or 1
+    Number(u32),
+    Text(Label),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Text(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+/// "FCCF", little endian
+const MAGIC: u32 = 0x4643_4346;
+
+/// Current version of the record format
+pub const VERSION: u16 = 1;
+
+/// Magic, version, payload length, sequence number
+const HEADER_LEN: usize = 12;
+
+const PAYLOAD_LEN: usize = 88;
+
+/// Header, payload, CRC
+pub const RECORD_LEN: usize = HEADER_LEN + PAYLOAD_LEN + 4;
+
+/// A record read back from flash
+struct Stored {
+    seq: u32,
+    /// `None` if the record is intact but can't be used, e.g. it was
+    /// written by a newer version or holds invalid settings
+    config: Option<Config>,
+}
+
+impl Config {
+    /// Addresses of the two flash pages holding the configuration
+    pub const PAGES: [u32; 2] = [0x000F_F000, 0x000F_E000];
+
+    fn payload(&self) -> [u8; PAYLOAD_LEN] {
+        let mut buf = [0u8; PAYLOAD_LEN];
+
+        let s = &self.scd30;
+        buf[0..2].copy_from_slice(&s.interval_s.to_le_bytes());
+        buf[2..4].copy_from_slice(&s.altitude_m.to_le_bytes());
+        buf[4..6].copy_from_slice(&s.pressure_mbar.to_le_bytes());
+        buf[6..8].copy_from_slice(&s.temp_offset_cdeg.to_le_bytes());
+        buf[8] = s.asc_enabled as u8;
+        buf[10..12].copy_from_slice(&s.frc_reference_ppm.to_le_bytes());
+
+        let c = &self.calibration;
+        buf[12..14].copy_from_slice(&c.reference_ppm.to_le_bytes());
+        buf[16..20].copy_from_slice(&c.timestamp.to_le_bytes());
+
+        let a = &self.alert;
+        for (i, threshold) in a.thresholds_ppm.iter().enumerate() {
+            buf[20 + (i * 2)..][..2].copy_from_slice(&threshold.to_le_bytes());
+        }
+        buf[26..28].copy_from_slice(&a.hysteresis_ppm.to_le_bytes());
+        buf[28] = a.buzzer_enabled as u8;
+        buf[29] = a.quiet_start_hour;
+        buf[30] = a.quiet_end_hour;
+
+        let y = &self.system;
+        buf[32..34].copy_from_slice(&y.watchdog_timeout_s.to_le_bytes());
+        buf[34..36].copy_from_slice(&y.twim_khz.to_le_bytes());
+        buf[36..38].copy_from_slice(&y.spim_khz.to_le_bytes());
+        buf[38..40].copy_from_slice(&y.page_dwell_ms.to_le_bytes());
+        buf[40..44].copy_from_slice(&y.base_datetime.to_le_bytes());
+
+        let i = &self.identity;
+        buf[44..][..LABEL_LEN].copy_from_slice(&i.name.encode());
+        buf[60..][..LABEL_LEN].copy_from_slice(&i.location.encode());
+
+        let u = &self.units;
+        buf[76] = u.temperature as u8;
+        buf[77] = u.clock as u8;
+
+        let r = &self.room;
+        buf[78..80].copy_from_slice(&r.volume_m3.to_le_bytes());
+        buf[80..82].copy_from_slice(&r.air_changes_dph.to_le_bytes());
+        buf[82..84].copy_from_slice(&r.outdoor_ppm.to_le_bytes());
+
+        buf[84..88].copy_from_slice(&self.time.utc_offset_s.to_le_bytes());
+
+        buf
+    }
+
+    fn from_payload(buf: &[u8]) -> Option<Self> {
+        if buf.len() != PAYLOAD_LEN {
+            return None;
+        }
+
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+
+        let scd30 = Scd30Settings {
+            interval_s: u16_at(0),
+            altitude_m: u16_at(2),
+            pressure_mbar: u16_at(4),
+            temp_offset_cdeg: u16_at(6),
+            asc_enabled: buf[8] != 0,
+            frc_reference_ppm: u16_at(10),
+        };
+
+        let calibration = Calibration {
+            reference_ppm: u16_at(12),
+            timestamp: u32_at(16),
+        };
+
+        let alert = AlertConfig {
+            thresholds_ppm: [u16_at(20), u16_at(22), u16_at(24)],
+            hysteresis_ppm: u16_at(26),
+            buzzer_enabled: buf[28] != 0,
+            quiet_start_hour: buf[29],
+            quiet_end_hour: buf[30],
+        };
+
+        let mut system = SystemConfig {
+            watchdog_timeout_s: u16_at(32),
+            twim_khz: u16_at(34),
+            spim_khz: u16_at(36),
+            page_dwell_ms: u16_at(38),
+            base_datetime: u32_at(40),
+        };
+        // Records from before the timeout had to cover the screens may not,
+        // make room rather than lose the rest of the config
+        system.watchdog_timeout_s = system
+            .watchdog_timeout_s
+            .max(system.min_watchdog_timeout_s());
+
+        let identity = IdentityConfig {
+            name: Label::decode(&buf[44..60])?,
+            location: Label::decode(&buf[60..76])?,
+        };
+
+        let units = UnitsConfig {
+            temperature: TemperatureUnit::from_u8(buf[76])?,
+            clock: ClockFormat::from_u8(buf[77])?,
+        };
+
+        let room = RoomConfig {
+            volume_m3: u16_at(78),
+            air_changes_dph: u16_at(80),
+            outdoor_ppm: u16_at(82),
+        };
+
+        let time = TimeConfig {
+            utc_offset_s: u32_at(84) as i32,
+        };
+
+        let cfg = Self {
+            scd30,
+            calibration,
+            alert,
+            system,
+            identity,
+            units,
+            room,
+            time,
+        };
+        if cfg.is_valid() {
+            Some(cfg)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.scd30.is_valid()
+            && self.alert.is_valid()
+            && self.system.is_valid()
+            && self.room.is_valid()
+            && self.time.is_valid()
+    }
+
+    pub fn get(&self, key: Key) -> Value {
+        let (s, a, y) = (&self.scd30, &self.alert, &self.system);
+        let number = match key {
+            Key::Scd30Interval => s.interval_s as u32,
+            Key::Scd30Altitude => s.altitude_m as u32,
+            Key::Scd30Pressure => s.pressure_mbar as u32,
+            Key::Scd30TempOffset => s.temp_offset_cdeg as u32,
+            Key::Scd30Asc => s.asc_enabled as u32,
+            Key::Scd30FrcReference => s.frc_reference_ppm as u32,
+            Key::AlertElevated => a.thresholds_ppm[0] as u32,
+            Key::AlertHigh => a.thresholds_ppm[1] as u32,
+            Key::AlertCritical => a.thresholds_ppm[2] as u32,
+            Key::AlertHysteresis => a.hysteresis_ppm as u32,
+            Key::AlertBuzzer => a.buzzer_enabled as u32,
+            Key::AlertQuietStart => a.quiet_start_hour as u32,
+            Key::AlertQuietEnd => a.quiet_end_hour as u32,
+            Key::WatchdogTimeout => y.watchdog_timeout_s as u32,
+            Key::TwimKhz => y.twim_khz as u32,
+            Key::SpimKhz => y.spim_khz as u32,
+            Key::PageDwell => y.page_dwell_ms as u32,
+            Key::BaseDatetime => y.base_datetime,
+            Key::Name => return Value::Text(self.identity.name),
+            Key::Location => return Value::Text(self.identity.location),
+            Key::Fahrenheit => (self.units.temperature == TemperatureUnit::Fahrenheit) as u32,
+            Key::Clock12h => (self.units.clock == ClockFormat::H12) as u32,
+            Key::RoomVolume => self.room.volume_m3 as u32,
+            Key::RoomAirChanges => self.room.air_changes_dph as u32,
+            Key::RoomOutdoor => self.room.outdoor_ppm as u32,
+        };
+        Value::Number(number)
+    }
+
+    /// Change a setting. Returns `false`, leaving the configuration as it
+    /// was, if the value is out of range or the wrong type.
+    pub fn set(&mut self, key: Key, value: Value) -> bool {
+        let value = match (value, key) {
+            (Value::Text(text), Key::Name) => {
+                self.identity.name = text;
+                return true;
+            }
+            (Value::Text(text), Key::Location) => {
+                self.identity.location = text;
+                return true;
+            }
+            (Value::Number(n), _) if !key.is_text() => n,
+            _ => return false,
+        };
+
+        let mut new = *self;
+        let (s, a, y, u, r) = (
+            &mut new.scd30,
+            &mut new.alert,
+            &mut new.system,
+            &mut new.units,
+            &mut new.room,
+        );
+
+        let word = value as u16;
+        let byte = value as u8;
+        let max = match key {
+            Key::Scd30Asc | Key::AlertBuzzer | Key::Fahrenheit | Key::Clock12h => 1,
+            Key::AlertQuietStart | Key::AlertQuietEnd => u8::MAX as u32,
+            Key::BaseDatetime => u32::MAX,
+            _ => u16::MAX as u32,
+        };
+        if value > max {
+            return false;
+        }
+
+        match key {
+            Key::Scd30Interval => s.interval_s = word,
+            Key::Scd30Altitude => s.altitude_m = word,
+            Key::Scd30Pressure => s.pressure_mbar = word,
+            Key::Scd30TempOffset => s.temp_offset_cdeg = word,
+            Key::Scd30Asc => s.asc_enabled = value != 0,
+            Key::Scd30FrcReference => s.frc_reference_ppm = word,
+            Key::AlertElevated => a.thresholds_ppm[0] = word,
+            Key::AlertHigh => a.thresholds_ppm[1] = word,
+            Key::AlertCritical => a.thresholds_ppm[2] = word,
+            Key::AlertHysteresis => a.hysteresis_ppm = word,
+            Key::AlertBuzzer => a.buzzer_enabled = value != 0,
+            Key::AlertQuietStart => a.quiet_start_hour = byte,
+            Key::AlertQuietEnd => a.quiet_end_hour = byte,
+            Key::WatchdogTimeout => y.watchdog_timeout_s = word,
+            Key::TwimKhz => y.twim_khz = word,
+            Key::SpimKhz => y.spim_khz = word,
+            Key::PageDwell => y.page_dwell_ms = word,
+            Key::BaseDatetime => y.base_datetime = value,
+            Key::Fahrenheit => {
+                u.temperature = if value != 0 {
+                    TemperatureUnit::Fahrenheit
+                } else {
+                    TemperatureUnit::Celsius
+                }
+            }
+            Key::Clock12h => {
+                u.clock = if value != 0 {
+                    ClockFormat::H12
+                } else {
+                    ClockFormat::H24
+                }
+            }
+            Key::RoomVolume => r.volume_m3 = word,
+            Key::RoomAirChanges => r.air_changes_dph = word,
+            Key::RoomOutdoor => r.outdoor_ppm = word,
+            Key::Name | Key::Location => unreachable!(),
+        }
+
+        if new.is_valid() {
+            *self = new;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Encode the configuration as a record with the given sequence number
+    pub fn to_bytes(&self, seq: u32) -> [u8; RECORD_LEN] {
+        let mut buf = [0u8; RECORD_LEN];
+        buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        buf[6..8].copy_from_slice(&(PAYLOAD_LEN as u16).to_le_bytes());
+        buf[8..12].copy_from_slice(&seq.to_le_bytes());
+        buf[HEADER_LEN..][..PAYLOAD_LEN].copy_from_slice(&self.payload());
+
+        let crc = crc32(&buf[..RECORD_LEN - 4]);
+        buf[RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Decode a record, returning the configuration and its sequence number
+    pub fn from_bytes(buf: &[u8]) -> Option<(Self, u32)> {
+        let stored = decode(buf)?;
+        Some((stored.config?, stored.seq))
+    }
+
+    /// Load the newest valid stored configuration, if there is one
+    pub fn load<F: Flash>(flash: &mut F) -> Result<Option<Self>, F::Error> {
+        let (a, b) = read_pages(flash)?;
+        let newest = match (a, b) {
+            (Some(a), Some(b)) if a.config.is_some() && b.config.is_some() => {
+                Some(if b.seq > a.seq { b } else { a })
+            }
+            (a, b) => a.filter(|a| a.config.is_some()).or(b),
+        };
+        Ok(newest.and_then(|stored| stored.config))
+    }
+
+    /// Load the newest valid stored configuration, falling back to the
+    /// defaults if there isn't one
+    pub fn load_or_default<F: Flash>(flash: &mut F) -> Self {
+        Self::load(flash).ok().flatten().unwrap_or_default()
+    }
+
+    /// Store the configuration, leaving the newest existing copy intact
+    pub fn store<F: Flash>(&self, flash: &mut F) -> Result<(), F::Error> {
+        let (a, b) = read_pages(flash)?;
+
+        // Intact records we can't use still count, so a record from a
+        // newer version isn't left with a higher sequence number than ours
+        let seq_a = a.map(|a| a.seq);
+        let seq_b = b.map(|b| b.seq);
+        let (page, seq) = match (seq_a, seq_b) {
+            (Some(sa), Some(sb)) if sb > sa => (0, sb),
+            (Some(sa), _) => (1, sa),
+            (None, Some(sb)) => (0, sb),
+            (None, None) => (0, 0),
+        };
+
+        let addr = Self::PAGES[page];
+        flash.erase(addr)?;
+        flash.write(addr, &self.to_bytes(seq.wrapping_add(1)))
+    }
+}
+
+fn read_pages<F: Flash>(flash: &mut F) -> Result<(Option<Stored>, Option<Stored>), F::Error> {
+    let mut buf = [0u8; RECORD_LEN];
+    flash.read(Config::PAGES[0], &mut buf)?;
+    let a = decode(&buf);
+    flash.read(Config::PAGES[1], &mut buf)?;
+    let b = decode(&buf);
+    Ok((a, b))
+}
+
+fn decode(buf: &[u8]) -> Option<Stored> {
+    let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+
+    if buf.len() < HEADER_LEN || u32_at(0) != MAGIC {
+        return None;
+    }
+
+    let payload_len = u16_at(6) as usize;
+    let record_len = HEADER_LEN + payload_len + 4;
+    if record_len > buf.len() || u32_at(record_len - 4) != crc32(&buf[..record_len - 4]) {
+        return None;
+    }
+
+    let config = if u16_at(4) == VERSION {
+        Config::from_payload(&buf[HEADER_LEN..][..payload_len])
+    } else {
+        None
+    };
+    Some(Stored {
+        seq: u32_at(8),
+        config,
+    })
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::flash::MemFlash;
+
+    fn blank() -> MemFlash {
+        MemFlash::new(vec![0xFFu8; 0x0010_0000])
+    }
+
+    fn custom() -> Config {
+        let mut cfg = Config::default();
+        cfg.scd30.altitude_m = 120;
+        cfg.calibration = Calibration {
+            reference_ppm: 420,
+            timestamp: 1_614_000_000,
+        };
+        cfg.alert.buzzer_enabled = true;
+        cfg.system.twim_khz = 100;
+        cfg.system.watchdog_timeout_s = 60;
+        cfg.system.page_dwell_ms = 3000;
+        cfg.identity.name = Label::new("clock-7").unwrap();
+        cfg.units.clock = ClockFormat::H12;
+        cfg.room.volume_m3 = 75;
+        cfg.time.utc_offset_s = -5 * 3600;
+        cfg
+    }
+
+    #[test]
+    fn round_trip() {
+        let cfg = custom();
+        assert_eq!(Config::from_bytes(&cfg.to_bytes(7)), Some((cfg, 7)));
+    }
+
+    #[test]
+    fn blank_flash_uses_defaults() {
+        let mut flash = blank();
+        assert_eq!(Config::load(&mut flash), Ok(None));
+        assert_eq!(Config::load_or_default(&mut flash), Config::default());
+    }
+
+    #[test]
+    fn stores_alternate_between_pages() {
+        let mut flash = blank();
+        let mut cfg = custom();
+
+        for seq in 1..=4 {
+            cfg.scd30.altitude_m = seq as u16;
+            cfg.store(&mut flash).unwrap();
+            assert_eq!(Config::load(&mut flash), Ok(Some(cfg)));
+
+            let mut buf = [0u8; RECORD_LEN];
+            let page = Config::PAGES[(seq as usize + 1) % 2];
+            flash.read(page, &mut buf).unwrap();
+            assert_eq!(Config::from_bytes(&buf), Some((cfg, seq)));
+        }
+    }
+
+    #[test]
+    fn interrupted_store_keeps_previous_copy() {
+        let mut flash = blank();
+        let old = custom();
+        old.store(&mut flash).unwrap();
+
+        let mut new = old;
+        new.alert.hysteresis_ppm = 50;
+        new.store(&mut flash).unwrap();
+
+        // Lose the end of the record in the second page
+        let mut image = flash.into_inner();
+        let torn = Config::PAGES[1] as usize + RECORD_LEN - 8;
+        for b in &mut image[torn..][..8] {
+            *b = 0xFF;
+        }
+
+        let mut flash = MemFlash::new(image);
+        assert_eq!(Config::load(&mut flash), Ok(Some(old)));
+
+        // The next store must not overwrite the good copy
+        new.store(&mut flash).unwrap();
+        assert_eq!(Config::load(&mut flash), Ok(Some(new)));
+        let mut buf = [0u8; RECORD_LEN];
+        flash.read(Config::PAGES[0], &mut buf).unwrap();
+        assert_eq!(Config::from_bytes(&buf), Some((old, 1)));
+    }
+
+    #[test]
+    fn newer_versions_are_not_overwritten_by_older_sequence_numbers() {
+        let mut flash = blank();
+        let mut record = custom().to_bytes(41);
+        record[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let crc = crc32(&record[..RECORD_LEN - 4]);
+        record[RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        flash.write(Config::PAGES[0], &record).unwrap();
+
+        assert_eq!(Config::load(&mut flash), Ok(None));
+
+        Config::default().store(&mut flash).unwrap();
+        let mut buf = [0u8; RECORD_LEN];
+        flash.read(Config::PAGES[1], &mut buf).unwrap();
+        assert_eq!(Config::from_bytes(&buf), Some((Config::default(), 42)));
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let mut cfg = Config::default();
+        cfg.system.spim_khz = 3000;
+        assert_eq!(Config::from_bytes(&cfg.to_bytes(1)), None);
+
+        let mut record = custom().to_bytes(1);
+        record[HEADER_LEN + 44] = b'"';
+        let crc = crc32(&record[..RECORD_LEN - 4]);
+        record[RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(Config::from_bytes(&record), None);
+
+        let mut record = custom().to_bytes(1);
+        record[HEADER_LEN + 76] = 2;
+        let crc = crc32(&record[..RECORD_LEN - 4]);
+        record[RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(Config::from_bytes(&record), None);
+
+        // No ventilation at all
+        let mut record = custom().to_bytes(1);
+        record[HEADER_LEN + 80..][..2].copy_from_slice(&0u16.to_le_bytes());
+        let crc = crc32(&record[..RECORD_LEN - 4]);
+        record[RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(Config::from_bytes(&record), None);
+
+        // Not a whole number of quarter hours
+        let mut record = custom().to_bytes(1);
+        record[HEADER_LEN + 84..][..4].copy_from_slice(&600i32.to_le_bytes());
+        let crc = crc32(&record[..RECORD_LEN - 4]);
+        record[RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(Config::from_bytes(&record), None);
+    }
+
+    #[test]
+    fn get_and_set_by_name() {
+        let mut cfg = Config::default();
+        for key in Key::ALL.iter() {
+            assert_eq!(Key::from_name(key.name()), Some(*key));
+            let value = cfg.get(*key);
+            assert!(cfg.set(*key, value), "{}", key.name());
+        }
+        assert_eq!(cfg, Config::default());
+
+        let key = Key::from_name("alert.high_ppm").unwrap();
+        assert!(cfg.set(key, Value::Number(1500)));
+        assert_eq!(cfg.alert.thresholds_ppm, [1000, 1500, 2000]);
+
+        // Thresholds must stay in order
+        assert!(!cfg.set(key, Value::Number(2500)));
+        assert_eq!(cfg.get(key), Value::Number(1500));
+
+        assert!(!cfg.set(Key::AlertBuzzer, Value::Number(2)));
+        assert!(!cfg.set(Key::Scd30Altitude, Value::Number(70_000)));
+        assert_eq!(Key::from_name("nope"), None);
+
+        let name = Label::new("clock-7").unwrap();
+        let key = Key::from_name("identity.name").unwrap();
+        assert!(cfg.set(key, Value::Text(name)));
+        assert_eq!(cfg.identity.name, name);
+        assert_eq!(cfg.get(key).to_string(), "clock-7");
+
+        // Text and numbers don't mix
+        assert!(!cfg.set(key, Value::Number(7)));
+        assert!(!cfg.set(Key::PageDwell, Value::Text(name)));
+
+        assert!(cfg.set(Key::Fahrenheit, Value::Number(1)));
+        assert_eq!(cfg.units.temperature, TemperatureUnit::Fahrenheit);
+        assert_eq!(cfg.get(Key::Fahrenheit), Value::Number(1));
+        assert!(!cfg.set(Key::Clock12h, Value::Number(2)));
+        assert_eq!(cfg.units.clock, ClockFormat::H24);
+
+        assert!(cfg.set(Key::RoomAirChanges, Value::Number(25)));
+        assert_eq!(cfg.room.air_changes_dph, 25);
+        assert_eq!(cfg.get(Key::RoomVolume), Value::Number(30));
+        assert!(!cfg.set(Key::RoomOutdoor, Value::Number(5000)));
+        assert_eq!(cfg.room.outdoor_ppm, 420);
+    }
+
+    #[test]
+    fn watchdog_covers_the_screens() {
+        let system = SystemConfig::default();
+        assert!(system.longest_pass_ms() < system.watchdog_timeout_s as u32 * 1000);
+
+        // Slower screens need a longer timeout first
+        let mut cfg = Config::default();
+        assert!(!cfg.set(Key::PageDwell, Value::Number(10_000)));
+        assert!(cfg.set(Key::WatchdogTimeout, Value::Number(125)));
+        assert!(cfg.set(Key::PageDwell, Value::Number(10_000)));
+        assert!(!cfg.set(Key::WatchdogTimeout, Value::Number(124)));
+        assert_eq!(cfg.system.min_watchdog_timeout_s(), 125);
+
+        let system = SystemConfig {
+            watchdog_timeout_s: 10,
+            page_dwell_ms: 10_000,
+            ..SystemConfig::default()
+        };
+        assert!(!system.is_valid());
+
+        // Stored before the check, the timeout is raised to fit
+        let mut cfg = custom();
+        cfg.system.watchdog_timeout_s = 30;
+        let (loaded, _) = Config::from_bytes(&cfg.to_bytes(1)).unwrap();
+        assert_eq!(loaded.system.watchdog_timeout_s, 41);
+        assert_eq!(loaded.system.page_dwell_ms, 3000);
+    }
+}
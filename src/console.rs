@@ -0,0 +1,375 @@
+//! Line oriented command console
+//!
+//! Everything here is independent of the transport: bytes from the UART or
+//! USB serial port are fed into a [`LineBuffer`], and each complete line is
+//! turned into a [`Command`] by [`parse`]. Running the commands is up to
+//! the application, as it owns the hardware.
+
+use core::fmt;
+
+use crate::{
+    calendar::DateTime,
+    config::{Key, Value},
+    identity::Label,
+};
+
+/// Longest accepted line, in bytes. Anything past this is dropped.
+pub const MAX_LINE: usize = 64;
+
+/// Prompt printed before each line
+pub const PROMPT: &str = "> ";
+
+pub const HELP: &str = "\
+commands:
+  help
+  info
+  time get
+  time set <unix seconds | YYYY-MM-DD HH:MM:SS>
+  sensor read
+  config get [key]
+  config set <key> <value>
+  calibrate co2 [reference ppm]
+  calibrate asc <on|off>
+  i2c scan
+  log dump
+  stream <on|off>
+  reset
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum Command {
+    Help,
+    /// Who this clock is, see `identity`
+    Info,
+    TimeGet,
+    /// Seconds since the unix epoch
+    TimeSet(u32),
+    SensorRead,
+    /// A single setting, or all of them
+    ConfigGet(Option<Key>),
+    ConfigSet(Key, Value),
+    /// Forced recalibration, against the configured reference unless one
+    /// is given
+    CalibrateCo2(Option<u16>),
+    /// Turn automatic self-calibration on or off
+    CalibrateAsc(bool),
+    I2cScan,
+    LogDump,
+    /// Print each new sample as it is taken
+    Stream(bool),
+    Reset,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum ParseError {
+    UnknownCommand,
+    MissingArgument,
+    TooManyArguments,
+    BadArgument,
+    UnknownKey,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ParseError::UnknownCommand => "unknown command, try `help`",
+            ParseError::MissingArgument => "missing argument",
+            ParseError::TooManyArguments => "too many arguments",
+            ParseError::BadArgument => "bad argument",
+            ParseError::UnknownKey => "unknown config key, try `config get`",
+        };
+        f.write_str(msg)
+    }
+}
+
+/// Parse a line of input. Returns `Ok(None)` for blank lines.
+pub fn parse(line: &str) -> Result<Option<Command>, ParseError> {
+    let mut words = line.split_whitespace();
+    let first = match words.next() {
+        Some(word) => word,
+        None => return Ok(None),
+    };
+    let second = words.next();
+
+    let cmd = match (first, second) {
+        ("help", None) | ("?", None) => Command::Help,
+        ("info", None) => Command::Info,
+        ("time", Some("get")) => Command::TimeGet,
+        ("time", Some("set")) => {
+            let arg = words.next().ok_or(ParseError::MissingArgument)?;
+            let timestamp = match words.next() {
+                Some(time) => DateTime::parse(arg, time).and_then(|dt| dt.to_unix()),
+                None => arg.parse().ok(),
+            };
+            Command::TimeSet(timestamp.ok_or(ParseError::BadArgument)?)
+        }
+        ("sensor", Some("read")) => Command::SensorRead,
+        ("config", Some("get")) => match words.next() {
+            Some(name) => Command::ConfigGet(Some(parse_key(name)?)),
+            None => Command::ConfigGet(None),
+        },
+        ("config", Some("set")) => {
+            let key = parse_key(words.next().ok_or(ParseError::MissingArgument)?)?;
+            let value = words.next().ok_or(ParseError::MissingArgument)?;
+            if key.is_text() {
+                // Text runs to the end of the line, spaces and all
+                let start = value.as_ptr() as usize - line.as_ptr() as usize;
+                let text = Label::new(line[start..].trim_end()).ok_or(ParseError::BadArgument)?;
+                return Ok(Some(Command::ConfigSet(key, Value::Text(text))));
+            }
+            Command::ConfigSet(key, Value::Number(parse_value(value)?))
+        }
+        ("calibrate", Some("co2")) => match words.next() {
+            Some(ppm) => {
+                Command::CalibrateCo2(Some(ppm.parse().map_err(|_| ParseError::BadArgument)?))
+            }
+            None => Command::CalibrateCo2(None),
+        },
+        ("calibrate", Some("asc")) => {
+            let value = words.next().ok_or(ParseError::MissingArgument)?;
+            Command::CalibrateAsc(parse_value(value)? != 0)
+        }
+        ("i2c", Some("scan")) => Command::I2cScan,
+        ("log", Some("dump")) => Command::LogDump,
+        ("stream", Some(value)) => Command::Stream(parse_value(value)? != 0),
+        ("reset", None) => Command::Reset,
+        ("help", Some(_)) | ("?", Some(_)) | ("info", Some(_)) | ("reset", Some(_)) => {
+            return Err(ParseError::TooManyArguments)
+        }
+        ("time", None) | ("sensor", None) | ("config", None) | ("calibrate", None) => {
+            return Err(ParseError::MissingArgument)
+        }
+        ("i2c", None) | ("log", None) | ("stream", None) => {
+            return Err(ParseError::MissingArgument)
+        }
+        _ => return Err(ParseError::UnknownCommand),
+    };
+
+    if words.next().is_some() {
+        return Err(ParseError::TooManyArguments);
+    }
+    Ok(Some(cmd))
+}
+
+fn parse_key(name: &str) -> Result<Key, ParseError> {
+    Key::from_name(name).ok_or(ParseError::UnknownKey)
+}
+
+/// Numbers, or on/off for switches
+fn parse_value(value: &str) -> Result<u32, ParseError> {
+    match value {
+        "on" | "true" => Ok(1),
+        "off" | "false" => Ok(0),
+        _ => value.parse().map_err(|_| ParseError::BadArgument),
+    }
+}
+
+/// What became of a byte fed to a [`LineBuffer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input<'a> {
+    /// Taken into the line, or removed a character from it. Echo it back.
+    Echo,
+    /// Dropped, e.g. the LF of a CRLF. Don't echo it.
+    Ignored,
+    /// The line is complete
+    Line(&'a str),
+}
+
+/// Collects bytes into lines, with basic editing
+pub struct LineBuffer {
+    buf: [u8; MAX_LINE],
+    len: usize,
+    /// A line was returned, clear it before taking more input
+    done: bool,
+    /// The last byte was a CR, so an LF straight after it isn't a new line
+    after_cr: bool,
+}
+
+impl Default for LineBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineBuffer {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_LINE],
+            len: 0,
+            done: false,
+            after_cr: false,
+        }
+    }
+
+    /// Feed in a received byte, returning the line once it is complete.
+    ///
+    /// Lines may end with CR, LF or both, and the LF of a CRLF is ignored.
+    /// Backspace and delete remove the last character, other control
+    /// characters, non-ASCII bytes and anything past [`MAX_LINE`] are
+    /// ignored.
+    pub fn push(&mut self, byte: u8) -> Input<'_> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        if byte == b'\n' && after_cr {
+            return Input::Ignored;
+        }
+
+        if self.done {
+            self.len = 0;
+            self.done = false;
+        }
+
+        match byte {
+            b'\r' | b'\n' => {
+                self.done = true;
+                // Only printable ASCII is ever stored
+                let line = core::str::from_utf8(&self.buf[..self.len]).unwrap_or("");
+                Input::Line(line)
+            }
+            0x08 | 0x7F if self.len > 0 => {
+                self.len -= 1;
+                Input::Echo
+            }
+            b' '..=b'~' if self.len < MAX_LINE => {
+                self.buf[self.len] = byte;
+                self.len += 1;
+                Input::Echo
+            }
+            _ => Input::Ignored,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    fn lines(input: &[u8]) -> Vec<String> {
+        let mut buf = LineBuffer::new();
+        input
+            .iter()
+            .filter_map(|b| match buf.push(*b) {
+                Input::Line(line) => Some(String::from(line)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The bytes that would be echoed back
+    fn echoed(input: &[u8]) -> Vec<u8> {
+        let mut buf = LineBuffer::new();
+        input
+            .iter()
+            .filter(|b| buf.push(**b) == Input::Echo)
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn line_editing() {
+        assert_eq!(
+            lines(b"time get\r\nlog dump\r\n"),
+            vec!["time get", "log dump"]
+        );
+        assert_eq!(lines(b"\n\n"), vec!["", ""]);
+        assert_eq!(lines(b"tine\x08\x08me get\n"), vec!["time get"]);
+        assert_eq!(lines(b"\x1bi2c\x7f\x7f2c scan\r"), vec!["i2c scan"]);
+
+        let long = [b'a'; MAX_LINE + 10];
+        let got = lines(&[&long[..], b"\n"].concat());
+        assert_eq!(got[0].len(), MAX_LINE);
+    }
+
+    #[test]
+    fn crlf_is_one_line_ending() {
+        let mut buf = LineBuffer::new();
+        assert_eq!(buf.push(b'a'), Input::Echo);
+        assert_eq!(buf.push(b'\r'), Input::Line("a"));
+        assert_eq!(buf.push(b'\n'), Input::Ignored);
+        assert_eq!(buf.push(b'\n'), Input::Line(""));
+        assert_eq!(buf.push(b'\r'), Input::Line(""));
+        assert_eq!(buf.push(b'b'), Input::Echo);
+        assert_eq!(buf.push(b'\n'), Input::Line("b"));
+
+        // Line endings are never echoed, only what goes into the line
+        assert_eq!(echoed(b"ab\x08\x1b\r\n\x7f\xe9c\r\n"), b"ab\x08c");
+    }
+
+    #[test]
+    fn commands() {
+        let cases = [
+            ("", None),
+            ("   ", None),
+            ("help", Some(Command::Help)),
+            ("info", Some(Command::Info)),
+            ("time get", Some(Command::TimeGet)),
+            ("time set 1614000000", Some(Command::TimeSet(1_614_000_000))),
+            (
+                "time set 2021-02-21 22:36:40",
+                Some(Command::TimeSet(1_613_947_000)),
+            ),
+            ("sensor  read", Some(Command::SensorRead)),
+            ("config get", Some(Command::ConfigGet(None))),
+            (
+                "config get alert.buzzer",
+                Some(Command::ConfigGet(Some(Key::AlertBuzzer))),
+            ),
+            (
+                "config set alert.buzzer on",
+                Some(Command::ConfigSet(Key::AlertBuzzer, Value::Number(1))),
+            ),
+            (
+                "config set scd30.altitude_m 120",
+                Some(Command::ConfigSet(Key::Scd30Altitude, Value::Number(120))),
+            ),
+            (
+                "config set identity.location  Room 2.14, desk ",
+                Some(Command::ConfigSet(
+                    Key::Location,
+                    Value::Text(Label::new("Room 2.14, desk").unwrap()),
+                )),
+            ),
+            ("calibrate co2", Some(Command::CalibrateCo2(None))),
+            ("calibrate co2 415", Some(Command::CalibrateCo2(Some(415)))),
+            ("calibrate asc off", Some(Command::CalibrateAsc(false))),
+            ("i2c scan", Some(Command::I2cScan)),
+            ("log dump", Some(Command::LogDump)),
+            ("stream on", Some(Command::Stream(true))),
+            ("stream off", Some(Command::Stream(false))),
+            ("reset", Some(Command::Reset)),
+        ];
+        for (line, expected) in cases.iter() {
+            assert_eq!(parse(line), Ok(*expected), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("launch", ParseError::UnknownCommand),
+            ("time", ParseError::MissingArgument),
+            ("time now", ParseError::UnknownCommand),
+            ("time set", ParseError::MissingArgument),
+            ("time set yesterday", ParseError::BadArgument),
+            ("time set 2021-02-30 00:00:00", ParseError::BadArgument),
+            ("config get colour", ParseError::UnknownKey),
+            ("config set alert.buzzer", ParseError::MissingArgument),
+            ("config set alert.buzzer maybe", ParseError::BadArgument),
+            ("config set identity.name", ParseError::MissingArgument),
+            (
+                "config set identity.name far too long a name",
+                ParseError::BadArgument,
+            ),
+            ("info please", ParseError::TooManyArguments),
+            ("calibrate co2 lots", ParseError::BadArgument),
+            ("reset now", ParseError::TooManyArguments),
+            ("i2c scan 0x70", ParseError::TooManyArguments),
+            ("stream", ParseError::MissingArgument),
+            ("stream fast", ParseError::BadArgument),
+        ];
+        for (line, expected) in cases.iter() {
+            assert_eq!(parse(line), Err(*expected), "{:?}", line);
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//! Crash dumps, kept across the reset that follows a crash
+//!
+//! The panic and HardFault handlers write a [`Crash`] into a section of RAM
+//! that the runtime doesn't zero at boot, and RAM keeps its contents
+//! through a system or watchdog reset. On the next boot [`take`] reads it
+//! back, so the application can log it, store it in the data log and show
+//! it on the display. A magic word and a CRC tell a real dump from whatever
+//! RAM held at power on.
+//!
+//! The record, all little endian:
+//!
+//! | offset   | field                                        |
+//! |----------|----------------------------------------------|
+//! | 0..4     | magic                                        |
+//! | 4        | kind                                         |
+//! | 5        | message length                               |
+//! | 8..12    | panic line, 0 for faults                     |
+//! | 12..20   | PC, LR                                       |
+//! | 20..36   | CFSR, HFSR, MMFAR, BFAR                      |
+//! | 36..40   | uptime, seconds                              |
+//! | 40..120  | message, as printed by the panic handler     |
+//! | 120..124 | CRC-32 of the above                          |
+
+use core::fmt;
+
+use crate::crc::crc32;
+
+/// "CRSH", little endian
+const MAGIC: u32 = 0x4853_5243;
+
+pub const MESSAGE_LEN: usize = 80;
+
+pub const RECORD_LEN: usize = 40 + MESSAGE_LEN + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Kind {
+    Panic = 1,
+    HardFault = 2,
+}
+
+/// As much of a message as fits, cut short if need be
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Message {
+    bytes: [u8; MESSAGE_LEN],
+    len: u8,
+}
+
+impl Message {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; MESSAGE_LEN],
+            len: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        let bytes = &self.bytes[..self.len as usize];
+        match core::str::from_utf8(bytes) {
+            Ok(s) => s,
+            // Cut short in the middle of a character
+            Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+}
+
+impl Default for Message {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = self.len as usize;
+        let n = s.len().min(MESSAGE_LEN - len);
+        self.bytes[len..][..n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n as u8;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crash {
+    pub kind: Kind,
+    /// Where the panic happened, 0 for faults
+    pub line: u32,
+    /// Where the fault happened, 0 for panics
+    pub pc: u32,
+    pub lr: u32,
+    /// Configurable, HardFault, MemManage and BusFault status and address
+    /// registers from the SCB
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+    pub uptime_s: u32,
+    pub message: Message,
+}
+
+impl Crash {
+    pub fn panic(line: u32, message: Message, uptime_s: u32) -> Self {
+        Self {
+            kind: Kind::Panic,
+            line,
+            pc: 0,
+            lr: 0,
+            cfsr: 0,
+            hfsr: 0,
+            mmfar: 0,
+            bfar: 0,
+            uptime_s,
+            message,
+        }
+    }
+
+    /// Short enough for the display and the data log: the line for a
+    /// panic, the low half of the PC for a fault
+    pub fn code(&self) -> u16 {
+        match self.kind {
+            Kind::Panic => self.line.min(9999) as u16,
+            Kind::HardFault => self.pc as u16,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut buf = [0u8; RECORD_LEN];
+        buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[4] = self.kind as u8;
+        buf[5] = self.message.len;
+        let words = [
+            self.line,
+            self.pc,
+            self.lr,
+            self.cfsr,
+            self.hfsr,
+            self.mmfar,
+            self.bfar,
+            self.uptime_s,
+        ];
+        for (i, word) in words.iter().enumerate() {
+            buf[8 + i * 4..][..4].copy_from_slice(&word.to_le_bytes());
+        }
+        buf[40..][..MESSAGE_LEN].copy_from_slice(&self.message.bytes);
+
+        let crc = crc32(&buf[..RECORD_LEN - 4]);
+        buf[RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Returns `None` unless the buffer holds an intact record
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < RECORD_LEN {
+            return None;
+        }
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        if u32_at(0) != MAGIC || u32_at(RECORD_LEN - 4) != crc32(&buf[..RECORD_LEN - 4]) {
+            return None;
+        }
+
+        let kind = match buf[4] {
+            1 => Kind::Panic,
+            2 => Kind::HardFault,
+            _ => return None,
+        };
+        let len = buf[5];
+        if len as usize > MESSAGE_LEN {
+            return None;
+        }
+        let mut message = Message::new();
+        message.bytes.copy_from_slice(&buf[40..][..MESSAGE_LEN]);
+        message.len = len;
+
+        Some(Self {
+            kind,
+            line: u32_at(8),
+            pc: u32_at(12),
+            lr: u32_at(16),
+            cfsr: u32_at(20),
+            hfsr: u32_at(24),
+            mmfar: u32_at(28),
+            bfar: u32_at(32),
+            uptime_s: u32_at(36),
+            message,
+        })
+    }
+}
+
+#[cfg(feature = "firmware")]
+mod capture {
+    use core::{fmt::Write as _, mem::MaybeUninit, panic::PanicInfo, ptr};
+
+    use cortex_m::peripheral::SCB;
+    use cortex_m_rt::ExceptionFrame;
+
+    use super::{Crash, Kind, Message, RECORD_LEN};
+    use crate::monotonic;
+
+    /// Left alone by the runtime at boot, so it survives a reset
+    #[link_section = ".uninit.CRASH"]
+    static mut CRASH: MaybeUninit<[u8; RECORD_LEN]> = MaybeUninit::uninit();
+
+    fn read() -> [u8; RECORD_LEN] {
+        // SAFETY: only accessed with interrupts off, or from the panic and
+        // fault handlers which never return. Any bit pattern is a valid
+        // `[u8; N]`, and `decode` checks it.
+        unsafe { ptr::read_volatile(CRASH.as_ptr()) }
+    }
+
+    fn write(record: &[u8; RECORD_LEN]) {
+        // SAFETY: as for `read`
+        unsafe { ptr::write_volatile(CRASH.as_mut_ptr(), *record) }
+    }
+
+    /// Keep the first crash if a second one follows, e.g. a fault while
+    /// handling a panic. The dump from the previous boot was taken already.
+    fn store(crash: &Crash) {
+        if Crash::decode(&read()).is_none() {
+            write(&crash.encode());
+        }
+    }
+
+    fn uptime_s() -> u32 {
+        monotonic::now().since_start().as_secs() as u32
+    }
+
+    /// Record a panic, returning the message for logging. Call this from
+    /// the panic handler.
+    pub fn record_panic(info: &PanicInfo) -> Message {
+        let mut message = Message::new();
+        write!(message, "{}", info).ok();
+        let line = info.location().map(|l| l.line()).unwrap_or(0);
+
+        store(&Crash::panic(line, message, uptime_s()));
+        message
+    }
+
+    /// Record a `defmt::panic!`, which doesn't say where it happened
+    pub fn record_defmt_panic() {
+        let mut message = Message::new();
+        message.write_str("defmt panic").ok();
+        store(&Crash::panic(0, message, uptime_s()));
+    }
+
+    /// Record a HardFault. Call this from the HardFault handler.
+    pub fn record_hard_fault(frame: &ExceptionFrame) {
+        // SAFETY: reading the fault status registers has no side effects
+        let scb = unsafe { &*SCB::ptr() };
+        let crash = Crash {
+            kind: Kind::HardFault,
+            line: 0,
+            pc: frame.pc,
+            lr: frame.lr,
+            cfsr: scb.cfsr.read(),
+            hfsr: scb.hfsr.read(),
+            mmfar: scb.mmfar.read(),
+            bfar: scb.bfar.read(),
+            uptime_s: uptime_s(),
+            message: Message::new(),
+        };
+        store(&crash);
+    }
+
+    impl Crash {
+        pub fn log(&self) {
+            defmt::error!(
+                "Last reset was a {:?} after {=u32}s: {=str}",
+                self.kind,
+                self.uptime_s,
+                self.message.as_str()
+            );
+            if self.kind == Kind::HardFault {
+                defmt::error!(
+                    "PC {=u32:x} LR {=u32:x} CFSR {=u32:x} HFSR {=u32:x} MMFAR {=u32:x} BFAR {=u32:x}",
+                    self.pc,
+                    self.lr,
+                    self.cfsr,
+                    self.hfsr,
+                    self.mmfar,
+                    self.bfar
+                );
+            }
+        }
+    }
+
+    /// The crash that caused the last reset, if there was one. Clears it,
+    /// so call this once at boot.
+    pub fn take() -> Option<Crash> {
+        cortex_m::interrupt::free(|_| {
+            let crash = Crash::decode(&read());
+            write(&[0; RECORD_LEN]);
+            crash
+        })
+    }
+}
+
+#[cfg(feature = "firmware")]
+pub use capture::{record_defmt_panic, record_hard_fault, record_panic, take};
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use core::fmt::Write as _;
+
+    fn fault() -> Crash {
+        let mut message = Message::new();
+        write!(message, "IMPRECISERR").unwrap();
+        Crash {
+            kind: Kind::HardFault,
+            line: 0,
+            pc: 0x0001_2A4C,
+            lr: 0x0001_2A11,
+            cfsr: 0x0000_0400,
+            hfsr: 0x4000_0000,
+            mmfar: 0xE000_EDF8,
+            bfar: 0xE000_EDF8,
+            uptime_s: 86_400,
+            message,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let crash = fault();
+        let buf = crash.encode();
+        assert_eq!(&buf[0..4], b"CRSH");
+        assert_eq!(Crash::decode(&buf), Some(crash));
+        assert_eq!(crash.code(), 0x2A4C);
+
+        let mut message = Message::new();
+        write!(message, "sevseg.rs:210: No RTC found!").unwrap();
+        let panic = Crash::panic(210, message, 3);
+        assert_eq!(Crash::decode(&panic.encode()), Some(panic));
+        assert_eq!(panic.message.as_str(), "sevseg.rs:210: No RTC found!");
+        assert_eq!(panic.code(), 210);
+    }
+
+    #[test]
+    fn garbage_is_ignored() {
+        // Zeroed, and erased
+        assert_eq!(Crash::decode(&[0; RECORD_LEN]), None);
+        assert_eq!(Crash::decode(&[0xFF; RECORD_LEN]), None);
+
+        let mut buf = fault().encode();
+        buf[13] ^= 0x01;
+        assert_eq!(Crash::decode(&buf), None);
+
+        // Right CRC, but nonsense
+        let mut buf = fault().encode();
+        buf[4] = 7;
+        let crc = crc32(&buf[..RECORD_LEN - 4]);
+        buf[RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(Crash::decode(&buf), None);
+
+        assert_eq!(Crash::decode(&fault().encode()[..RECORD_LEN - 1]), None);
+    }
+
+    #[test]
+    fn long_messages_are_cut_short() {
+        let mut message = Message::new();
+        for _ in 0..MESSAGE_LEN {
+            write!(message, "\u{e9}").unwrap();
+        }
+        // Two bytes each, and the record stops at the last whole one
+        assert_eq!(message.as_str().chars().count(), MESSAGE_LEN / 2);
+
+        let mut message = Message::new();
+        write!(message, "x").unwrap();
+        for _ in 0..MESSAGE_LEN {
+            write!(message, "\u{e9}").unwrap();
+        }
+        assert_eq!(message.as_str().len(), MESSAGE_LEN - 1);
+
+        let crash = Crash::panic(u32::MAX, message, 0);
+        assert_eq!(crash.code(), 9999);
+        assert_eq!(Crash::decode(&crash.encode()), Some(crash));
+    }
+}
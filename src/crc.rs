@@ -0,0 +1,41 @@
+//! Small, table-free CRC implementations
+//!
+//! These are bitwise rather than table driven, as everything we check is
+//! at most a few hundred bytes long, and flash is worth more than cycles.
+
+/// CRC-8 as used by Sensirion sensors (poly 0x31, init 0xFF)
+pub fn crc8_sensirion(data: &[u8]) -> u8 {
+    let mut crc = 0xFFu8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if (crc & 0x80) != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// CRC-32 (IEEE 802.3, as used by zlib/PNG)
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(0xFFFF_FFFF, data)
+}
+
+/// Feed more data into a running CRC-32. Start with `0xFFFF_FFFF`, and
+/// invert the final result.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
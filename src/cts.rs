@@ -0,0 +1,353 @@
+//! Current Time Service values, and when to act on them
+//!
+//! Once a day, see [`SyncSchedule`], the clock asks for the time by
+//! soliciting the Current Time Service in its advertisements. The BLE
+//! stack can only be connected to, so a gateway that answers connects and
+//! writes the time to the clock's own service, see `gatt`: Local Time
+//! Information first if it has it, then Current Time. See the GATT
+//! Specification Supplement for the formats.
+
+use crate::calendar::DateTime;
+
+pub const CURRENT_TIME_LEN: usize = 10;
+pub const LOCAL_TIME_INFO_LEN: usize = 2;
+
+/// The RTC is only corrected once it is off by more than this
+pub const DRIFT_THRESHOLD_S: u32 = 2;
+
+/// Time zone and DST offsets are in units of 15 minutes
+const QUARTER_HOUR_S: i64 = 15 * 60;
+
+/// DST offset meaning "unknown"
+const DST_UNKNOWN: u8 = 255;
+
+/// The Current Time characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct CurrentTime {
+    /// Local time, as kept by the sender
+    pub local: DateTime,
+    /// 1/256ths of a second
+    pub fractions256: u8,
+    /// Why the sender's time last changed, e.g. manual update or DST
+    pub adjust_reason: u8,
+}
+
+impl CurrentTime {
+    /// Returns `None` if the date or time is unknown or invalid
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != CURRENT_TIME_LEN {
+            return None;
+        }
+
+        let local = DateTime {
+            year: u16::from_le_bytes([data[0], data[1]]),
+            month: data[2],
+            day: data[3],
+            hour: data[4],
+            minute: data[5],
+            second: data[6],
+        };
+        // Day of the week, 0 is unknown
+        if data[7] > 7 {
+            return None;
+        }
+        local.to_unix()?;
+
+        Some(Self {
+            local,
+            fractions256: data[8],
+            adjust_reason: data[9],
+        })
+    }
+
+    pub fn encode(&self) -> [u8; CURRENT_TIME_LEN] {
+        let [y0, y1] = self.local.year.to_le_bytes();
+        [
+            y0,
+            y1,
+            self.local.month,
+            self.local.day,
+            self.local.hour,
+            self.local.minute,
+            self.local.second,
+            self.local.weekday(),
+            self.fractions256,
+            self.adjust_reason,
+        ]
+    }
+}
+
+/// The Local Time Information characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct LocalTimeInfo {
+    /// Standard time offset from UTC, in 15 minute steps
+    pub time_zone: i8,
+    /// Daylight saving offset, in 15 minute steps, if known
+    pub dst_offset: Option<u8>,
+}
+
+impl LocalTimeInfo {
+    /// Returns `None` if the time zone is unknown or out of range
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != LOCAL_TIME_INFO_LEN {
+            return None;
+        }
+
+        let time_zone = data[0] as i8;
+        if !(-48..=56).contains(&time_zone) {
+            return None;
+        }
+        let dst_offset = match data[1] {
+            DST_UNKNOWN => None,
+            offset if [0, 2, 4, 8].contains(&offset) => Some(offset),
+            _ => return None,
+        };
+        Some(Self {
+            time_zone,
+            dst_offset,
+        })
+    }
+
+    pub fn encode(&self) -> [u8; LOCAL_TIME_INFO_LEN] {
+        [self.time_zone as u8, self.dst_offset.unwrap_or(DST_UNKNOWN)]
+    }
+
+    /// Local time minus UTC, in seconds
+    pub fn offset_s(&self) -> i32 {
+        let quarters = self.time_zone as i64 + self.dst_offset.unwrap_or(0) as i64;
+        (quarters * QUARTER_HOUR_S) as i32
+    }
+}
+
+/// A time written by a time source, see `gatt`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct TimeReference {
+    /// The sender's local time, in seconds since the unix epoch as if it
+    /// were UTC
+    pub local: u32,
+    /// Local time minus UTC, in seconds, if the sender gave its Local Time
+    /// Information
+    pub utc_offset_s: Option<i32>,
+}
+
+impl TimeReference {
+    /// Returns `None` if the date is out of range
+    pub fn new(current: &CurrentTime, info: Option<&LocalTimeInfo>) -> Option<Self> {
+        Some(Self {
+            local: current.local.to_unix()?,
+            utc_offset_s: info.map(LocalTimeInfo::offset_s),
+        })
+    }
+
+    /// Seconds since the unix epoch. Without the sender's offset, the one
+    /// kept in the config is used.
+    pub fn to_utc(&self, time: &TimeConfig) -> Option<u32> {
+        let offset = self.utc_offset_s.unwrap_or(time.utc_offset_s) as i64;
+        let utc = self.local as i64 - offset;
+        if (0..=u32::MAX as i64).contains(&utc) {
+            Some(utc as u32)
+        } else {
+            None
+        }
+    }
+}
+
+/// The local time zone, kept in the config. The RTC and the data log stay
+/// in UTC, the screens and the quiet hours go by local time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct TimeConfig {
+    /// Local time minus UTC, in seconds, daylight saving included. Taken
+    /// from the Local Time Information at each sync that has it.
+    pub utc_offset_s: i32,
+}
+
+impl TimeConfig {
+    /// UTC-12:00 to UTC+14:00 plus up to two hours of daylight saving, in
+    /// 15 minute steps, as Local Time Information allows
+    pub fn is_valid(&self) -> bool {
+        let offset = self.utc_offset_s as i64;
+        (-48 * QUARTER_HOUR_S..=64 * QUARTER_HOUR_S).contains(&offset)
+            && offset % QUARTER_HOUR_S == 0
+    }
+
+    /// The local time, in seconds since the unix epoch as if it were UTC
+    pub fn local(&self, utc: u32) -> u32 {
+        (utc as i64 + self.utc_offset_s as i64).clamp(0, u32::MAX as i64) as u32
+    }
+
+    /// When the local day holding `utc` began, in seconds since the unix
+    /// epoch
+    pub fn start_of_day(&self, utc: u32) -> u32 {
+        utc.saturating_sub(self.local(utc) % 86_400)
+    }
+}
+
+/// How far to move the RTC to match the reference, or `None` if it is
+/// close enough already
+pub fn correction(rtc: u32, reference: u32) -> Option<i64> {
+    let drift = reference as i64 - rtc as i64;
+    if drift.unsigned_abs() > DRIFT_THRESHOLD_S as u64 {
+        Some(drift)
+    } else {
+        None
+    }
+}
+
+/// How often to ask for the time. The DS3231 is good to 2 ppm, about a
+/// second a week, so a daily sync keeps it within the threshold.
+pub const SYNC_INTERVAL_S: u64 = 24 * 60 * 60;
+
+/// When the RTC is due a sync, in seconds on the monotonic clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SyncSchedule {
+    last_s: Option<u64>,
+}
+
+impl SyncSchedule {
+    pub const fn new() -> Self {
+        Self { last_s: None }
+    }
+
+    /// Due from startup until the first sync, then every
+    /// [`SYNC_INTERVAL_S`]
+    pub fn is_due(&self, now_s: u64) -> bool {
+        match self.last_s {
+            Some(last_s) => now_s.saturating_sub(last_s) >= SYNC_INTERVAL_S,
+            None => true,
+        }
+    }
+
+    /// A time reference arrived, whether or not the RTC needed correcting
+    pub fn synced(&mut self, now_s: u64) {
+        self.last_s = Some(now_s);
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    // 2021-02-21 23:36:40, a Sunday, a quarter of the way into the second
+    const CURRENT_TIME: [u8; 10] = [0xE5, 0x07, 2, 21, 23, 36, 40, 7, 64, 1];
+
+    #[test]
+    fn current_time() {
+        let ct = CurrentTime::decode(&CURRENT_TIME).unwrap();
+        assert_eq!(ct.local.to_string(), "2021-02-21T23:36:40Z");
+        assert_eq!(ct.fractions256, 64);
+        assert_eq!(ct.adjust_reason, 1);
+        assert_eq!(ct.encode(), CURRENT_TIME);
+
+        // Unknown year and month
+        let mut unknown = CURRENT_TIME;
+        unknown[..4].copy_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(CurrentTime::decode(&unknown), None);
+
+        let mut bad_weekday = CURRENT_TIME;
+        bad_weekday[7] = 8;
+        assert_eq!(CurrentTime::decode(&bad_weekday), None);
+
+        assert_eq!(CurrentTime::decode(&CURRENT_TIME[..9]), None);
+    }
+
+    #[test]
+    fn local_time_info() {
+        let info = LocalTimeInfo::decode(&[4, 4]).unwrap();
+        assert_eq!(info.offset_s(), 2 * 3600);
+        assert_eq!(info.encode(), [4, 4]);
+
+        // UTC-3:30, DST unknown
+        let info = LocalTimeInfo::decode(&[(-14i8) as u8, 255]).unwrap();
+        assert_eq!(info.dst_offset, None);
+        assert_eq!(info.offset_s(), -(3 * 3600 + 1800));
+
+        assert_eq!(LocalTimeInfo::decode(&[(-128i8) as u8, 0]), None);
+        assert_eq!(LocalTimeInfo::decode(&[4, 3]), None);
+        assert_eq!(LocalTimeInfo::decode(&[4]), None);
+    }
+
+    #[test]
+    fn reference() {
+        let ct = CurrentTime::decode(&CURRENT_TIME).unwrap();
+        let cet = LocalTimeInfo {
+            time_zone: 4,
+            dst_offset: Some(0),
+        };
+        let utc = TimeConfig::default();
+        let eastern = TimeConfig {
+            utc_offset_s: -5 * 3600,
+        };
+
+        // The sender's offset wins, and is what the config will keep
+        let reference = TimeReference::new(&ct, Some(&cet)).unwrap();
+        assert_eq!(reference.utc_offset_s, Some(3600));
+        assert_eq!(reference.to_utc(&utc), Some(1_613_947_000));
+        assert_eq!(reference.to_utc(&eastern), Some(1_613_947_000));
+
+        // Without one, the offset already kept is used
+        let reference = TimeReference::new(&ct, None).unwrap();
+        assert_eq!(reference.local, 1_613_950_600);
+        assert_eq!(reference.to_utc(&utc), Some(1_613_950_600));
+        assert_eq!(reference.to_utc(&eastern), Some(1_613_968_600));
+
+        let early = TimeReference {
+            local: 0,
+            utc_offset_s: Some(3600),
+        };
+        assert_eq!(early.to_utc(&utc), None);
+    }
+
+    #[test]
+    fn local_time() {
+        // 2021-02-21 22:36:40 UTC is 17:36:40 in New York
+        let eastern = TimeConfig {
+            utc_offset_s: -5 * 3600,
+        };
+        let local = DateTime::from_unix(eastern.local(1_613_947_000));
+        assert_eq!(local.to_string(), "2021-02-21T17:36:40Z");
+        assert_eq!(TimeConfig::default().local(1_613_947_000), 1_613_947_000);
+        assert_eq!(eastern.local(0), 0);
+
+        // Local midnight is 05:00 UTC, so the day runs on past UTC midnight
+        assert_eq!(eastern.start_of_day(1_613_947_000), 1_613_883_600);
+        assert_eq!(eastern.start_of_day(1_613_962_800), 1_613_883_600);
+        assert_eq!(eastern.start_of_day(1_613_883_599), 1_613_797_200);
+        let utc = TimeConfig::default();
+        assert_eq!(utc.start_of_day(1_613_947_000), 1_613_865_600);
+
+        let valid = |utc_offset_s| TimeConfig { utc_offset_s }.is_valid();
+        assert!(valid(-5 * 3600));
+        assert!(valid(16 * 3600));
+        assert!(!valid(-13 * 3600));
+        assert!(!valid(600));
+        // The furthest Local Time Information can go
+        let lti = LocalTimeInfo::decode(&[56, 8]).unwrap();
+        assert!(valid(lti.offset_s()));
+    }
+
+    #[test]
+    fn drift() {
+        assert_eq!(correction(1000, 1000), None);
+        assert_eq!(correction(1000, 1002), None);
+        assert_eq!(correction(1002, 1000), None);
+        assert_eq!(correction(1000, 1003), Some(3));
+        assert_eq!(correction(1003, 1000), Some(-3));
+        assert_eq!(correction(0, u32::MAX), Some(u32::MAX as i64));
+    }
+
+    #[test]
+    fn schedule() {
+        let mut schedule = SyncSchedule::new();
+        assert!(schedule.is_due(0));
+        assert!(schedule.is_due(5));
+
+        schedule.synced(10);
+        assert!(!schedule.is_due(10));
+        assert!(!schedule.is_due(10 + SYNC_INTERVAL_S - 1));
+        assert!(schedule.is_due(10 + SYNC_INTERVAL_S));
+    }
+}
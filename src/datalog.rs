@@ -0,0 +1,509 @@
+//! Wear-levelled sensor and event log
+//!
+//! The log region is treated as a ring of erase sectors. Each sector starts
+//! with a header holding a sequence number, followed by records packed as
+//! [`record`](crate::record) frames. Records are appended until the sector
+//! is full, then the next sector in the ring is erased and takes the next
+//! sequence number. Every sector is erased once per trip around the ring,
+//! so wear is spread evenly, and the oldest data is discarded first.
+//!
+//! Each sector starts with an absolute timestamp, so any sector can be
+//! decoded without the ones before it. Frames are padded to a multiple of
+//! four bytes, as the flash can only be written a word at a time. The
+//! padding is `0x00` rather than the `0xFF` of erased flash, so the last
+//! word of a frame is never taken for free space.
+//!
+//! On startup the sector with the highest sequence number is the one being
+//! written to, and the first word after the last programmed word in it is
+//! where the next record goes.
+
+use crate::{
+    crc::crc32,
+    flash::Flash,
+    record::{self, Encoder, Frames, FILL, MAX_FRAME_LEN, PADDING},
+};
+
+pub use crate::record::{EventKind, Record};
+
+/// Size of a sector header in bytes
+const HEADER_SIZE: usize = 16;
+
+/// Largest sector size supported, sectors are read into RAM to decode them
+pub const MAX_SECTOR_SIZE: usize = 4096;
+
+/// "FCLG", little endian
+const SECTOR_MAGIC: u32 = 0x474C_4346;
+
+/// Bumped whenever the on-flash layout changes
+const FORMAT_VERSION: u16 = 2;
+
+/// Frames rounded up to whole words, plus a word of fill for a frame that
+/// ends in a word of `0xFF`
+const MAX_SLOT_LEN: usize = ((MAX_FRAME_LEN + 3) & !3) + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SectorHeader {
+    seq: u32,
+}
+
+impl SectorHeader {
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0xFFu8; HEADER_SIZE];
+        buf[0..4].copy_from_slice(&SECTOR_MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        buf[8..10].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        buf[10..12].copy_from_slice(&(record::VERSION as u16).to_le_bytes());
+        let crc = crc32(&buf[..12]);
+        buf[12..16].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; HEADER_SIZE]) -> Option<Self> {
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+
+        let valid = u32_at(0) == SECTOR_MAGIC
+            && u16_at(8) == FORMAT_VERSION
+            && u16_at(10) == record::VERSION as u16
+            && u32_at(12) == crc32(&buf[..12]);
+
+        if valid {
+            Some(Self { seq: u32_at(4) })
+        } else {
+            None
+        }
+    }
+}
+
+/// Position of a read of the log a sector at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectorCursor {
+    /// Index of the sector to read next
+    next: u32,
+    /// Sectors still to read
+    left: u32,
+}
+
+pub struct DataLog<F: Flash> {
+    flash: F,
+    /// Address of the first sector of the log region
+    start: u32,
+    /// Number of sectors in the log region
+    sectors: u32,
+    /// Index of the sector currently being written
+    head: u32,
+    /// Sequence number of the head sector
+    seq: u32,
+    /// Offset of the next free word within the head sector
+    offset: u32,
+    encoder: Encoder,
+}
+
+impl<F: Flash> DataLog<F> {
+    /// Open the log stored in `sectors` sectors starting at `start`,
+    /// formatting the region if it doesn't contain a log yet.
+    pub fn open(mut flash: F, start: u32, sectors: u32) -> Result<Self, F::Error> {
+        assert!(F::SECTOR_SIZE as usize <= MAX_SECTOR_SIZE);
+
+        let mut newest: Option<(u32, SectorHeader)> = None;
+        for idx in 0..sectors {
+            let hdr = read_header(&mut flash, start + (idx * F::SECTOR_SIZE))?;
+            if let Some(hdr) = hdr {
+                if newest.map(|(_, n)| hdr.seq > n.seq).unwrap_or(true) {
+                    newest = Some((idx, hdr));
+                }
+            }
+        }
+
+        let mut log = Self {
+            flash,
+            start,
+            sectors,
+            head: 0,
+            seq: 0,
+            offset: HEADER_SIZE as u32,
+            encoder: Encoder::new(),
+        };
+
+        match newest {
+            Some((idx, hdr)) => {
+                log.head = idx;
+                log.seq = hdr.seq;
+                log.offset = log.find_free_offset()?;
+            }
+            None => log.start_sector(0, 0)?,
+        }
+
+        Ok(log)
+    }
+
+    pub fn free(self) -> F {
+        self.flash
+    }
+
+    fn sector_addr(&self, idx: u32) -> u32 {
+        self.start + (idx * F::SECTOR_SIZE)
+    }
+
+    /// Find the word after the last programmed word in the head sector.
+    /// Whatever is left of a frame torn by a reset is skipped when reading.
+    fn find_free_offset(&mut self) -> Result<u32, F::Error> {
+        let base = self.sector_addr(self.head);
+        let mut word = [0u8; 4];
+
+        let mut offset = F::SECTOR_SIZE;
+        while offset > HEADER_SIZE as u32 {
+            self.flash.read(base + offset - 4, &mut word)?;
+            if word != [PADDING; 4] {
+                break;
+            }
+            offset -= 4;
+        }
+        Ok(offset)
+    }
+
+    fn start_sector(&mut self, idx: u32, seq: u32) -> Result<(), F::Error> {
+        let addr = self.sector_addr(idx);
+        self.flash.erase(addr)?;
+        self.flash.write(addr, &SectorHeader { seq }.to_bytes())?;
+        self.head = idx;
+        self.seq = seq;
+        self.offset = HEADER_SIZE as u32;
+        self.encoder.reset();
+        Ok(())
+    }
+
+    pub fn append(&mut self, record: &Record) -> Result<(), F::Error> {
+        if self.offset + MAX_SLOT_LEN as u32 > F::SECTOR_SIZE {
+            let next = (self.head + 1) % self.sectors;
+            self.start_sector(next, self.seq.wrapping_add(1))?;
+        }
+
+        let mut frame = [0u8; MAX_FRAME_LEN];
+        let len = self.encoder.encode(record, &mut frame);
+
+        let mut slot = [FILL; MAX_SLOT_LEN];
+        slot[..len].copy_from_slice(&frame[..len]);
+        let mut len = (len + 3) & !3;
+        if slot[len - 4..len] == [PADDING; 4] {
+            len += 4;
+        }
+
+        let addr = self.sector_addr(self.head) + self.offset;
+        self.flash.write(addr, &slot[..len])?;
+        self.offset += len as u32;
+        Ok(())
+    }
+
+    /// Read back every record in the log, oldest first
+    pub fn for_each<E>(&mut self, f: impl FnMut(Record) -> Result<(), E>) -> Result<(), E>
+    where
+        E: From<F::Error>,
+    {
+        let first = (self.head + 1) % self.sectors;
+        read_records(&mut self.flash, self.start, self.sectors, first, f)
+    }
+
+    /// Start a read of the log a sector at a time, oldest first, see
+    /// [`for_each_in_sector`](Self::for_each_in_sector)
+    pub fn cursor(&self) -> SectorCursor {
+        SectorCursor {
+            next: (self.head + 1) % self.sectors,
+            left: self.sectors,
+        }
+    }
+
+    /// Read back the records in the next sector of a long read, and move
+    /// the cursor on. Returns `false` once every sector has been read.
+    ///
+    /// The cursor holds on to sectors by position, so records appended in
+    /// between don't cause a sector to be skipped or read twice. Records
+    /// appended to a sector after it was read are left out, as are those
+    /// in a sector erased before it was read.
+    pub fn for_each_in_sector<E>(
+        &mut self,
+        cursor: &mut SectorCursor,
+        f: impl FnMut(Record) -> Result<(), E>,
+    ) -> Result<bool, E>
+    where
+        E: From<F::Error>,
+    {
+        if cursor.left == 0 {
+            return Ok(false);
+        }
+        let base = self.sector_addr(cursor.next);
+        cursor.next = (cursor.next + 1) % self.sectors;
+        cursor.left -= 1;
+        read_sector(&mut self.flash, base, f)?;
+        Ok(true)
+    }
+}
+
+fn read_header<F: Flash>(flash: &mut F, addr: u32) -> Result<Option<SectorHeader>, F::Error> {
+    let mut buf = [0u8; HEADER_SIZE];
+    flash.read(addr, &mut buf)?;
+    Ok(SectorHeader::from_bytes(&buf))
+}
+
+/// Walk the ring of sectors starting at `first`, calling `f` on each valid
+/// record
+fn read_records<F, E>(
+    flash: &mut F,
+    start: u32,
+    sectors: u32,
+    first: u32,
+    mut f: impl FnMut(Record) -> Result<(), E>,
+) -> Result<(), E>
+where
+    F: Flash,
+    E: From<F::Error>,
+{
+    for i in 0..sectors {
+        let base = start + (((first + i) % sectors) * F::SECTOR_SIZE);
+        read_sector(flash, base, &mut f)?;
+    }
+    Ok(())
+}
+
+/// Call `f` on each valid record in the sector at `base`. A sector that
+/// has never been written is skipped, as are frames that fail their CRC
+/// (e.g. a write interrupted by a reset).
+fn read_sector<F, E>(
+    flash: &mut F,
+    base: u32,
+    mut f: impl FnMut(Record) -> Result<(), E>,
+) -> Result<(), E>
+where
+    F: Flash,
+    E: From<F::Error>,
+{
+    if read_header(flash, base)?.is_none() {
+        return Ok(());
+    }
+
+    let mut buf = [0u8; MAX_SECTOR_SIZE];
+    let buf = &mut buf[..F::SECTOR_SIZE as usize];
+    flash.read(base, buf)?;
+    for record in Frames::new(&buf[HEADER_SIZE..]) {
+        f(record)?;
+    }
+    Ok(())
+}
+
+/// Decode a raw image of the whole log region (e.g. dumped from the flash
+/// chip), oldest record first.
+#[cfg(feature = "std")]
+pub fn parse_image(image: &[u8]) -> Vec<Record> {
+    use crate::flash::MemFlash;
+
+    let sector_size = MemFlash::SECTOR_SIZE as usize;
+    let sectors = (image.len() / sector_size) as u32;
+
+    // Sequence numbers increase around the ring, so the oldest sector is
+    // the one after the newest
+    let mut flash = MemFlash::new(image.to_vec());
+    let mut newest: Option<(u32, u32)> = None;
+    for idx in 0..sectors {
+        if let Ok(Some(hdr)) = read_header(&mut flash, idx * MemFlash::SECTOR_SIZE) {
+            if newest.map(|(_, seq)| hdr.seq > seq).unwrap_or(true) {
+                newest = Some((idx, hdr.seq));
+            }
+        }
+    }
+
+    let mut records = Vec::new();
+    if let Some((head, _)) = newest {
+        let first = (head + 1) % sectors;
+        let res: Result<(), core::convert::Infallible> =
+            read_records(&mut flash, 0, sectors, first, |r| {
+                records.push(r);
+                Ok(())
+            });
+        res.ok();
+    }
+    records
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{flash::MemFlash, history::Sample};
+
+    fn sample(timestamp: u32) -> Record {
+        Record::Sample(Sample {
+            timestamp,
+            co2_ppm: 400 + (timestamp % 1000) as u16,
+            temp_cdeg: -250 + (timestamp % 5000) as i16,
+            // Whole 0.5% steps, so they survive encoding unchanged
+            rh_cpct: (timestamp % 200) as u16 * 50,
+        })
+    }
+
+    #[test]
+    fn blank_image_is_empty() {
+        let image = vec![0xFFu8; 4 * 4096];
+        assert!(parse_image(&image).is_empty());
+    }
+
+    #[test]
+    fn reopen_continues_where_it_left_off() {
+        let flash = MemFlash::new(vec![0xFFu8; 4 * 4096]);
+        let mut log = DataLog::open(flash, 0, 4).unwrap();
+        for t in 0..10 {
+            log.append(&sample(t * 60)).unwrap();
+        }
+
+        let mut log = DataLog::open(log.free(), 0, 4).unwrap();
+        for t in 10..20 {
+            log.append(&sample(t * 60)).unwrap();
+        }
+
+        let parsed = parse_image(&log.free().into_inner());
+        let expected: Vec<_> = (0..20).map(|t| sample(t * 60)).collect();
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn wraps_around_and_drops_oldest() {
+        let sectors = 3;
+        let total = 2000;
+
+        let flash = MemFlash::new(vec![0xFFu8; sectors as usize * 4096]);
+        let mut log = DataLog::open(flash, 0, sectors).unwrap();
+        for t in 0..total {
+            log.append(&sample(t * 60)).unwrap();
+        }
+
+        // Delta samples take two words, so a sector holds just under 510
+        let parsed = parse_image(&log.free().into_inner());
+        let kept = parsed.len() as u32;
+        assert!(kept > 2 * 500 && kept < 3 * 510);
+
+        let expected: Vec<_> = ((total - kept)..total).map(|t| sample(t * 60)).collect();
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn read_a_sector_at_a_time() {
+        let flash = MemFlash::new(vec![0xFFu8; 3 * 4096]);
+        let mut log = DataLog::open(flash, 0, 3).unwrap();
+        for t in 0..1200 {
+            log.append(&sample(t * 60)).unwrap();
+        }
+
+        let mut all = Vec::new();
+        let res: Result<(), core::convert::Infallible> = log.for_each(|r| {
+            all.push(r);
+            Ok(())
+        });
+        res.unwrap();
+
+        let mut by_sector = Vec::new();
+        let mut cursor = log.cursor();
+        let mut read = 0;
+        loop {
+            let more = log.for_each_in_sector(&mut cursor, |r| {
+                by_sector.push(r);
+                Ok::<(), core::convert::Infallible>(())
+            });
+            if !more.unwrap() {
+                break;
+            }
+            read += 1;
+        }
+        assert_eq!(read, 3);
+        assert_eq!(by_sector, all);
+        assert_eq!(all.last(), Some(&sample(1199 * 60)));
+    }
+
+    #[test]
+    fn sector_at_a_time_while_appending() {
+        let flash = MemFlash::new(vec![0xFFu8; 3 * 4096]);
+        let mut log = DataLog::open(flash, 0, 3).unwrap();
+        for t in 0..1200 {
+            log.append(&sample(t * 60)).unwrap();
+        }
+        let mut t = 1200;
+
+        let mut by_sector = Vec::new();
+        let mut read_next = |log: &mut DataLog<MemFlash>, cursor: &mut SectorCursor| {
+            log.for_each_in_sector(cursor, |r| {
+                by_sector.push(r);
+                Ok::<(), core::convert::Infallible>(())
+            })
+            .unwrap()
+        };
+
+        // Move the head on a sector after the oldest has been read
+        let mut cursor = log.cursor();
+        assert!(read_next(&mut log, &mut cursor));
+        let head = log.head;
+        while log.head == head {
+            log.append(&sample(t * 60)).unwrap();
+            t += 1;
+        }
+        while read_next(&mut log, &mut cursor) {}
+
+        // Every sector once, in order, ending with the old head sector
+        // filled up before the head moved on
+        let last = t - 2;
+        let first = match by_sector[0] {
+            Record::Sample(s) => s.timestamp / 60,
+            _ => unreachable!(),
+        };
+        let expected: Vec<_> = (first..=last).map(|t| sample(t * 60)).collect();
+        assert_eq!(by_sector, expected);
+    }
+
+    #[test]
+    fn torn_write_is_skipped() {
+        let flash = MemFlash::new(vec![0xFFu8; 2 * 4096]);
+        let mut log = DataLog::open(flash, 0, 2).unwrap();
+        for t in 0..2 {
+            log.append(&sample(t * 60)).unwrap();
+        }
+
+        // Simulate a reset halfway through programming the second frame,
+        // which is the second word of the delta frame. The first is an
+        // absolute frame, three words long.
+        let mut image = log.free().into_inner();
+        let second = HEADER_SIZE + 12;
+        for b in &mut image[second + 4..second + 8] {
+            *b = 0xFF;
+        }
+
+        let mut log = DataLog::open(MemFlash::new(image), 0, 2).unwrap();
+        log.append(&sample(120)).unwrap();
+
+        let parsed = parse_image(&log.free().into_inner());
+        assert_eq!(parsed, vec![sample(0), sample(120)]);
+    }
+
+    #[test]
+    fn frame_ending_in_erased_bytes() {
+        // A time sync capped at u16::MAX, at a time that makes the CRC
+        // 0xFF too. Padded with 0xFF, its last word would read as erased.
+        let event = |timestamp| Record::Event {
+            timestamp,
+            kind: EventKind::TimeSync,
+            arg: u16::MAX,
+        };
+        let timestamp = (1_600_000_000..)
+            .find(|t| {
+                let mut frame = [0u8; MAX_FRAME_LEN];
+                let len = Encoder::new().encode(&event(*t), &mut frame);
+                frame[len - 1] == PADDING
+            })
+            .unwrap();
+
+        let flash = MemFlash::new(vec![0xFFu8; 2 * 4096]);
+        let mut log = DataLog::open(flash, 0, 2).unwrap();
+        log.append(&event(timestamp)).unwrap();
+
+        let mut log = DataLog::open(log.free(), 0, 2).unwrap();
+        log.append(&sample(timestamp + 60)).unwrap();
+
+        let parsed = parse_image(&log.free().into_inner());
+        assert_eq!(parsed, vec![event(timestamp), sample(timestamp + 60)]);
+    }
+}
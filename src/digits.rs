@@ -0,0 +1,171 @@
+//! Numbers laid out for the four digit seven segment display
+//!
+//! Readings are fixed point, e.g. hundredths of a degree, and get as many
+//! decimal places as fit next to their unit, rounded half away from zero.
+//! Negative values get a leading minus sign. A value with too many digits
+//! to fit even without decimals shows "Hi" or "Lo" instead, rather than
+//! something that looks like a real reading.
+
+/// Number of digits on the display
+pub const WIDTH: usize = 4;
+
+/// What to send to the display
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Digits {
+    /// One ASCII character per digit
+    pub text: [u8; WIDTH],
+    /// The digit followed by the decimal point, if there is one
+    pub dot: Option<usize>,
+}
+
+/// Lay out `value`, in units of 10^-`scale`, right aligned in front of
+/// `unit`. Shows at most `max_decimals` decimal places, fewer if the
+/// integer part needs the room.
+pub fn render(value: i32, scale: u8, max_decimals: u8, unit: &[u8]) -> Digits {
+    let unit = &unit[..unit.len().min(WIDTH - 1)];
+    let width = WIDTH - unit.len();
+    let mut text = [b' '; WIDTH];
+    text[width..].copy_from_slice(unit);
+
+    for decimals in (0..=max_decimals.min(scale)).rev() {
+        let rounded = round_div(value as i64, 10i64.pow((scale - decimals) as u32));
+        let magnitude = rounded.unsigned_abs();
+        let negative = rounded < 0;
+
+        // At least one digit before the decimal point, e.g. "0.5"
+        let mut digits = 1;
+        while 10u64.pow(digits) <= magnitude {
+            digits += 1;
+        }
+        let digits = (digits as usize).max(decimals as usize + 1);
+        if digits + negative as usize > width {
+            continue;
+        }
+
+        let mut rest = magnitude;
+        for i in (width - digits..width).rev() {
+            text[i] = b'0' + (rest % 10) as u8;
+            rest /= 10;
+        }
+        if negative {
+            text[width - digits - 1] = b'-';
+        }
+        let dot = if decimals > 0 {
+            Some(width - decimals as usize - 1)
+        } else {
+            None
+        };
+        return Digits { text, dot };
+    }
+
+    let overflow: &[u8] = if value < 0 { b"Lo" } else { b"Hi" };
+    text[width - overflow.len()..width].copy_from_slice(overflow);
+    Digits { text, dot: None }
+}
+
+/// Hundredths, rounded half away from zero. NaN reads as too large to show.
+pub fn centi(value: f32) -> i32 {
+    if value.is_nan() {
+        return i32::MAX;
+    }
+    let half = if value < 0.0 { -0.5 } else { 0.5 };
+    // Saturates at the limits of `i32`
+    (value * 100.0 + half) as i32
+}
+
+/// `n / d`, rounded half away from zero
+fn round_div(n: i64, d: i64) -> i64 {
+    if n < 0 {
+        (n - d / 2) / d
+    } else {
+        (n + d / 2) / d
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    fn shown(digits: Digits) -> String {
+        let mut s = String::new();
+        for (i, c) in digits.text.iter().enumerate() {
+            s.push(*c as char);
+            if digits.dot == Some(i) {
+                s.push('.');
+            }
+        }
+        s
+    }
+
+    fn temp(celsius: f32) -> String {
+        shown(render(centi(celsius), 2, 1, b"C"))
+    }
+
+    fn rh(percent: f32) -> String {
+        shown(render(centi(percent), 2, 1, b""))
+    }
+
+    fn co2(ppm: f32) -> String {
+        shown(render(centi(ppm), 2, 0, b""))
+    }
+
+    #[test]
+    fn temperatures() {
+        assert_eq!(temp(23.45), "23.5C");
+        assert_eq!(temp(23.44), "23.4C");
+        assert_eq!(temp(9.96), "10.0C");
+        assert_eq!(temp(5.0), " 5.0C");
+        assert_eq!(temp(0.0), " 0.0C");
+        assert_eq!(temp(-0.04), " 0.0C");
+        assert_eq!(temp(-0.05), "-0.1C");
+        assert_eq!(temp(-5.55), "-5.6C");
+        assert_eq!(temp(-9.94), "-9.9C");
+        // No room for the decimals
+        assert_eq!(temp(-9.96), "-10C");
+        assert_eq!(temp(-12.5), "-13C");
+        assert_eq!(temp(99.94), "99.9C");
+        assert_eq!(temp(99.95), "100C");
+        assert_eq!(temp(999.4), "999C");
+        assert_eq!(temp(999.5), " HiC");
+        assert_eq!(temp(-99.4), "-99C");
+        assert_eq!(temp(-99.5), " LoC");
+        assert_eq!(temp(f32::NAN), " HiC");
+    }
+
+    #[test]
+    fn scd30_range() {
+        // -40..=70 degrees C, every hundredth
+        for centi_deg in -4000..=7000 {
+            let digits = render(centi_deg, 2, 1, b"C");
+            let text = core::str::from_utf8(&digits.text[..3]).unwrap();
+            let parsed: f32 = text.trim().parse().unwrap();
+            let decimals = digits.dot.map_or(0, |dot| 2 - dot) as i32;
+            let value = parsed / 10f32.powi(decimals);
+            assert!((value - centi_deg as f32 / 100.0).abs() <= 0.5 / 10f32.powi(decimals) + 1e-3);
+        }
+
+        // 0..=100 %RH
+        assert_eq!(rh(0.0), "  0.0");
+        assert_eq!(rh(45.26), " 45.3");
+        assert_eq!(rh(99.99), "100.0");
+        assert_eq!(rh(100.0), "100.0");
+
+        // 0..=40,000 ppm, with only four digits
+        assert_eq!(co2(0.0), "   0");
+        assert_eq!(co2(412.5), " 413");
+        assert_eq!(co2(9999.4), "9999");
+        assert_eq!(co2(9999.5), "  Hi");
+        assert_eq!(co2(40_000.0), "  Hi");
+    }
+
+    #[test]
+    fn precision() {
+        // Up to the scale of the value
+        assert_eq!(shown(render(5, 1, 3, b"")), "  0.5");
+        assert_eq!(shown(render(-5, 3, 3, b"")), "-0.01");
+        assert_eq!(shown(render(1234, 0, 2, b"")), "1234");
+        assert_eq!(shown(render(i32::MIN, 2, 2, b"")), "  Lo");
+        assert_eq!(shown(render(i32::MAX, 0, 0, b"rh")), "Hirh");
+    }
+}
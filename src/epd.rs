@@ -0,0 +1,176 @@
+//! Frame buffer and text for the 2.13" tricolour e-paper display
+//!
+//! The IL0373 controller takes each colour as a plane of one bit per
+//! pixel, rows of eight pixels to a byte with the leftmost pixel in the
+//! most significant bit. A clear bit is ink, so a blank plane is all ones.
+//! Text uses a 5x7 font, with a column of space after each character.
+
+/// Pixels across, in portrait orientation
+pub const WIDTH: usize = 104;
+
+/// Pixels down
+pub const HEIGHT: usize = 212;
+
+/// Bytes in one colour plane
+pub const PLANE_LEN: usize = WIDTH * HEIGHT / 8;
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+
+/// Horizontal space taken by each character, before scaling
+pub const ADVANCE: usize = GLYPH_WIDTH + 1;
+
+/// One colour plane
+pub struct Plane {
+    bytes: [u8; PLANE_LEN],
+}
+
+impl Default for Plane {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Plane {
+    /// A plane with no ink on it
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0xFF; PLANE_LEN],
+        }
+    }
+
+    /// Ink a pixel. Pixels off the display are ignored.
+    pub fn set(&mut self, x: usize, y: usize) {
+        if x < WIDTH && y < HEIGHT {
+            self.bytes[(y * WIDTH + x) / 8] &= !(0x80 >> (x % 8));
+        }
+    }
+
+    pub fn is_set(&self, x: usize, y: usize) -> bool {
+        x < WIDTH && y < HEIGHT && self.bytes[(y * WIDTH + x) / 8] & (0x80 >> (x % 8)) == 0
+    }
+
+    /// Draw `text` with its top left corner at `x`, `y`, each font pixel
+    /// `scale` pixels square. Characters without a glyph are left blank.
+    pub fn draw_text(&mut self, x: usize, y: usize, scale: usize, text: &[u8]) {
+        for (i, c) in text.iter().enumerate() {
+            let left = x + i * ADVANCE * scale;
+            for (row, bits) in glyph(*c).iter().enumerate() {
+                for col in 0..GLYPH_WIDTH {
+                    if bits & (0x10 >> col) == 0 {
+                        continue;
+                    }
+                    for dy in 0..scale {
+                        for dx in 0..scale {
+                            self.set(left + col * scale + dx, y + row * scale + dy);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Rows of five pixels, top first, leftmost pixel in bit 4
+fn glyph(c: u8) -> [u8; GLYPH_HEIGHT] {
+    match c {
+        b'0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        b'1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        b'2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        b'3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        b'4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        b'5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        b'6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        b'7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        b'8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        b'9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        b':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        b'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        b'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        b'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    /// The plane as text, one line per row, `#` for ink
+    fn rows(plane: &Plane, x: usize, y: usize, w: usize, h: usize) -> Vec<String> {
+        (y..y + h)
+            .map(|y| {
+                (x..x + w)
+                    .map(|x| if plane.is_set(x, y) { '#' } else { '.' })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pixels() {
+        let mut plane = Plane::new();
+        assert!(plane.as_bytes().iter().all(|b| *b == 0xFF));
+
+        plane.set(0, 0);
+        plane.set(9, 1);
+        plane.set(WIDTH - 1, HEIGHT - 1);
+        plane.set(WIDTH, 0);
+        plane.set(0, HEIGHT);
+
+        let bytes = plane.as_bytes();
+        assert_eq!(bytes[0], 0x7F);
+        // The second row starts at byte 13
+        assert_eq!(bytes[13 + 1], 0xBF);
+        assert_eq!(bytes[PLANE_LEN - 1], 0xFE);
+        assert_eq!(bytes.iter().filter(|b| **b != 0xFF).count(), 3);
+        assert!(!plane.is_set(WIDTH, 0));
+    }
+
+    #[test]
+    fn text() {
+        let mut plane = Plane::new();
+        plane.draw_text(1, 2, 1, b"1:P?");
+        assert_eq!(
+            rows(&plane, 1, 2, 3 * ADVANCE, 7),
+            [
+                "..#.........####..",
+                ".##....##...#...#.",
+                "..#....##...#...#.",
+                "..#.........####..",
+                "..#....##...#.....",
+                "..#....##...#.....",
+                ".###........#.....",
+            ]
+        );
+        // Nothing outside the glyphs, and nothing for '?'
+        let ink = (0..HEIGHT)
+            .flat_map(|y| (0..WIDTH).map(move |x| (x, y)))
+            .filter(|&(x, y)| plane.is_set(x, y))
+            .count();
+        assert_eq!(ink, 10 + 8 + 15);
+    }
+
+    #[test]
+    fn scaled() {
+        let mut plane = Plane::new();
+        plane.draw_text(0, 0, 2, b"1");
+        assert_eq!(
+            rows(&plane, 0, 0, 2 * ADVANCE, 4),
+            [
+                "....##......",
+                "....##......",
+                "..####......",
+                "..####......",
+            ]
+        );
+
+        // Running off the edge is cut short
+        plane.draw_text(WIDTH - 2, HEIGHT - 2, 3, b"M");
+        assert!(plane.is_set(WIDTH - 1, HEIGHT - 1));
+    }
+}
@@ -0,0 +1,68 @@
+//! Non-volatile storage
+//!
+//! [`Flash`] is the minimal interface the persistence code needs from a
+//! NOR flash device.
+
+pub trait Flash {
+    type Error;
+
+    /// Size of the smallest erasable unit, in bytes
+    const SECTOR_SIZE: u32;
+
+    /// Read `buf.len()` bytes starting at `addr`
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Program `data` starting at `addr`. Bits can only be cleared, so the
+    /// target region must have been erased first.
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Erase the sector containing `addr`, setting all bytes to 0xFF
+    fn erase(&mut self, addr: u32) -> Result<(), Self::Error>;
+}
+
+/// A RAM backed flash, with the same "program can only clear bits"
+/// behavior as NOR flash. Used for testing, and for decoding images dumped
+/// from real devices on the host.
+#[cfg(feature = "std")]
+pub struct MemFlash {
+    data: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl MemFlash {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(feature = "std")]
+impl Flash for MemFlash {
+    type Error = core::convert::Infallible;
+    const SECTOR_SIZE: u32 = 4096;
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let addr = addr as usize;
+        buf.copy_from_slice(&self.data[addr..][..buf.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Self::Error> {
+        let addr = addr as usize;
+        for (dst, src) in self.data[addr..][..data.len()].iter_mut().zip(data) {
+            *dst &= *src;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, addr: u32) -> Result<(), Self::Error> {
+        let start = (addr & !(Self::SECTOR_SIZE - 1)) as usize;
+        for b in &mut self.data[start..][..Self::SECTOR_SIZE as usize] {
+            *b = 0xFF;
+        }
+        Ok(())
+    }
+}
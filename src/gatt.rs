@@ -0,0 +1,583 @@
+//! GATT database for the clock
+//!
+//! The attribute table is fixed. The Environmental Sensing Service has
+//! three characteristics, each with a value and a client configuration
+//! descriptor for notifications. The Current Time Service is written to by
+//! a gateway to set the clock, see `cts`. The Device Information Service
+//! tells clocks apart, see `identity`.
+//!
+//! | handle   | attribute                               |
+//! |----------|-----------------------------------------|
+//! | 1        | Environmental Sensing service           |
+//! | 2..=4    | Temperature, 0.01 degC, sint16          |
+//! | 5..=7    | Humidity, 0.01 %, uint16                |
+//! | 8..=10   | CO2 concentration, ppm, SFLOAT          |
+//! | 11       | Current Time service                    |
+//! | 12..=13  | Current Time                            |
+//! | 14..=15  | Local Time Information                  |
+//! | 16       | Device Information service              |
+//! | 17..=18  | Serial Number String, the device ID     |
+//! | 19..=20  | Firmware Revision String                |
+//! | 21..=22  | Hardware Revision String                |
+//!
+//! This module only knows about the table and how values are encoded, the
+//! BLE stack lives in `ble`.
+
+use core::fmt::{self, Write as _};
+
+use crate::{
+    cts::{self, CurrentTime, LocalTimeInfo, TimeReference},
+    history::Sample,
+    identity::{Firmware, Identity},
+};
+
+/// 16-bit UUIDs assigned by the Bluetooth SIG
+pub mod uuid {
+    pub const PRIMARY_SERVICE: u16 = 0x2800;
+    pub const CHARACTERISTIC: u16 = 0x2803;
+    pub const CLIENT_CONFIG: u16 = 0x2902;
+
+    pub const ENVIRONMENTAL_SENSING: u16 = 0x181A;
+    pub const TEMPERATURE: u16 = 0x2A6E;
+    pub const HUMIDITY: u16 = 0x2A6F;
+    pub const CO2_CONCENTRATION: u16 = 0x2B8C;
+
+    pub const CURRENT_TIME_SERVICE: u16 = 0x1805;
+    pub const CURRENT_TIME: u16 = 0x2A2B;
+    pub const LOCAL_TIME_INFO: u16 = 0x2A0F;
+
+    pub const DEVICE_INFORMATION: u16 = 0x180A;
+    pub const SERIAL_NUMBER: u16 = 0x2A25;
+    pub const FIRMWARE_REVISION: u16 = 0x2A26;
+    pub const HARDWARE_REVISION: u16 = 0x2A27;
+}
+
+pub const ESS_HANDLE: u16 = 1;
+pub const TEMPERATURE_HANDLE: u16 = 3;
+pub const HUMIDITY_HANDLE: u16 = 6;
+pub const CO2_HANDLE: u16 = 9;
+const ESS_END_HANDLE: u16 = 10;
+
+pub const CTS_HANDLE: u16 = 11;
+pub const CURRENT_TIME_HANDLE: u16 = 13;
+pub const LOCAL_TIME_INFO_HANDLE: u16 = 15;
+const CTS_END_HANDLE: u16 = 15;
+
+pub const DIS_HANDLE: u16 = 16;
+pub const SERIAL_NUMBER_HANDLE: u16 = 18;
+/// The last handle in the table
+pub const LAST_HANDLE: u16 = 22;
+
+/// Characteristic properties, see Core spec Vol 3, Part G, 3.3.1.1
+const PROP_READ: u8 = 0x02;
+const PROP_WRITE: u8 = 0x08;
+const PROP_NOTIFY: u8 = 0x10;
+
+/// Characteristics in handle order
+const CHARACTERISTICS: [u16; 3] = [uuid::TEMPERATURE, uuid::HUMIDITY, uuid::CO2_CONCENTRATION];
+
+/// Device information strings in handle order
+const DEVICE_INFO: [u16; 3] = [
+    uuid::SERIAL_NUMBER,
+    uuid::FIRMWARE_REVISION,
+    uuid::HARDWARE_REVISION,
+];
+
+/// Longest attribute value in the table. Strings are cut short to this, so
+/// they can be read without a long read.
+pub const MAX_VALUE_LEN: usize = 20;
+
+/// An attribute value, stored inline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Value {
+    bytes: [u8; MAX_VALUE_LEN],
+    len: u8,
+}
+
+impl Value {
+    const EMPTY: Self = Self {
+        bytes: [0; MAX_VALUE_LEN],
+        len: 0,
+    };
+
+    fn new(data: &[u8]) -> Self {
+        let mut bytes = [0; MAX_VALUE_LEN];
+        bytes[..data.len()].copy_from_slice(data);
+        Self {
+            bytes,
+            len: data.len() as u8,
+        }
+    }
+}
+
+/// Text, cut short if it doesn't fit
+impl fmt::Write for Value {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = self.len as usize;
+        let n = s.len().min(MAX_VALUE_LEN - len);
+        self.bytes[len..][..n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n as u8;
+        Ok(())
+    }
+}
+
+impl AsRef<[u8]> for Value {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attribute {
+    pub handle: u16,
+    /// Attribute type
+    pub uuid: u16,
+    pub value: Value,
+}
+
+/// Errors returned to the client, with their ATT error codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum AttError {
+    InvalidHandle = 0x01,
+    WriteNotPermitted = 0x03,
+    InvalidLength = 0x0D,
+    OutOfRange = 0xFF,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Characteristic {
+    value: [u8; 2],
+    notify: bool,
+}
+
+/// The services' state: current values, which notifications the connected
+/// client has turned on, and the last time written
+#[derive(Debug, Clone)]
+pub struct Database {
+    chars: [Characteristic; 3],
+    current_time: Option<CurrentTime>,
+    local_time_info: Option<LocalTimeInfo>,
+    /// The current time was written and hasn't been taken yet
+    time_written: bool,
+    /// Device information strings
+    device_info: [Value; 3],
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Database {
+    pub const fn new() -> Self {
+        const EMPTY: Characteristic = Characteristic {
+            value: [0; 2],
+            notify: false,
+        };
+        Self {
+            chars: [EMPTY; 3],
+            current_time: None,
+            local_time_info: None,
+            time_written: false,
+            device_info: [Value::EMPTY; 3],
+        }
+    }
+
+    /// Fill in the Device Information Service
+    pub fn set_identity(&mut self, identity: &Identity) {
+        let mut values = [Value::EMPTY; 3];
+        values[0] = Value::new(&identity.device_id.to_hex());
+        write!(values[1], "{}", Firmware).ok();
+        write!(values[2], "{}", identity.variant).ok();
+        self.device_info = values;
+    }
+
+    /// Take the values from a new reading
+    pub fn update(&mut self, sample: &Sample) {
+        self.chars[0].value = sample.temp_cdeg.to_le_bytes();
+        self.chars[1].value = sample.rh_cpct.to_le_bytes();
+        self.chars[2].value = sfloat(sample.co2_ppm as u32).to_le_bytes();
+    }
+
+    pub fn attribute(&self, handle: u16) -> Option<Attribute> {
+        match handle {
+            ESS_HANDLE => return Some(service(handle, uuid::ENVIRONMENTAL_SENSING)),
+            CTS_HANDLE..=CTS_END_HANDLE => return self.time_attribute(handle),
+            DIS_HANDLE..=LAST_HANDLE => return self.device_info_attribute(handle),
+            _ => {}
+        }
+
+        let offset = handle.checked_sub(2)? as usize;
+        let index = offset / 3;
+        let uuid = *CHARACTERISTICS.get(index)?;
+        let ch = &self.chars[index];
+        let value_handle = value_handle(index);
+
+        let (uuid, value) = match offset % 3 {
+            // Declaration, just before the value
+            0 => {
+                let decl = declaration(PROP_READ | PROP_NOTIFY, value_handle, uuid);
+                (uuid::CHARACTERISTIC, decl)
+            }
+            1 => (uuid, Value::new(&ch.value)),
+            _ => {
+                let config = (ch.notify as u16).to_le_bytes();
+                (uuid::CLIENT_CONFIG, Value::new(&config))
+            }
+        };
+        Some(Attribute {
+            handle,
+            uuid,
+            value,
+        })
+    }
+
+    fn time_attribute(&self, handle: u16) -> Option<Attribute> {
+        let props = PROP_READ | PROP_WRITE;
+        let (uuid, value) = match handle {
+            CTS_HANDLE => return Some(service(handle, uuid::CURRENT_TIME_SERVICE)),
+            12 => (
+                uuid::CHARACTERISTIC,
+                declaration(props, CURRENT_TIME_HANDLE, uuid::CURRENT_TIME),
+            ),
+            CURRENT_TIME_HANDLE => {
+                let value = self.current_time.map(|ct| ct.encode()).unwrap_or_default();
+                (uuid::CURRENT_TIME, Value::new(&value))
+            }
+            14 => (
+                uuid::CHARACTERISTIC,
+                declaration(props, LOCAL_TIME_INFO_HANDLE, uuid::LOCAL_TIME_INFO),
+            ),
+            LOCAL_TIME_INFO_HANDLE => {
+                let value = self
+                    .local_time_info
+                    .map(|lti| lti.encode())
+                    .unwrap_or_default();
+                (uuid::LOCAL_TIME_INFO, Value::new(&value))
+            }
+            _ => return None,
+        };
+        Some(Attribute {
+            handle,
+            uuid,
+            value,
+        })
+    }
+
+    fn device_info_attribute(&self, handle: u16) -> Option<Attribute> {
+        if handle == DIS_HANDLE {
+            return Some(service(handle, uuid::DEVICE_INFORMATION));
+        }
+
+        let offset = (handle - DIS_HANDLE - 1) as usize;
+        let index = offset / 2;
+        let uuid = *DEVICE_INFO.get(index)?;
+        let value_handle = DIS_HANDLE + 2 + index as u16 * 2;
+        let (uuid, value) = match offset % 2 {
+            0 => (
+                uuid::CHARACTERISTIC,
+                declaration(PROP_READ, value_handle, uuid),
+            ),
+            _ => (uuid, self.device_info[index]),
+        };
+        Some(Attribute {
+            handle,
+            uuid,
+            value,
+        })
+    }
+
+    /// The whole table, in handle order
+    pub fn attributes(&self) -> impl Iterator<Item = Attribute> + '_ {
+        (ESS_HANDLE..=LAST_HANDLE).filter_map(move |handle| self.attribute(handle))
+    }
+
+    /// The last handle of the service that `handle` belongs to
+    pub fn group_end(handle: u16) -> Option<u16> {
+        match handle {
+            ESS_HANDLE..=ESS_END_HANDLE => Some(ESS_END_HANDLE),
+            CTS_HANDLE..=CTS_END_HANDLE => Some(CTS_END_HANDLE),
+            DIS_HANDLE..=LAST_HANDLE => Some(LAST_HANDLE),
+            _ => None,
+        }
+    }
+
+    /// A write from the client. Only the client configuration descriptors
+    /// and the time characteristics are writable.
+    pub fn write(&mut self, handle: u16, data: &[u8]) -> Result<(), AttError> {
+        let attr = self.attribute(handle).ok_or(AttError::InvalidHandle)?;
+        match attr.uuid {
+            uuid::CLIENT_CONFIG => {
+                if data.len() != 2 {
+                    return Err(AttError::InvalidLength);
+                }
+                // Indications aren't supported, so only the notify bit
+                // matters
+                let index = (handle - 2) as usize / 3;
+                self.chars[index].notify = data[0] & 0x01 != 0;
+            }
+            uuid::CURRENT_TIME => {
+                if data.len() != cts::CURRENT_TIME_LEN {
+                    return Err(AttError::InvalidLength);
+                }
+                let ct = CurrentTime::decode(data).ok_or(AttError::OutOfRange)?;
+                self.current_time = Some(ct);
+                self.time_written = true;
+            }
+            uuid::LOCAL_TIME_INFO => {
+                if data.len() != cts::LOCAL_TIME_INFO_LEN {
+                    return Err(AttError::InvalidLength);
+                }
+                let lti = LocalTimeInfo::decode(data).ok_or(AttError::OutOfRange)?;
+                self.local_time_info = Some(lti);
+            }
+            _ => return Err(AttError::WriteNotPermitted),
+        }
+        Ok(())
+    }
+
+    /// The time last written by the client, if it hasn't been taken
+    /// already. The local time information should be written first, if at
+    /// all.
+    pub fn take_time(&mut self) -> Option<TimeReference> {
+        if !core::mem::replace(&mut self.time_written, false) {
+            return None;
+        }
+        TimeReference::new(&self.current_time?, self.local_time_info.as_ref())
+    }
+
+    /// Forget the client's configuration, e.g. on disconnect
+    pub fn reset_notifications(&mut self) {
+        for ch in self.chars.iter_mut() {
+            ch.notify = false;
+        }
+    }
+
+    /// Value handles and values for the notifications the client asked for
+    pub fn notifications(&self) -> impl Iterator<Item = (u16, [u8; 2])> + '_ {
+        self.chars
+            .iter()
+            .enumerate()
+            .filter(|(_, ch)| ch.notify)
+            .map(|(index, ch)| (value_handle(index), ch.value))
+    }
+}
+
+fn value_handle(index: usize) -> u16 {
+    3 + (index as u16 * 3)
+}
+
+fn service(handle: u16, uuid: u16) -> Attribute {
+    Attribute {
+        handle,
+        uuid: uuid::PRIMARY_SERVICE,
+        value: Value::new(&uuid.to_le_bytes()),
+    }
+}
+
+fn declaration(props: u8, value_handle: u16, uuid: u16) -> Value {
+    let [h0, h1] = value_handle.to_le_bytes();
+    let [u0, u1] = uuid.to_le_bytes();
+    Value::new(&[props, h0, h1, u0, u1])
+}
+
+/// IEEE 11073 16-bit SFLOAT: a 4-bit signed exponent over a 12-bit
+/// mantissa. Precision is dropped, rounding, until the value fits.
+pub fn sfloat(mut mantissa: u32) -> u16 {
+    // 0x7FE and up are reserved for NaN, infinity and so on
+    const MAX_MANTISSA: u32 = 0x7FD;
+
+    let mut exponent = 0u16;
+    while mantissa > MAX_MANTISSA {
+        mantissa = (mantissa + 5) / 10;
+        exponent += 1;
+    }
+    (exponent << 12) | mantissa as u16
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    fn sample() -> Sample {
+        Sample {
+            timestamp: 1_613_947_000,
+            co2_ppm: 612,
+            temp_cdeg: -1234,
+            rh_cpct: 4550,
+        }
+    }
+
+    #[test]
+    fn table() {
+        let db = Database::new();
+        let attrs: Vec<_> = db.attributes().collect();
+        assert_eq!(attrs.len(), LAST_HANDLE as usize);
+        for (i, attr) in attrs.iter().enumerate() {
+            assert_eq!(attr.handle, i as u16 + 1);
+        }
+
+        assert_eq!(attrs[0].uuid, uuid::PRIMARY_SERVICE);
+        assert_eq!(attrs[0].value.as_ref(), &[0x1A, 0x18]);
+
+        assert_eq!(attrs[1].uuid, uuid::CHARACTERISTIC);
+        assert_eq!(attrs[1].value.as_ref(), &[0x12, 3, 0, 0x6E, 0x2A]);
+        assert_eq!(attrs[2].uuid, uuid::TEMPERATURE);
+        assert_eq!(attrs[3].uuid, uuid::CLIENT_CONFIG);
+
+        assert_eq!(attrs[4].value.as_ref(), &[0x12, 6, 0, 0x6F, 0x2A]);
+        assert_eq!(attrs[5].uuid, uuid::HUMIDITY);
+
+        assert_eq!(attrs[7].value.as_ref(), &[0x12, 9, 0, 0x8C, 0x2B]);
+        assert_eq!(attrs[8].uuid, uuid::CO2_CONCENTRATION);
+        assert_eq!(attrs[9].uuid, uuid::CLIENT_CONFIG);
+
+        assert_eq!(attrs[10].value.as_ref(), &[0x05, 0x18]);
+        assert_eq!(attrs[11].value.as_ref(), &[0x0A, 13, 0, 0x2B, 0x2A]);
+        assert_eq!(attrs[12].uuid, uuid::CURRENT_TIME);
+        assert_eq!(attrs[13].value.as_ref(), &[0x0A, 15, 0, 0x0F, 0x2A]);
+        assert_eq!(attrs[14].uuid, uuid::LOCAL_TIME_INFO);
+
+        assert_eq!(attrs[15].value.as_ref(), &[0x0A, 0x18]);
+        assert_eq!(attrs[16].value.as_ref(), &[0x02, 18, 0, 0x25, 0x2A]);
+        assert_eq!(attrs[17].uuid, uuid::SERIAL_NUMBER);
+        assert_eq!(attrs[18].value.as_ref(), &[0x02, 20, 0, 0x26, 0x2A]);
+        assert_eq!(attrs[19].uuid, uuid::FIRMWARE_REVISION);
+        assert_eq!(attrs[20].value.as_ref(), &[0x02, 22, 0, 0x27, 0x2A]);
+        assert_eq!(attrs[21].uuid, uuid::HARDWARE_REVISION);
+
+        assert_eq!(Database::group_end(ESS_HANDLE), Some(10));
+        assert_eq!(Database::group_end(CO2_HANDLE), Some(10));
+        assert_eq!(Database::group_end(CTS_HANDLE), Some(15));
+        assert_eq!(Database::group_end(DIS_HANDLE), Some(LAST_HANDLE));
+        assert_eq!(Database::group_end(LAST_HANDLE + 1), None);
+
+        assert_eq!(db.attribute(0), None);
+        assert_eq!(db.attribute(LAST_HANDLE + 1), None);
+    }
+
+    #[test]
+    fn values() {
+        let mut db = Database::new();
+        db.update(&sample());
+
+        let value = |handle| db.attribute(handle).unwrap().value;
+        assert_eq!(
+            value(TEMPERATURE_HANDLE).as_ref(),
+            &(-1234i16).to_le_bytes()
+        );
+        assert_eq!(value(HUMIDITY_HANDLE).as_ref(), &4550u16.to_le_bytes());
+        assert_eq!(value(CO2_HANDLE).as_ref(), &612u16.to_le_bytes());
+    }
+
+    #[test]
+    fn device_information() {
+        let mut db = Database::new();
+        let identity = Identity::new(0x0123_4567_89ab_cdef, *b"AAD0", &Default::default());
+        db.set_identity(&identity);
+
+        let value = |handle| db.attribute(handle).unwrap().value;
+        assert_eq!(value(SERIAL_NUMBER_HANDLE).as_ref(), b"0123456789abcdef");
+        assert_eq!(value(LAST_HANDLE).as_ref(), b"nRF52840-AAD0");
+
+        let firmware = Firmware.to_string();
+        let len = firmware.len().min(MAX_VALUE_LEN);
+        assert_eq!(
+            value(SERIAL_NUMBER_HANDLE + 2).as_ref(),
+            &firmware.as_bytes()[..len]
+        );
+
+        assert_eq!(
+            db.write(SERIAL_NUMBER_HANDLE, b"0"),
+            Err(AttError::WriteNotPermitted)
+        );
+    }
+
+    #[test]
+    fn sfloat_encoding() {
+        assert_eq!(sfloat(0), 0x0000);
+        assert_eq!(sfloat(400), 0x0190);
+        assert_eq!(sfloat(2045), 0x07FD);
+        // 204.6e1 rounds to 205e1
+        assert_eq!(sfloat(2046), 0x10CD);
+        assert_eq!(sfloat(40_000), 0x2000 | 400);
+        assert_eq!(sfloat(65_535), 0x2000 | 655);
+    }
+
+    #[test]
+    fn notifications() {
+        let mut db = Database::new();
+        db.update(&sample());
+        assert_eq!(db.notifications().count(), 0);
+
+        assert_eq!(db.write(CO2_HANDLE + 1, &[0x01, 0x00]), Ok(()));
+        assert_eq!(
+            db.attribute(CO2_HANDLE + 1).unwrap().value.as_ref(),
+            &[0x01, 0x00]
+        );
+        let notes: Vec<_> = db.notifications().collect();
+        assert_eq!(notes, vec![(CO2_HANDLE, 612u16.to_le_bytes())]);
+
+        db.write(TEMPERATURE_HANDLE + 1, &[0x01, 0x00]).unwrap();
+        assert_eq!(db.notifications().count(), 2);
+
+        db.reset_notifications();
+        assert_eq!(db.notifications().count(), 0);
+    }
+
+    #[test]
+    fn write_errors() {
+        let mut db = Database::new();
+        assert_eq!(db.write(0, &[1, 0]), Err(AttError::InvalidHandle));
+        assert_eq!(
+            db.write(LAST_HANDLE + 1, &[1, 0]),
+            Err(AttError::InvalidHandle)
+        );
+        assert_eq!(
+            db.write(ESS_HANDLE, &[1, 0]),
+            Err(AttError::WriteNotPermitted)
+        );
+        assert_eq!(
+            db.write(CO2_HANDLE, &[1, 0]),
+            Err(AttError::WriteNotPermitted)
+        );
+        assert_eq!(db.write(CO2_HANDLE + 1, &[1]), Err(AttError::InvalidLength));
+    }
+
+    #[test]
+    fn set_time() {
+        let mut db = Database::new();
+        assert_eq!(db.take_time(), None);
+
+        // 2021-02-21 23:36:40 CET
+        let current_time = [0xE5, 0x07, 2, 21, 23, 36, 40, 7, 0, 1];
+        db.write(LOCAL_TIME_INFO_HANDLE, &[4, 0]).unwrap();
+        db.write(CURRENT_TIME_HANDLE, &current_time).unwrap();
+        assert_eq!(
+            db.attribute(CURRENT_TIME_HANDLE).unwrap().value.as_ref(),
+            &current_time
+        );
+        let reference = TimeReference {
+            local: 1_613_950_600,
+            utc_offset_s: Some(3600),
+        };
+        assert_eq!(db.take_time(), Some(reference));
+        assert_eq!(db.take_time(), None);
+
+        assert_eq!(
+            db.write(CURRENT_TIME_HANDLE, &current_time[..7]),
+            Err(AttError::InvalidLength)
+        );
+        assert_eq!(
+            db.write(CURRENT_TIME_HANDLE, &[0; 10]),
+            Err(AttError::OutOfRange)
+        );
+        assert_eq!(
+            db.write(LOCAL_TIME_INFO_HANDLE, &[0x80, 0]),
+            Err(AttError::OutOfRange)
+        );
+        assert_eq!(db.take_time(), None);
+    }
+}
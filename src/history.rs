@@ -0,0 +1,425 @@
+//! Rolling history of sensor samples
+//!
+//! The last 24 hours are kept at one minute resolution. Samples are also
+//! averaged into one per hour, which are kept for a week. Statistics can be
+//! computed over any window, using the minute samples where they're still
+//! available, and the hourly averages before that.
+
+/// A single sensor reading, in fixed point
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Sample {
+    /// Seconds since the unix epoch
+    pub timestamp: u32,
+    /// CO2 concentration in ppm
+    pub co2_ppm: u16,
+    /// Temperature in 0.01 degrees C
+    pub temp_cdeg: i16,
+    /// Relative humidity in 0.01 %
+    pub rh_cpct: u16,
+}
+
+impl Sample {
+    pub fn from_f32(timestamp: u32, co2: f32, temp: f32, rh: f32) -> Self {
+        Self {
+            timestamp,
+            co2_ppm: round(co2).clamp(0, u16::MAX as i32) as u16,
+            temp_cdeg: round(temp * 100.0).clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+            rh_cpct: round(rh * 100.0).clamp(0, 10_000) as u16,
+        }
+    }
+
+    pub fn get(&self, field: Field) -> i32 {
+        match field {
+            Field::Co2 => self.co2_ppm as i32,
+            Field::Temperature => self.temp_cdeg as i32,
+            Field::Humidity => self.rh_cpct as i32,
+        }
+    }
+}
+
+fn round(x: f32) -> i32 {
+    if x < 0.0 {
+        (x - 0.5) as i32
+    } else {
+        (x + 0.5) as i32
+    }
+}
+
+/// Which part of a [`Sample`] to compute statistics over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum Field {
+    Co2,
+    Temperature,
+    Humidity,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Stats {
+    pub min: i32,
+    pub max: i32,
+    pub mean: i32,
+    pub count: u32,
+}
+
+/// A fixed capacity ring buffer, overwriting the oldest item when full
+pub struct Ring<T, const N: usize> {
+    buf: [T; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy + Default, const N: usize> Default for Ring<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy + Default, const N: usize> Ring<T, N> {
+    pub fn new() -> Self {
+        Self {
+            buf: [T::default(); N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: T) {
+        self.buf[self.head] = item;
+        self.head = (self.head + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Iterate from the oldest to the newest item
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> + '_ {
+        let start = (self.head + N - self.len) % N;
+        (0..self.len).map(move |i| &self.buf[(start + i) % N])
+    }
+
+    pub fn oldest(&self) -> Option<&T> {
+        self.iter().next()
+    }
+
+    pub fn newest(&self) -> Option<&T> {
+        self.iter().next_back()
+    }
+}
+
+/// Minutes in a day
+pub const MINUTE_SAMPLES: usize = 24 * 60;
+
+/// Hours in a week
+pub const HOURLY_SAMPLES: usize = 7 * 24;
+
+#[derive(Default)]
+struct HourAccumulator {
+    hour: u32,
+    co2: u32,
+    temp: i32,
+    rh: u32,
+    count: u32,
+}
+
+impl HourAccumulator {
+    fn take(&mut self) -> Option<Sample> {
+        if self.count == 0 {
+            return None;
+        }
+        let n = self.count;
+        let sample = Sample {
+            timestamp: self.hour * 3600,
+            co2_ppm: (self.co2 / n) as u16,
+            temp_cdeg: (self.temp / n as i32) as i16,
+            rh_cpct: (self.rh / n) as u16,
+        };
+        *self = Self::default();
+        Some(sample)
+    }
+}
+
+#[derive(Default)]
+pub struct History {
+    minutes: Ring<Sample, MINUTE_SAMPLES>,
+    hours: Ring<Sample, HOURLY_SAMPLES>,
+    acc: HourAccumulator,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a new sample. Samples are expected roughly once a minute, in
+    /// chronological order.
+    pub fn push(&mut self, sample: Sample) {
+        let hour = sample.timestamp / 3600;
+        if self.acc.count != 0 && hour != self.acc.hour {
+            if let Some(avg) = self.acc.take() {
+                self.hours.push(avg);
+            }
+        }
+
+        self.acc.hour = hour;
+        self.acc.co2 += sample.co2_ppm as u32;
+        self.acc.temp += sample.temp_cdeg as i32;
+        self.acc.rh += sample.rh_cpct as u32;
+        self.acc.count += 1;
+
+        self.minutes.push(sample);
+    }
+
+    pub fn latest(&self) -> Option<&Sample> {
+        self.minutes.newest()
+    }
+
+    pub fn minutes(&self) -> &Ring<Sample, MINUTE_SAMPLES> {
+        &self.minutes
+    }
+
+    pub fn hours(&self) -> &Ring<Sample, HOURLY_SAMPLES> {
+        &self.hours
+    }
+
+    /// All samples with `from <= timestamp < to`, oldest first. Hourly
+    /// averages are used for the hours that ended before the oldest minute
+    /// sample. The hour the minute ring starts in is left to the minutes
+    /// that remain of it, rather than counting its average as well.
+    pub fn samples(&self, from: u32, to: u32) -> impl Iterator<Item = Sample> + '_ {
+        let first_minute = self.minutes.oldest().map(|s| s.timestamp).unwrap_or(u32::MAX);
+        let in_window = move |s: &&Sample| s.timestamp >= from && s.timestamp < to;
+
+        let hourly = self
+            .hours
+            .iter()
+            .filter(move |s| s.timestamp.saturating_add(3600) <= first_minute)
+            .filter(in_window);
+        let minutely = self.minutes.iter().filter(in_window);
+
+        hourly.chain(minutely).copied()
+    }
+
+    pub fn stats(&self, field: Field, from: u32, to: u32) -> Option<Stats> {
+        let mut stats: Option<Stats> = None;
+        let mut sum = 0i64;
+
+        for value in self.samples(from, to).map(|s| s.get(field)) {
+            sum += value as i64;
+            stats = Some(match stats {
+                None => Stats {
+                    min: value,
+                    max: value,
+                    mean: 0,
+                    count: 1,
+                },
+                Some(st) => Stats {
+                    min: st.min.min(value),
+                    max: st.max.max(value),
+                    mean: 0,
+                    count: st.count + 1,
+                },
+            });
+        }
+
+        stats.map(|mut st| {
+            st.mean = (sum / st.count as i64) as i32;
+            st
+        })
+    }
+
+    /// The `pct`th percentile (0..=100) of the window, using the nearest
+    /// rank method. Rather than copying the window to sort it, this
+    /// bisects the range of values, counting the samples at or below each
+    /// guess: a few dozen passes over the history, but no large buffer.
+    pub fn percentile(&self, field: Field, from: u32, to: u32, pct: u8) -> Option<i32> {
+        let stats = self.stats(field, from, to)?;
+
+        let n = stats.count as usize;
+        let pct = pct.min(100) as usize;
+
+        // The smallest value with at least `pct`% of the samples at or
+        // below it. There is always one at or below the minimum.
+        let (mut lo, mut hi) = (stats.min, stats.max);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let at_or_below = self
+                .samples(from, to)
+                .filter(|s| s.get(field) <= mid)
+                .count();
+            if at_or_below * 100 >= pct * n {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        Some(lo)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    /// The start of an hour
+    const H0: u32 = 448_334 * 3600;
+
+    fn sample(timestamp: u32, co2_ppm: u16) -> Sample {
+        Sample {
+            timestamp,
+            co2_ppm,
+            temp_cdeg: 2100,
+            rh_cpct: 4000,
+        }
+    }
+
+    #[test]
+    fn ring() {
+        let mut ring = Ring::<u8, 3>::new();
+        assert!(ring.is_empty());
+        assert_eq!(ring.oldest(), None);
+
+        ring.push(1);
+        ring.push(2);
+        assert_eq!(ring.iter().copied().collect::<Vec<_>>(), [1, 2]);
+
+        // Wrapping around overwrites the oldest
+        for i in 3..=7 {
+            ring.push(i);
+        }
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.iter().copied().collect::<Vec<_>>(), [5, 6, 7]);
+        assert_eq!(ring.iter().rev().copied().collect::<Vec<_>>(), [7, 6, 5]);
+        assert_eq!((ring.oldest(), ring.newest()), (Some(&5), Some(&7)));
+    }
+
+    #[test]
+    fn decimation() {
+        let mut history = History::new();
+        history.push(Sample {
+            temp_cdeg: -150,
+            ..sample(H0, 400)
+        });
+        history.push(Sample {
+            temp_cdeg: -250,
+            ..sample(H0 + 3599, 600)
+        });
+        // An hour is only averaged once the next one starts
+        assert!(history.hours().is_empty());
+
+        history.push(sample(H0 + 3600, 1000));
+        let hours: Vec<Sample> = history.hours().iter().copied().collect();
+        assert_eq!(
+            hours,
+            [Sample {
+                timestamp: H0,
+                co2_ppm: 500,
+                temp_cdeg: -200,
+                rh_cpct: 4000,
+            }]
+        );
+
+        // Gaps leave no hourly sample behind
+        history.push(sample(H0 + 3 * 3600, 800));
+        let hours: Vec<u32> = history.hours().iter().map(|s| s.timestamp).collect();
+        assert_eq!(hours, [H0, H0 + 3600]);
+    }
+
+    #[test]
+    fn overlap() {
+        // Twenty five and a half hours of one sample a minute, so the
+        // minute ring starts half way through the second hour
+        let mut history = History::new();
+        for i in 0..MINUTE_SAMPLES as u32 + 90 {
+            history.push(sample(H0 + i * 60, 400 + (i / 60) as u16));
+        }
+        let first_minute = history.minutes().oldest().unwrap().timestamp;
+        assert_eq!(first_minute, H0 + 5400);
+        assert_eq!(history.hours().len(), 25);
+
+        // Only the first hour comes from the averages, the second is left
+        // to its remaining minutes
+        let samples: Vec<Sample> = history.samples(H0, u32::MAX).collect();
+        assert_eq!(samples.len(), 1 + MINUTE_SAMPLES);
+        assert_eq!(samples[0].timestamp, H0);
+        assert_eq!(samples[1].timestamp, first_minute);
+        assert!(samples.windows(2).all(|w| w[0].timestamp < w[1].timestamp));
+
+        // Windows entirely within the minutes don't touch the averages
+        let stats = history.stats(Field::Co2, H0 + 2 * 3600, H0 + 3 * 3600);
+        assert_eq!(
+            stats,
+            Some(Stats {
+                min: 402,
+                max: 402,
+                mean: 402,
+                count: 60,
+            })
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let mut history = History::new();
+        assert_eq!(history.stats(Field::Co2, 0, u32::MAX), None);
+        assert_eq!(history.percentile(Field::Co2, 0, u32::MAX, 50), None);
+
+        for (i, co2) in [600, 400, 900, 500].iter().enumerate() {
+            history.push(sample(H0 + i as u32 * 60, *co2));
+        }
+        assert_eq!(
+            history.stats(Field::Co2, 0, u32::MAX),
+            Some(Stats {
+                min: 400,
+                max: 900,
+                mean: 600,
+                count: 4,
+            })
+        );
+        // The window excludes its end
+        let stats = history.stats(Field::Co2, H0 + 60, H0 + 180).unwrap();
+        assert_eq!((stats.min, stats.max, stats.count), (400, 900, 2));
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut history = History::new();
+        for (i, co2) in [35, 20, 50, 15, 40].iter().enumerate() {
+            history.push(sample(H0 + i as u32 * 60, *co2));
+        }
+        let pct = |p| history.percentile(Field::Co2, 0, u32::MAX, p);
+        // The smallest value with at least p% of samples at or below it
+        assert_eq!(pct(0), Some(15));
+        assert_eq!(pct(5), Some(15));
+        assert_eq!(pct(30), Some(20));
+        assert_eq!(pct(40), Some(20));
+        assert_eq!(pct(50), Some(35));
+        assert_eq!(pct(100), Some(50));
+        assert_eq!(pct(255), Some(50));
+
+        let mut history = History::new();
+        for i in 0..100u32 {
+            history.push(Sample {
+                temp_cdeg: -(i as i16) * 10,
+                ..sample(H0 + i * 60, 400 + ((i * 37) % 100) as u16)
+            });
+        }
+        let pct = |field, p| history.percentile(field, 0, u32::MAX, p);
+        assert_eq!(pct(Field::Co2, 50), Some(449));
+        assert_eq!(pct(Field::Co2, 90), Some(489));
+        assert_eq!(pct(Field::Temperature, 50), Some(-500));
+        assert_eq!(pct(Field::Temperature, 1), Some(-990));
+    }
+}
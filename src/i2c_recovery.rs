@@ -0,0 +1,189 @@
+//! I2C bus recovery
+//!
+//! If a device browns out in the middle of a read, it may keep holding SDA
+//! low while it waits for clocks that will never come. From then on every
+//! transaction on the shared bus fails. [`RecoverableTwim`] wraps a [`Twim`]
+//! and, after a few consecutive bus errors, takes the pins back from the
+//! peripheral, clocks SCL by hand until the device lets go of SDA, issues a
+//! STOP, and re-initializes the peripheral.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embedded_hal::{
+    blocking::i2c::{Read, Write, WriteRead},
+    digital::v2::{InputPin, OutputPin},
+};
+use nrf52840_hal::{
+    gpio::{Floating, Input, Level, OpenDrainConfig, Pin},
+    twim::{Error, Frequency, Instance, Pins, Twim},
+};
+
+/// Number of consecutive bus errors before attempting a recovery
+const ERROR_THRESHOLD: u8 = 3;
+
+/// Maximum number of SCL pulses needed to shift out a stuck byte + ACK
+const MAX_CLOCKS: u8 = 9;
+
+/// ~5us at 64MHz, half a period of a 100kHz clock
+const HALF_PERIOD_CYCLES: u32 = 320;
+
+static RECOVERIES: AtomicU32 = AtomicU32::new(0);
+
+/// Number of bus recoveries performed since boot
+pub fn recovery_count() -> u32 {
+    RECOVERIES.load(Ordering::Relaxed)
+}
+
+pub struct RecoverableTwim<T: Instance> {
+    // NOTE: Always `Some`, except for the duration of `recover()`
+    twim: Option<Twim<T>>,
+    scl: u32,
+    sda: u32,
+    frequency: Frequency,
+    errors: u8,
+}
+
+impl<T: Instance> RecoverableTwim<T> {
+    /// Create a new TWIM driver, clearing the bus first if a device is
+    /// already holding SDA low (e.g. after a watchdog reset mid-transfer)
+    pub fn new(instance: T, pins: Pins, frequency: Frequency) -> Self {
+        let scl = pins.scl.psel_bits();
+        let sda = pins.sda.psel_bits();
+
+        let stuck = pins.sda.is_low().unwrap_or(false);
+
+        let mut me = Self {
+            twim: Some(Twim::new(instance, pins, frequency)),
+            scl,
+            sda,
+            frequency,
+            errors: 0,
+        };
+
+        if stuck {
+            defmt::warn!("SDA held low at boot!");
+            me.recover();
+        }
+
+        me
+    }
+
+    fn twim(&mut self) -> &mut Twim<T> {
+        // `twim` is only taken while `recover()` has `&mut self`
+        self.twim.as_mut().unwrap()
+    }
+
+    fn track<R>(&mut self, res: Result<R, Error>) -> Result<R, Error> {
+        match &res {
+            Ok(_) => self.errors = 0,
+            Err(e) if is_bus_error(e) => {
+                self.errors = self.errors.saturating_add(1);
+                if self.errors >= ERROR_THRESHOLD {
+                    self.recover();
+                }
+            }
+            Err(_) => {}
+        }
+        res
+    }
+
+    /// Release the bus from the peripheral, clock out whatever the stuck
+    /// device is trying to send, issue a STOP, and re-initialize the TWIM.
+    pub fn recover(&mut self) {
+        let instance = match self.twim.take() {
+            Some(twim) => twim.free(),
+            None => return,
+        };
+
+        defmt::warn!("Recovering I2C bus...");
+
+        // Hand the pins back to the GPIO peripheral
+        instance.enable.write(|w| w.enable().disabled());
+
+        // SAFETY: These are the pins we handed to the TWIM in `new()`. The
+        // peripheral is disabled, so nothing else is driving them.
+        let sda = unsafe { Pin::<Input<Floating>>::from_psel_bits(self.sda) };
+        let mut scl = unsafe { Pin::<Input<Floating>>::from_psel_bits(self.scl) }
+            .into_open_drain_output(OpenDrainConfig::Standard0Disconnect1, Level::High);
+        half_period();
+
+        let mut clocks = 0;
+        while clocks < MAX_CLOCKS && sda.is_low().unwrap_or(true) {
+            scl.set_low().ok();
+            half_period();
+            scl.set_high().ok();
+            half_period();
+            clocks += 1;
+        }
+
+        // STOP: SDA goes low -> high while SCL is high
+        scl.set_low().ok();
+        let mut sda =
+            sda.into_open_drain_output(OpenDrainConfig::Standard0Disconnect1, Level::Low);
+        half_period();
+        scl.set_high().ok();
+        half_period();
+        sda.set_high().ok();
+        half_period();
+
+        let released = sda.is_high().unwrap_or(false);
+
+        let pins = Pins {
+            scl: scl.into_floating_input(),
+            sda: sda.into_floating_input(),
+        };
+        self.twim = Some(Twim::new(instance, pins, self.frequency));
+        self.errors = 0;
+
+        let count = RECOVERIES.fetch_add(1, Ordering::Relaxed) + 1;
+        if released {
+            defmt::info!(
+                "I2C bus recovered after {:?} clocks ({:?} total)",
+                clocks,
+                count
+            );
+        } else {
+            defmt::error!("SDA still held low after recovery ({:?} total)", count);
+        }
+    }
+}
+
+impl<T: Instance> Write for RecoverableTwim<T> {
+    type Error = Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Error> {
+        let res = self.twim().write(address, bytes);
+        self.track(res)
+    }
+}
+
+impl<T: Instance> Read for RecoverableTwim<T> {
+    type Error = Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        let res = self.twim().read(address, buffer);
+        self.track(res)
+    }
+}
+
+impl<T: Instance> WriteRead for RecoverableTwim<T> {
+    type Error = Error;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+        let res = self.twim().write_read(address, bytes, buffer);
+        self.track(res)
+    }
+}
+
+/// Errors that indicate a problem with the bus itself, rather than a
+/// device that isn't there or a bad buffer
+fn is_bus_error(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::Transmit | Error::Receive | Error::Overrun | Error::DataNack
+    )
+}
+
+fn half_period() {
+    cortex_m::asm::delay(HALF_PERIOD_CYCLES);
+}
@@ -0,0 +1,176 @@
+//! Boot-time I2C bus scan
+//!
+//! Not every clock in the fleet has the same hardware fitted, so at boot we
+//! probe every valid 7-bit address on the shared bus and only enable the
+//! features whose devices actually answered.
+
+use embedded_hal::blocking::i2c::Read;
+
+/// Devices the firmware knows how to recognize by address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum Device {
+    /// DS3231 real time clock
+    Ds3231,
+    /// AT24C32 EEPROM, fitted alongside the DS3231 on many breakout boards
+    At24c32,
+    /// Sensirion SCD30 CO2/temperature/humidity sensor
+    Scd30,
+    /// HT16K33 LED matrix/segment driver (not yet supported)
+    Ht16k33,
+    /// SparkFun Serial 7-Segment display (I2C mode)
+    SparkfunSevSeg,
+}
+
+impl Device {
+    pub const ALL: [Device; 5] = [
+        Device::Ds3231,
+        Device::At24c32,
+        Device::Scd30,
+        Device::Ht16k33,
+        Device::SparkfunSevSeg,
+    ];
+
+    /// The default 7-bit address of this device
+    pub const fn address(self) -> u8 {
+        match self {
+            Device::Ds3231 => 0x68,
+            Device::At24c32 => 0x57,
+            Device::Scd30 => 0x61,
+            Device::Ht16k33 => 0x70,
+            Device::SparkfunSevSeg => 0x71,
+        }
+    }
+
+    /// Find the known device that lives at the given address, if any
+    pub fn at(address: u8) -> Option<Device> {
+        Self::ALL.iter().copied().find(|d| d.address() == address)
+    }
+}
+
+/// The set of addresses that responded during a scan
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Inventory {
+    present: u128,
+}
+
+impl Inventory {
+    /// First and last addresses that aren't reserved by the I2C spec
+    pub const FIRST_ADDRESS: u8 = 0x08;
+    pub const LAST_ADDRESS: u8 = 0x77;
+
+    /// Did anything answer at this address?
+    pub fn responded(&self, address: u8) -> bool {
+        address < 128 && (self.present & (1 << address)) != 0
+    }
+
+    /// Is this known device present at its default address?
+    pub fn has(&self, device: Device) -> bool {
+        self.responded(device.address())
+    }
+
+    /// Iterate over all addresses that responded
+    pub fn addresses(&self) -> impl Iterator<Item = u8> + '_ {
+        (Self::FIRST_ADDRESS..=Self::LAST_ADDRESS).filter(move |a| self.responded(*a))
+    }
+
+    /// Number of addresses that responded
+    pub fn count(&self) -> u32 {
+        self.present.count_ones()
+    }
+
+    fn insert(&mut self, address: u8) {
+        self.present |= 1 << address;
+    }
+
+    /// Log the inventory over defmt
+    #[cfg(feature = "firmware")]
+    pub fn log(&self) {
+        defmt::info!("I2C scan found {:?} device(s)", self.count());
+        for address in self.addresses() {
+            match Device::at(address) {
+                Some(device) => defmt::info!("  0x{:x}: {:?}", address, device),
+                None => defmt::warn!("  0x{:x}: unknown device", address),
+            }
+        }
+        for device in Device::ALL.iter().filter(|d| !self.has(**d)) {
+            defmt::info!("  missing: {:?}", device);
+        }
+    }
+}
+
+/// Probe every valid address on the bus with a single byte read.
+///
+/// A read is used rather than an empty write, as it never modifies the
+/// state of the devices we're interested in.
+pub fn scan<I: Read>(i2c: &mut I) -> Inventory {
+    let mut inventory = Inventory::default();
+    let mut buf = [0u8; 1];
+
+    for address in Inventory::FIRST_ADDRESS..=Inventory::LAST_ADDRESS {
+        if i2c.read(address, &mut buf).is_ok() {
+            inventory.insert(address);
+        }
+    }
+
+    inventory
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    /// A bus where only the given addresses answer
+    struct Bus(&'static [u8]);
+
+    impl Read for Bus {
+        type Error = ();
+
+        fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), ()> {
+            assert_eq!(buffer.len(), 1);
+            assert!((Inventory::FIRST_ADDRESS..=Inventory::LAST_ADDRESS).contains(&address));
+            if self.0.contains(&address) {
+                Ok(())
+            } else {
+                Err(())
+            }
+        }
+    }
+
+    #[test]
+    fn device_addresses() {
+        for device in Device::ALL.iter() {
+            assert_eq!(Device::at(device.address()), Some(*device));
+        }
+        assert_eq!(Device::at(0x68), Some(Device::Ds3231));
+        assert_eq!(Device::at(0x71), Some(Device::SparkfunSevSeg));
+        assert_eq!(Device::at(0x3C), None);
+        assert_eq!(Device::at(0xFF), None);
+    }
+
+    #[test]
+    fn scan_finds_what_answers() {
+        let inventory = scan(&mut Bus(&[0x3C, 0x61, 0x68]));
+        assert_eq!(inventory.count(), 3);
+        assert_eq!(
+            inventory.addresses().collect::<Vec<_>>(),
+            [0x3C, 0x61, 0x68]
+        );
+        assert!(inventory.responded(0x3C));
+        assert!(!inventory.responded(0x3D));
+        assert!(!inventory.responded(200));
+
+        assert!(inventory.has(Device::Scd30));
+        assert!(inventory.has(Device::Ds3231));
+        assert!(!inventory.has(Device::At24c32));
+        assert!(!inventory.has(Device::SparkfunSevSeg));
+    }
+
+    #[test]
+    fn empty_bus() {
+        let inventory = scan(&mut Bus(&[]));
+        assert_eq!(inventory, Inventory::default());
+        assert_eq!(inventory.addresses().count(), 0);
+        assert!(Device::ALL.iter().all(|d| !inventory.has(*d)));
+    }
+}
@@ -0,0 +1,262 @@
+//! Which clock is which
+//!
+//! Every nRF52840 has a 64 bit device ID programmed into FICR at the
+//! factory, which tells units apart for good. On top of that each clock
+//! can be given a name and a location in the config, so people know which
+//! one they are looking at and where to find it. Together with the
+//! hardware variant and the firmware build this is reported at boot, on
+//! the diagnostics screen, by the console `info` command, in the USB serial
+//! number and in the BLE Device Information Service.
+
+use core::fmt;
+
+/// Longest name or location, in bytes
+pub const LABEL_LEN: usize = 16;
+
+/// Length of a device ID written out in hex
+pub const DEVICE_ID_LEN: usize = 16;
+
+/// The chip, the variant from FICR says which build of it
+pub const PART: &str = "nRF52840";
+
+/// Version of this firmware, from Cargo.toml
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The commit this firmware was built from, set by `build.rs`
+pub const GIT_HASH: &str = match option_env!("FLEET_CLOCK_GIT_HASH") {
+    Some(hash) => hash,
+    None => "unknown",
+};
+
+/// Short text set by the user, e.g. a name. Printable ASCII, apart from
+/// double quotes so it can always be quoted.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Label {
+    bytes: [u8; LABEL_LEN],
+    len: u8,
+}
+
+impl Label {
+    /// Returns `None` if the text is too long or has other characters
+    pub fn new(text: &str) -> Option<Self> {
+        let allowed = |b: &u8| (b' '..=b'~').contains(b) && *b != b'"';
+        if text.len() > LABEL_LEN || !text.bytes().all(|b| allowed(&b)) {
+            return None;
+        }
+
+        let mut bytes = [0; LABEL_LEN];
+        bytes[..text.len()].copy_from_slice(text.as_bytes());
+        Some(Self {
+            bytes,
+            len: text.len() as u8,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever built from a checked `&str`
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The text padded with zeros, for storage
+    pub fn encode(&self) -> [u8; LABEL_LEN] {
+        self.bytes
+    }
+
+    /// Read back a label stored by [`encode`](Self::encode)
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let buf = buf.get(..LABEL_LEN)?;
+        let len = buf.iter().position(|b| *b == 0).unwrap_or(LABEL_LEN);
+        if buf[len..].iter().any(|b| *b != 0) {
+            return None;
+        }
+        Self::new(core::str::from_utf8(&buf[..len]).ok()?)
+    }
+}
+
+impl fmt::Debug for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The user assigned part of the identity, kept in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct IdentityConfig {
+    pub name: Label,
+    pub location: Label,
+}
+
+/// Unique per chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct DeviceId(pub u64);
+
+impl DeviceId {
+    /// Lowercase hex, most significant digit first
+    pub fn to_hex(self) -> [u8; DEVICE_ID_LEN] {
+        let mut buf = [0; DEVICE_ID_LEN];
+        for (i, b) in buf.iter_mut().enumerate() {
+            let nibble = (self.0 >> ((DEVICE_ID_LEN - 1 - i) * 4)) as u8 & 0xF;
+            *b = match nibble {
+                0..=9 => b'0' + nibble,
+                _ => b'a' + nibble - 10,
+            };
+        }
+        buf
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Build code and revision of the chip, four ASCII characters such as
+/// "AAD0", from FICR INFO.VARIANT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Variant(pub [u8; 4]);
+
+impl Variant {
+    pub fn as_str(&self) -> &str {
+        match core::str::from_utf8(&self.0) {
+            Ok(s) if self.0.iter().all(u8::is_ascii_alphanumeric) => s,
+            // Unprogrammed, or a value we don't understand
+            _ => "????",
+        }
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", PART, self.as_str())
+    }
+}
+
+/// The firmware build, e.g. "0.1.0+1a2b3c4d"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Firmware;
+
+impl fmt::Display for Firmware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{}", FIRMWARE_VERSION, GIT_HASH)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identity {
+    pub device_id: DeviceId,
+    pub variant: Variant,
+    pub name: Label,
+    pub location: Label,
+}
+
+impl Identity {
+    pub fn new(device_id: u64, variant: [u8; 4], config: &IdentityConfig) -> Self {
+        Self {
+            device_id: DeviceId(device_id),
+            variant: Variant(variant),
+            name: config.name,
+            location: config.location,
+        }
+    }
+
+    /// Read the factory information from FICR
+    #[cfg(feature = "firmware")]
+    pub fn read(ficr: &nrf52840_hal::pac::FICR, config: &IdentityConfig) -> Self {
+        let low = ficr.deviceid[0].read().bits() as u64;
+        let high = ficr.deviceid[1].read().bits() as u64;
+        let variant = ficr.info.variant.read().bits();
+        Self::new((high << 32) | low, variant.to_be_bytes(), config)
+    }
+
+    /// Report over defmt, e.g. at boot
+    #[cfg(feature = "firmware")]
+    pub fn log(&self) {
+        let id = self.device_id.to_hex();
+        defmt::info!(
+            "device {=str} hw {=str} fw {=str}+{=str} name {=str} location {=str}",
+            core::str::from_utf8(&id).unwrap_or("?"),
+            self.variant.as_str(),
+            FIRMWARE_VERSION,
+            GIT_HASH,
+            self.name.as_str(),
+            self.location.as_str()
+        );
+    }
+}
+
+/// One line of `key=value` pairs, quoting the user's text
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "id={} hw={} fw={} name=\"{}\" location=\"{}\"",
+            self.device_id, self.variant, Firmware, self.name, self.location
+        )
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels() {
+        let label = Label::new("Lab 3, east wall").unwrap();
+        assert_eq!(label.as_str(), "Lab 3, east wall");
+        assert_eq!(Label::decode(&label.encode()), Some(label));
+
+        let empty = Label::default();
+        assert!(empty.is_empty());
+        assert_eq!(empty.encode(), [0; LABEL_LEN]);
+        assert_eq!(Label::decode(&[0; LABEL_LEN]), Some(empty));
+
+        assert_eq!(Label::new("seventeen letters"), None);
+        assert_eq!(Label::new("say \"hi\""), None);
+        assert_eq!(Label::new("caf\u{e9}"), None);
+        assert_eq!(Label::new("tab\there"), None);
+
+        // Erased flash, and text after the padding
+        assert_eq!(Label::decode(&[0xFF; LABEL_LEN]), None);
+        let mut buf = Label::new("desk").unwrap().encode();
+        buf[6] = b'x';
+        assert_eq!(Label::decode(&buf), None);
+        assert_eq!(Label::decode(&buf[..4]), None);
+    }
+
+    #[test]
+    fn report() {
+        let config = IdentityConfig {
+            name: Label::new("clock-7").unwrap(),
+            location: Label::new("Room 2.14").unwrap(),
+        };
+        let identity = Identity::new(0x0123_4567_89ab_cdef, *b"AAD0", &config);
+
+        assert_eq!(&identity.device_id.to_hex(), b"0123456789abcdef");
+        assert_eq!(
+            identity.to_string(),
+            format!(
+                "id=0123456789abcdef hw=nRF52840-AAD0 fw={}+{} \
+                 name=\"clock-7\" location=\"Room 2.14\"",
+                FIRMWARE_VERSION, GIT_HASH
+            )
+        );
+
+        assert_eq!(DeviceId(0xF).to_hex(), *b"000000000000000f");
+        assert_eq!(Variant([0xFF; 4]).to_string(), "nRF52840-????");
+    }
+}
@@ -1,36 +1,100 @@
-#![no_std]
-
-use core::sync::atomic::{AtomicUsize, Ordering};
+#![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg(feature = "firmware")]
 use defmt_rtt as _; // global logger
+#[cfg(feature = "firmware")]
 use nrf52840_hal as _; // memory layout
 
-use panic_probe as _;
+// Hardware independent, also built on the host with the `std` feature
+pub mod alert;
+pub mod beacon;
+pub mod boot;
+pub mod button;
+pub mod calendar;
+pub mod comfort;
+pub mod config;
+pub mod console;
+pub mod crash;
+pub mod crc;
+pub mod cts;
+pub mod datalog;
+pub mod digits;
+pub mod epd;
+pub mod flash;
+pub mod gatt;
+pub mod history;
+pub mod i2c_scan;
+pub mod identity;
+pub mod monotonic;
+pub mod occupancy;
+pub mod record;
+pub mod trend;
+pub mod units;
+pub mod uptime;
+pub mod watchdog;
 
-// same panicking *behavior* as `panic-probe` but doesn't print a panic message
-// this prevents the panic message being printed *twice* when `defmt::panic` is invoked
-#[cfg(not(feature = "panic-reset"))]
-#[defmt::panic_handler]
-fn panic() -> ! {
-    cortex_m::asm::udf()
+#[cfg(feature = "firmware")]
+pub mod ble;
+#[cfg(feature = "firmware")]
+pub mod buzzer;
+#[cfg(feature = "firmware")]
+pub mod i2c_recovery;
+#[cfg(feature = "firmware")]
+pub mod nvmc;
+#[cfg(feature = "firmware")]
+pub mod qspi;
+#[cfg(feature = "firmware")]
+pub mod scd30;
+#[cfg(feature = "firmware")]
+pub mod usb_serial;
+
+// Panics and faults leave a crash dump for the next boot, see `crash`
+
+#[cfg(feature = "firmware")]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    let message = crash::record_panic(info);
+    defmt::error!("{=str}", message.as_str());
+    halt()
 }
 
-#[cfg(feature = "panic-reset")]
+// `defmt::panic!` has printed its message already, and doesn't go through
+// the panic handler above
+#[cfg(feature = "firmware")]
 #[defmt::panic_handler]
-fn panic() -> ! {
-    cortex_m::peripheral::SCB::sys_reset()
+fn defmt_panic() -> ! {
+    crash::record_defmt_panic();
+    halt()
+}
+
+#[cfg(feature = "firmware")]
+#[cortex_m_rt::exception]
+fn HardFault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    crash::record_hard_fault(frame);
+    defmt::error!("HardFault at PC {:?}", frame.pc);
+    halt()
 }
 
-// #[defmt::timestamp]
-// fn timestamp() -> u64 {
-//     static COUNT: AtomicUsize = AtomicUsize::new(0);
-//     // NOTE(no-CAS) `timestamps` runs with interrupts disabled
-//     let n = COUNT.load(Ordering::Relaxed);
-//     COUNT.store(n + 1, Ordering::Relaxed);
-//     n as u64
-// }
+/// Reset with the `panic-reset` feature, otherwise stop here for the
+/// debugger (or the watchdog)
+#[cfg(feature = "firmware")]
+fn halt() -> ! {
+    if cfg!(feature = "panic-reset") {
+        cortex_m::peripheral::SCB::sys_reset()
+    } else {
+        cortex_m::asm::udf()
+    }
+}
+
+/// Microseconds since boot, zero until `monotonic::init` is called
+#[cfg(feature = "firmware")]
+#[defmt::timestamp]
+fn timestamp() -> u64 {
+    monotonic::now().since_start().as_micros()
+}
 
 /// Terminates the application and makes `probe-run` exit with exit-code = 0
+#[cfg(feature = "firmware")]
 pub fn exit() -> ! {
     loop {
         cortex_m::asm::bkpt();
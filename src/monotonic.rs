@@ -0,0 +1,278 @@
+//! Time since boot, from the RTC
+//!
+//! RTC1 counts the 32.768kHz low frequency clock, which keeps running
+//! through sleep and whatever the main loop is busy with. The counter is
+//! only 24 bits wide and wraps every 512 seconds, so the overflow interrupt
+//! counts the wraps to extend it to 64 bits. That is good for millions of
+//! years of uptime, so [`Instant`]s can be compared without worrying about
+//! wrapping.
+//!
+//! This is the clock behind the defmt timestamps, and anything that needs
+//! to measure or schedule intervals should use it too. Unlike the DS3231 it
+//! never jumps when the wall clock is set. The caller must unmask RTC1 and
+//! call [`on_interrupt`] from it.
+
+use core::ops::{Add, AddAssign, Sub};
+
+/// Ticks per second
+pub const TICK_HZ: u64 = 32_768;
+
+/// Width of the hardware counter, in bits
+const COUNTER_BITS: u32 = 24;
+
+/// A span of time, in ticks of the RTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Duration(u64);
+
+impl Duration {
+    pub const ZERO: Self = Self(0);
+
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    pub const fn from_secs(secs: u32) -> Self {
+        Self(secs as u64 * TICK_HZ)
+    }
+
+    /// Rounded up to the next tick, so a deadline is never early
+    pub const fn from_millis(ms: u32) -> Self {
+        let ticks = ms as u64 * TICK_HZ;
+        if ticks == 0 {
+            Self::ZERO
+        } else {
+            Self((ticks - 1) / 1000 + 1)
+        }
+    }
+
+    pub const fn as_ticks(self) -> u64 {
+        self.0
+    }
+
+    /// Whole seconds, rounded down
+    pub const fn as_secs(self) -> u64 {
+        self.0 / TICK_HZ
+    }
+
+    /// Whole milliseconds, rounded down
+    pub const fn as_millis(self) -> u64 {
+        self.0 * 1000 / TICK_HZ
+    }
+
+    /// Whole microseconds, rounded down
+    pub const fn as_micros(self) -> u64 {
+        // 1_000_000 / 32_768 is 15_625 / 512
+        self.0 * 15_625 / 512
+    }
+}
+
+impl Add for Duration {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Duration {
+    type Output = Self;
+
+    /// Saturates at zero
+    fn sub(self, rhs: Self) -> Self {
+        Self(self.0.saturating_sub(rhs.0))
+    }
+}
+
+/// A point in time, counted in ticks since the clock was started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Instant(u64);
+
+impl Instant {
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    /// Combine the overflow count with a reading of the hardware counter
+    pub const fn from_parts(overflows: u32, counter: u32) -> Self {
+        let counter = counter & ((1 << COUNTER_BITS) - 1);
+        Self(((overflows as u64) << COUNTER_BITS) | counter as u64)
+    }
+
+    pub const fn as_ticks(self) -> u64 {
+        self.0
+    }
+
+    /// Time since the clock started, i.e. since boot
+    pub const fn since_start(self) -> Duration {
+        Duration(self.0)
+    }
+
+    /// Zero if `earlier` is actually later
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Time since this instant, up to now
+    #[cfg(feature = "firmware")]
+    pub fn elapsed(self) -> Duration {
+        now().duration_since(self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Self;
+
+    fn add(self, rhs: Duration) -> Self {
+        Self(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    /// Saturates at zero, see [`Instant::duration_since`]
+    fn sub(self, rhs: Self) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+#[cfg(feature = "firmware")]
+mod clock {
+    use core::{
+        cell::RefCell,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use cortex_m::interrupt::{self, Mutex};
+    use nrf52840_hal::{
+        pac::RTC1,
+        rtc::{Rtc, RtcInterrupt},
+    };
+
+    use super::Instant;
+
+    static RTC: Mutex<RefCell<Option<Rtc<RTC1>>>> = Mutex::new(RefCell::new(None));
+
+    /// Counter wraps handled by the interrupt so far
+    static OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+
+    /// Start counting from zero. LFCLK must already be running.
+    pub fn init(rtc: RTC1) {
+        // No prescaling, one tick per LFCLK cycle
+        let mut rtc = Rtc::new(rtc, 0).unwrap();
+        rtc.enable_event(RtcInterrupt::Overflow);
+        rtc.enable_interrupt(RtcInterrupt::Overflow, None);
+        rtc.enable_counter();
+
+        interrupt::free(|cs| {
+            RTC.borrow(cs).replace(Some(rtc));
+        });
+    }
+
+    /// Count a counter wrap. Call this from the RTC1 interrupt.
+    pub fn on_interrupt() {
+        interrupt::free(|cs| {
+            if let Some(rtc) = RTC.borrow(cs).borrow_mut().as_mut() {
+                if rtc.is_event_triggered(RtcInterrupt::Overflow) {
+                    rtc.reset_event(RtcInterrupt::Overflow);
+                    OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+
+    /// The current time, or zero before [`init`]
+    pub fn now() -> Instant {
+        interrupt::free(|cs| match RTC.borrow(cs).borrow().as_ref() {
+            Some(rtc) => {
+                let overflows = OVERFLOWS.load(Ordering::Relaxed);
+                let counter = rtc.get_counter();
+                // The counter may have wrapped since the interrupt last
+                // ran, with interrupts off here it can't run now. Read the
+                // counter again in case the first read was from before the
+                // wrap.
+                if rtc.is_event_triggered(RtcInterrupt::Overflow) {
+                    Instant::from_parts(overflows + 1, rtc.get_counter())
+                } else {
+                    Instant::from_parts(overflows, counter)
+                }
+            }
+            None => Instant::default(),
+        })
+    }
+}
+
+#[cfg(feature = "firmware")]
+pub use clock::{init, now, on_interrupt};
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Duration::from_secs(2).as_ticks(), 65_536);
+        assert_eq!(Duration::from_millis(1000), Duration::from_secs(1));
+        // 32.768 ticks, rounded up
+        assert_eq!(Duration::from_millis(1).as_ticks(), 33);
+        assert_eq!(Duration::from_millis(0), Duration::ZERO);
+
+        let tick = Duration::from_ticks(1);
+        assert_eq!(tick.as_micros(), 30);
+        assert_eq!(tick.as_millis(), 0);
+        assert_eq!(Duration::from_ticks(TICK_HZ - 1).as_secs(), 0);
+        assert_eq!(Duration::from_secs(90).as_millis(), 90_000);
+        assert_eq!(Duration::from_secs(90).as_micros(), 90_000_000);
+        assert_eq!(Duration::from_millis(100).as_millis(), 100);
+
+        // Centuries, without overflowing
+        let centuries = Duration::from_secs(u32::MAX) + Duration::from_secs(u32::MAX);
+        assert_eq!(centuries.as_micros(), 2 * u32::MAX as u64 * 1_000_000);
+    }
+
+    #[test]
+    fn overflow_extension() {
+        assert_eq!(Instant::from_parts(0, 0).as_ticks(), 0);
+        assert_eq!(Instant::from_parts(0, 0xFF_FFFF).as_ticks(), 0xFF_FFFF);
+        assert_eq!(Instant::from_parts(1, 0).as_ticks(), 0x100_0000);
+        assert_eq!(
+            Instant::from_parts(u32::MAX, 5).as_ticks(),
+            (u32::MAX as u64) << 24 | 5
+        );
+        // Only the low 24 bits of the register are the counter
+        assert_eq!(Instant::from_parts(0, 0x0100_0007).as_ticks(), 7);
+
+        // Each wrap is 512 seconds
+        let before = Instant::from_parts(3, 0xFF_FFF0);
+        let after = Instant::from_parts(4, 0x10);
+        assert_eq!((after - before).as_ticks(), 0x20);
+        assert_eq!(Instant::from_parts(1, 0).since_start().as_secs(), 512);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let start = Instant::from_ticks(1000);
+        let mut deadline = start + Duration::from_millis(100);
+        assert!(deadline > start);
+        assert_eq!(deadline - start, Duration::from_millis(100));
+
+        // Going backwards saturates rather than wrapping
+        assert_eq!(start - deadline, Duration::ZERO);
+        assert_eq!(start.duration_since(deadline), Duration::ZERO);
+        assert_eq!(
+            Duration::from_secs(1) - Duration::from_secs(2),
+            Duration::ZERO
+        );
+
+        deadline += Duration::from_secs(1);
+        assert_eq!((deadline - start).as_millis(), 1100);
+    }
+}
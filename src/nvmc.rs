@@ -0,0 +1,94 @@
+//! The nRF52840's internal flash, via the NVMC peripheral
+
+use nrf52840_hal::pac::NVMC;
+
+use crate::flash::Flash;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum NvmcError {
+    /// Writes must be word aligned and a multiple of four bytes long
+    Unaligned,
+    /// The address is outside of the region reserved for storage
+    OutOfBounds,
+}
+
+/// The nRF52840's internal flash, restricted to the pages at the top of
+/// flash that are reserved for persistent storage.
+pub struct Nvmc {
+    nvmc: NVMC,
+}
+
+impl Nvmc {
+    /// First byte of the region reserved for persistent storage. The linker
+    /// checks that the image ends below it, see `storage.x`.
+    pub const STORAGE_START: u32 = 0x000F_C000;
+
+    /// One past the last byte of internal flash
+    pub const STORAGE_END: u32 = 0x0010_0000;
+
+    pub fn new(nvmc: NVMC) -> Self {
+        Self { nvmc }
+    }
+
+    pub fn free(self) -> NVMC {
+        self.nvmc
+    }
+
+    fn check(addr: u32, len: usize) -> Result<(), NvmcError> {
+        let end = addr as usize + len;
+        if addr < Self::STORAGE_START || end > Self::STORAGE_END as usize {
+            Err(NvmcError::OutOfBounds)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn wait_ready(&self) {
+        while self.nvmc.ready.read().ready().is_busy() {}
+    }
+}
+
+impl Flash for Nvmc {
+    type Error = NvmcError;
+    const SECTOR_SIZE: u32 = 4096;
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), NvmcError> {
+        Self::check(addr, buf.len())?;
+
+        // SAFETY: internal flash is memory mapped, and the range was
+        // bounds checked above.
+        let src = unsafe { core::slice::from_raw_parts(addr as *const u8, buf.len()) };
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), NvmcError> {
+        Self::check(addr, data.len())?;
+        if addr % 4 != 0 || data.len() % 4 != 0 {
+            return Err(NvmcError::Unaligned);
+        }
+
+        self.nvmc.config.write(|w| w.wen().wen());
+        for (i, word) in data.chunks_exact(4).enumerate() {
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            let dst = (addr as usize + (i * 4)) as *mut u32;
+
+            // SAFETY: bounds and alignment checked above, NVMC is in write mode
+            unsafe { core::ptr::write_volatile(dst, word) };
+            self.wait_ready();
+        }
+        self.nvmc.config.write(|w| w.wen().ren());
+        Ok(())
+    }
+
+    fn erase(&mut self, addr: u32) -> Result<(), NvmcError> {
+        let addr = addr & !(Self::SECTOR_SIZE - 1);
+        Self::check(addr, Self::SECTOR_SIZE as usize)?;
+
+        self.nvmc.config.write(|w| w.wen().een());
+        self.nvmc.erasepage().write(|w| unsafe { w.bits(addr) });
+        self.wait_ready();
+        self.nvmc.config.write(|w| w.wen().ren());
+        Ok(())
+    }
+}
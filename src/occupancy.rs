@@ -0,0 +1,184 @@
+//! Estimating how many people are in a room from its CO2
+//!
+//! People breathe out CO2 at a fairly steady rate, and ventilation swaps
+//! the room's air for outdoor air. For a well mixed room of volume `V`,
+//! ventilated at `ACH` air changes per hour, with `N` people in it:
+//!
+//! ```text
+//! V dC/dt = N G - ACH V (C - C_out)
+//! ```
+//!
+//! so `N = V (dC/dt + ACH (C - C_out)) / G`. The level and rate of change
+//! come from the [`Trend`], which smooths out the sensor noise. The
+//! estimate lags changes by a few minutes, and is only as good as the
+//! configured volume and ventilation rate: an open door or window
+//! ventilates far more than the configured rate, so reads low.
+
+use crate::trend::Trend;
+
+/// CO2 breathed out per person, in litres per hour. About 0.005 L/s, for
+/// an adult doing office work.
+pub const GENERATION_LPH: u32 = 18;
+
+/// The room the clock is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct RoomConfig {
+    /// Volume of the room, in cubic metres (1..=10000)
+    pub volume_m3: u16,
+
+    /// Ventilation rate, in tenths of an air change per hour (1..=200)
+    pub air_changes_dph: u16,
+
+    /// CO2 of the air the ventilation brings in, in ppm (250..=1000)
+    pub outdoor_ppm: u16,
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        Self {
+            // A small meeting room, 4m x 3m x 2.5m
+            volume_m3: 30,
+            air_changes_dph: 10,
+            outdoor_ppm: 420,
+        }
+    }
+}
+
+impl RoomConfig {
+    pub fn is_valid(&self) -> bool {
+        (1..=10_000).contains(&self.volume_m3)
+            && (1..=200).contains(&self.air_changes_dph)
+            && (250..=1000).contains(&self.outdoor_ppm)
+    }
+
+    /// Estimated number of people, rounded. Never negative: CO2 falling
+    /// faster than the ventilation explains reads as an empty room.
+    pub fn occupancy(&self, trend: &Trend) -> u16 {
+        // Everything in hundredths of a ppm per hour
+        let rise = trend.rate_cppm_min as i64 * 60;
+        let excess = trend.level_ppm as i64 - self.outdoor_ppm as i64;
+        let vented = excess * self.air_changes_dph as i64 * 10;
+
+        // One litre of CO2 per hour in one cubic metre is 1000 ppm/h
+        let per_person = GENERATION_LPH as i64 * 1000 * 100;
+        let people = (self.volume_m3 as i64 * (rise + vented) + per_person / 2) / per_person;
+        people.clamp(0, u16::MAX as i64) as u16
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::history::{History, Sample};
+
+    const START: u32 = 1_614_000_000;
+
+    /// Step the mass balance a minute at a time, with `people[i]` in the
+    /// room during minute `i`, and estimate the occupancy each minute
+    fn simulate(room: &RoomConfig, people: &[u32], noise: &[i32]) -> Vec<u16> {
+        let mut history = History::new();
+        let mut co2 = room.outdoor_ppm as f64;
+        let mut estimates = Vec::new();
+
+        for (i, n) in people.iter().enumerate() {
+            let volume = room.volume_m3 as f64;
+            let ach = room.air_changes_dph as f64 / 10.0;
+            let per_hour = *n as f64 * GENERATION_LPH as f64 * 1000.0 / volume
+                - ach * (co2 - room.outdoor_ppm as f64);
+            co2 += per_hour / 60.0;
+
+            let now = START + i as u32 * 60;
+            let reading = co2 + noise[i % noise.len()] as f64;
+            history.push(Sample {
+                timestamp: now,
+                co2_ppm: reading.round() as u16,
+                temp_cdeg: 2100,
+                rh_cpct: 4000,
+            });
+            let estimate = Trend::analyse(&history, now).map(|t| room.occupancy(&t));
+            estimates.push(estimate.unwrap_or(0));
+        }
+        estimates
+    }
+
+    fn schedule(parts: &[(u32, usize)]) -> Vec<u32> {
+        let mut people = Vec::new();
+        for (n, minutes) in parts.iter() {
+            people.extend_from_slice(&vec![*n; *minutes]);
+        }
+        people
+    }
+
+    #[test]
+    fn meeting() {
+        let room = RoomConfig::default();
+        let people = schedule(&[(0, 30), (6, 60), (0, 90)]);
+        let estimates = simulate(&room, &people, &[0]);
+
+        assert!(estimates[..30].iter().all(|n| *n == 0));
+        // Settled a window's length after people come and go
+        for (minute, expected) in [(50, 6), (89, 6), (110, 0), (179, 0)].iter() {
+            let n = estimates[*minute] as i32;
+            assert!((n - expected).abs() <= 1, "minute {}: {}", minute, n);
+        }
+    }
+
+    #[test]
+    fn noisy_readings() {
+        // The SCD30 is good to about 30 ppm
+        let noise = [0, 25, -10, 30, -25, 5, -30, 15, 10, -20, 20, -5];
+        let room = RoomConfig {
+            volume_m3: 120,
+            air_changes_dph: 30,
+            outdoor_ppm: 400,
+        };
+        let people = schedule(&[(0, 20), (25, 120), (4, 60)]);
+        let estimates = simulate(&room, &people, &noise);
+
+        let n = estimates[80] as i32;
+        assert!((n - 25).abs() <= 2, "{}", n);
+        let n = estimates[139] as i32;
+        assert!((n - 25).abs() <= 2, "{}", n);
+        let n = estimates[199] as i32;
+        assert!((n - 4).abs() <= 2, "{}", n);
+    }
+
+    #[test]
+    fn steady_state() {
+        let room = RoomConfig::default();
+        // Two people at 1 ACH hold 30m^3 at 1200 ppm above outdoors
+        let trend = Trend {
+            rate_cppm_min: 0,
+            level_ppm: 420 + 1200,
+        };
+        assert_eq!(room.occupancy(&trend), 2);
+
+        // Below outdoor levels, or airing out quickly
+        let trend = Trend {
+            rate_cppm_min: 0,
+            level_ppm: 380,
+        };
+        assert_eq!(room.occupancy(&trend), 0);
+        let trend = Trend {
+            rate_cppm_min: -5000,
+            level_ppm: 900,
+        };
+        assert_eq!(room.occupancy(&trend), 0);
+    }
+
+    #[test]
+    fn validation() {
+        assert!(RoomConfig::default().is_valid());
+        let room = RoomConfig {
+            air_changes_dph: 0,
+            ..RoomConfig::default()
+        };
+        assert!(!room.is_valid());
+        let room = RoomConfig {
+            volume_m3: 0,
+            ..RoomConfig::default()
+        };
+        assert!(!room.is_valid());
+    }
+}
@@ -0,0 +1,167 @@
+//! External QSPI NOR flash
+//!
+//! The Feather nRF52840 Express has a 2MB GD25Q16 on the QSPI pins. The
+//! HAL doesn't have a QSPI driver yet, so this drives the peripheral
+//! directly. Only single line commands are used (fast read/page program),
+//! which avoids having to set the chip's quad enable bit, and is plenty
+//! fast for logging.
+
+use nrf52840_hal::{
+    gpio::{Output, Pin, PushPull},
+    pac::QSPI,
+};
+
+use crate::flash::Flash;
+
+/// Size of the GD25Q16 on the Feather
+pub const CAPACITY: u32 = 2 * 1024 * 1024;
+
+/// Flash status register "write in progress" bit, mirrored into the top
+/// byte of the QSPI STATUS register
+const SREG_WIP: u32 = 1 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum QspiError {
+    /// Addresses and lengths must be a multiple of four bytes
+    Unaligned,
+    /// The address is beyond the end of the chip
+    OutOfBounds,
+}
+
+pub struct Pins {
+    pub sck: Pin<Output<PushPull>>,
+    pub csn: Pin<Output<PushPull>>,
+    pub io0: Pin<Output<PushPull>>,
+    pub io1: Pin<Output<PushPull>>,
+    pub io2: Pin<Output<PushPull>>,
+    pub io3: Pin<Output<PushPull>>,
+}
+
+/// The EasyDMA transfers need a word aligned buffer in RAM
+#[repr(align(4))]
+struct Bounce([u8; 256]);
+
+pub struct QspiFlash {
+    qspi: QSPI,
+    bounce: Bounce,
+}
+
+impl QspiFlash {
+    pub fn new(qspi: QSPI, pins: Pins) -> Self {
+        qspi.psel.sck.write(|w| unsafe { w.bits(pins.sck.psel_bits()) });
+        qspi.psel.csn.write(|w| unsafe { w.bits(pins.csn.psel_bits()) });
+        qspi.psel.io0.write(|w| unsafe { w.bits(pins.io0.psel_bits()) });
+        qspi.psel.io1.write(|w| unsafe { w.bits(pins.io1.psel_bits()) });
+        qspi.psel.io2.write(|w| unsafe { w.bits(pins.io2.psel_bits()) });
+        qspi.psel.io3.write(|w| unsafe { w.bits(pins.io3.psel_bits()) });
+
+        qspi.ifconfig0.write(|w| {
+            w.readoc().fastread();
+            w.writeoc().pp();
+            w.addrmode()._24bit();
+            w.dpmenable().disable()
+        });
+
+        // 32MHz / (1 + 1) = 16MHz, SPI mode 0
+        qspi.ifconfig1.write(|w| unsafe {
+            w.sckdelay().bits(1);
+            w.spimode().mode0();
+            w.sckfreq().bits(1)
+        });
+
+        qspi.enable.write(|w| w.enable().enabled());
+
+        let mut me = Self {
+            qspi,
+            bounce: Bounce([0; 256]),
+        };
+
+        me.qspi.tasks_activate.write(|w| unsafe { w.bits(1) });
+        me.wait_ready();
+
+        me
+    }
+
+    pub fn free(self) -> QSPI {
+        self.qspi.enable.write(|w| w.enable().disabled());
+        self.qspi
+    }
+
+    fn wait_ready(&mut self) {
+        while self.qspi.events_ready.read().bits() == 0 {}
+        self.qspi.events_ready.reset();
+    }
+
+    /// Wait for the flash chip itself to finish a program/erase
+    fn wait_idle(&mut self) {
+        while (self.qspi.status.read().bits() & SREG_WIP) != 0 {}
+    }
+
+    fn check(addr: u32, len: usize) -> Result<(), QspiError> {
+        if addr % 4 != 0 || len % 4 != 0 {
+            Err(QspiError::Unaligned)
+        } else if addr as usize + len > CAPACITY as usize {
+            Err(QspiError::OutOfBounds)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Flash for QspiFlash {
+    type Error = QspiError;
+    const SECTOR_SIZE: u32 = 4096;
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), QspiError> {
+        Self::check(addr, buf.len())?;
+
+        let mut addr = addr;
+        for chunk in buf.chunks_mut(self.bounce.0.len()) {
+            let len = chunk.len();
+            let dst = self.bounce.0.as_mut_ptr() as u32;
+
+            self.qspi.read.src.write(|w| unsafe { w.src().bits(addr) });
+            self.qspi.read.dst.write(|w| unsafe { w.dst().bits(dst) });
+            self.qspi.read.cnt.write(|w| unsafe { w.cnt().bits(len as u32) });
+            self.qspi.tasks_readstart.write(|w| unsafe { w.bits(1) });
+            self.wait_ready();
+
+            chunk.copy_from_slice(&self.bounce.0[..len]);
+            addr += len as u32;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), QspiError> {
+        Self::check(addr, data.len())?;
+
+        let mut addr = addr;
+        for chunk in data.chunks(self.bounce.0.len()) {
+            let len = chunk.len();
+            self.bounce.0[..len].copy_from_slice(chunk);
+            let src = self.bounce.0.as_ptr() as u32;
+
+            self.qspi.write.dst.write(|w| unsafe { w.dst().bits(addr) });
+            self.qspi.write.src.write(|w| unsafe { w.src().bits(src) });
+            self.qspi.write.cnt.write(|w| unsafe { w.cnt().bits(len as u32) });
+            self.qspi.tasks_writestart.write(|w| unsafe { w.bits(1) });
+            self.wait_ready();
+            self.wait_idle();
+
+            addr += len as u32;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, addr: u32) -> Result<(), QspiError> {
+        let addr = addr & !(Self::SECTOR_SIZE - 1);
+        Self::check(addr, Self::SECTOR_SIZE as usize)?;
+
+        self.qspi.erase.ptr.write(|w| unsafe { w.ptr().bits(addr) });
+        self.qspi.erase.len.write(|w| w.len()._4kb());
+        self.qspi.tasks_erasestart.write(|w| unsafe { w.bits(1) });
+        self.wait_ready();
+        self.wait_idle();
+        Ok(())
+    }
+}
@@ -0,0 +1,668 @@
+//! Compact binary log records
+//!
+//! Records are encoded as self-delimiting frames, so they can be packed
+//! back to back in flash or sent over a byte stream:
+//!
+//! ```text
+//! tag      u8      (format version << 4) | kind
+//! time     u32     seconds since the unix epoch, for "absolute" kinds
+//!   or     varint  seconds since the previous frame, for "delta" kinds
+//! payload  ...     depends on the kind, see below
+//! crc      u8      CRC-8 (Sensirion) over all of the above
+//! ```
+//!
+//! Sample payloads are CO2 in ppm (`u16`), temperature in 0.01 degrees C
+//! (`i16`), and relative humidity in 0.5% steps (`u8`). Event payloads are
+//! the event kind (`u8`) and an argument (`u16`). All values are little
+//! endian. With one sample a minute, a delta sample frame is 8 bytes.
+//!
+//! A decoder needs an absolute frame before it can make sense of delta
+//! frames, so an [`Encoder`] should be [`reset`](Encoder::reset) wherever
+//! a reader might start, e.g. at the start of each flash sector.
+//!
+//! `0xFF` and `0x00` are never valid tags, and are skipped as padding by
+//! [`Frames`].
+
+use core::fmt;
+
+use crate::{comfort::Comfort, crc::crc8_sensirion, history::Sample};
+
+/// Version of the frame format, stored in the top nibble of every tag
+pub const VERSION: u8 = 1;
+
+/// Longest possible frame: tag, 5 byte varint, 5 byte payload, CRC
+pub const MAX_FRAME_LEN: usize = 12;
+
+/// Byte used to pad between frames, e.g. to keep flash writes aligned
+pub const PADDING: u8 = 0xFF;
+
+/// Also skipped as padding. Unlike [`PADDING`], it can't be mistaken for
+/// erased flash.
+pub const FILL: u8 = 0x00;
+
+const KIND_ABS_SAMPLE: u8 = 0x0;
+const KIND_DELTA_SAMPLE: u8 = 0x1;
+const KIND_ABS_EVENT: u8 = 0x2;
+const KIND_DELTA_EVENT: u8 = 0x3;
+
+const SAMPLE_PAYLOAD: usize = 5;
+const EVENT_PAYLOAD: usize = 3;
+
+/// Deltas at least this large are cheaper to send as absolute times
+const MAX_DELTA: u32 = 1 << 21;
+
+/// Something worth noting in the log, other than a sensor reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+#[repr(u8)]
+pub enum EventKind {
+    /// The argument is why, see `boot::Cause`
+    Boot = 1,
+    Recalibrated = 2,
+    AlertLevel = 3,
+    I2cRecovery = 4,
+    /// The RTC was set from a time reference, the argument is how many
+    /// seconds it was off by
+    TimeSync = 5,
+    /// The last reset was a crash, the argument is its code, see
+    /// `crash::Crash::code`
+    Crash = 6,
+    /// The estimated number of people in the room changed, the argument
+    /// is the new estimate, see `occupancy`
+    Occupancy = 7,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 7] = [
+        EventKind::Boot,
+        EventKind::Recalibrated,
+        EventKind::AlertLevel,
+        EventKind::I2cRecovery,
+        EventKind::TimeSync,
+        EventKind::Crash,
+        EventKind::Occupancy,
+    ];
+
+    pub fn from_u8(val: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|k| *k as u8 == val)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Boot => "boot",
+            EventKind::Recalibrated => "recalibrated",
+            EventKind::AlertLevel => "alert_level",
+            EventKind::I2cRecovery => "i2c_recovery",
+            EventKind::TimeSync => "time_sync",
+            EventKind::Crash => "crash",
+            EventKind::Occupancy => "occupancy",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum Record {
+    Sample(Sample),
+    Event {
+        timestamp: u32,
+        kind: EventKind,
+        /// Meaning depends on the kind, e.g. the new alert level
+        arg: u16,
+    },
+}
+
+impl Record {
+    pub fn timestamp(&self) -> u32 {
+        match self {
+            Record::Sample(s) => s.timestamp,
+            Record::Event { timestamp, .. } => *timestamp,
+        }
+    }
+}
+
+/// Relative humidity is stored in 0.5% steps. This is what a sample's
+/// humidity will read back as after a trip through the encoder.
+pub fn quantize_rh(rh_cpct: u16) -> u16 {
+    rh_to_wire(rh_cpct) as u16 * 50
+}
+
+pub(crate) fn rh_to_wire(rh_cpct: u16) -> u8 {
+    ((rh_cpct.min(10_000) + 25) / 50) as u8
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum DecodeError {
+    /// The buffer ends part way through a frame
+    Truncated,
+    /// The frame failed its CRC check
+    Crc,
+    /// The frame was written by an unknown version of the format
+    Version(u8),
+    /// Unknown frame or event kind
+    Kind(u8),
+    /// A delta frame with no preceding absolute frame
+    NoBase,
+}
+
+/// Frame encoder. Tracks the time of the last frame, to send deltas.
+#[derive(Default)]
+pub struct Encoder {
+    last: Option<u32>,
+}
+
+impl Encoder {
+    pub const fn new() -> Self {
+        Self { last: None }
+    }
+
+    /// Make the next frame absolute
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+
+    /// Encode a record into `buf`, returning the length of the frame
+    pub fn encode(&mut self, record: &Record, buf: &mut [u8; MAX_FRAME_LEN]) -> usize {
+        let timestamp = record.timestamp();
+        let delta = self
+            .last
+            .and_then(|last| timestamp.checked_sub(last))
+            .filter(|delta| *delta < MAX_DELTA);
+        self.last = Some(timestamp);
+
+        let kind = match (record, delta.is_some()) {
+            (Record::Sample(_), false) => KIND_ABS_SAMPLE,
+            (Record::Sample(_), true) => KIND_DELTA_SAMPLE,
+            (Record::Event { .. }, false) => KIND_ABS_EVENT,
+            (Record::Event { .. }, true) => KIND_DELTA_EVENT,
+        };
+        buf[0] = (VERSION << 4) | kind;
+
+        let mut len = 1;
+        match delta {
+            Some(delta) => len += write_varint(delta, &mut buf[len..]),
+            None => {
+                buf[len..][..4].copy_from_slice(&timestamp.to_le_bytes());
+                len += 4;
+            }
+        }
+
+        match record {
+            Record::Sample(s) => {
+                buf[len..][..2].copy_from_slice(&s.co2_ppm.to_le_bytes());
+                buf[len + 2..][..2].copy_from_slice(&s.temp_cdeg.to_le_bytes());
+                buf[len + 4] = rh_to_wire(s.rh_cpct);
+                len += SAMPLE_PAYLOAD;
+            }
+            Record::Event { kind, arg, .. } => {
+                buf[len] = *kind as u8;
+                buf[len + 1..][..2].copy_from_slice(&arg.to_le_bytes());
+                len += EVENT_PAYLOAD;
+            }
+        }
+
+        buf[len] = crc8_sensirion(&buf[..len]);
+        len + 1
+    }
+}
+
+/// Frame decoder. Tracks the time of the last frame, to apply deltas.
+#[derive(Default)]
+pub struct Decoder {
+    last: Option<u32>,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self { last: None }
+    }
+
+    /// Decode the frame at the start of `buf`, returning the record and
+    /// the length of the frame
+    pub fn decode(&mut self, buf: &[u8]) -> Result<(Record, usize), DecodeError> {
+        let tag = *buf.first().ok_or(DecodeError::Truncated)?;
+        if (tag >> 4) != VERSION {
+            return Err(DecodeError::Version(tag >> 4));
+        }
+
+        let kind = tag & 0x0F;
+        let (is_sample, is_delta) = match kind {
+            KIND_ABS_SAMPLE => (true, false),
+            KIND_DELTA_SAMPLE => (true, true),
+            KIND_ABS_EVENT => (false, false),
+            KIND_DELTA_EVENT => (false, true),
+            _ => return Err(DecodeError::Kind(kind)),
+        };
+
+        let mut len = 1;
+        let (time, time_len) = if is_delta {
+            read_varint(&buf[len..]).ok_or(DecodeError::Truncated)?
+        } else {
+            let bytes = buf.get(len..len + 4).ok_or(DecodeError::Truncated)?;
+            (
+                u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                4,
+            )
+        };
+        len += time_len;
+
+        let payload_len = if is_sample {
+            SAMPLE_PAYLOAD
+        } else {
+            EVENT_PAYLOAD
+        };
+        let payload = buf
+            .get(len..len + payload_len)
+            .ok_or(DecodeError::Truncated)?;
+        len += payload_len;
+
+        let crc = *buf.get(len).ok_or(DecodeError::Truncated)?;
+        if crc != crc8_sensirion(&buf[..len]) {
+            return Err(DecodeError::Crc);
+        }
+        len += 1;
+
+        let timestamp = if is_delta {
+            self.last
+                .ok_or(DecodeError::NoBase)?
+                .checked_add(time)
+                .ok_or(DecodeError::NoBase)?
+        } else {
+            time
+        };
+
+        let record = if is_sample {
+            Record::Sample(Sample {
+                timestamp,
+                co2_ppm: u16::from_le_bytes([payload[0], payload[1]]),
+                temp_cdeg: i16::from_le_bytes([payload[2], payload[3]]),
+                rh_cpct: payload[4] as u16 * 50,
+            })
+        } else {
+            Record::Event {
+                timestamp,
+                kind: EventKind::from_u8(payload[0]).ok_or(DecodeError::Kind(payload[0]))?,
+                arg: u16::from_le_bytes([payload[1], payload[2]]),
+            }
+        };
+
+        self.last = Some(timestamp);
+        Ok((record, len))
+    }
+}
+
+/// Iterate over all of the records in a buffer of frames, skipping padding.
+///
+/// If a frame can't be decoded (e.g. a write was interrupted by a reset),
+/// the iterator moves forward one byte at a time until it finds the next
+/// valid absolute frame. Writers should start with an absolute frame after
+/// a reset, so little is lost.
+pub struct Frames<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    decoder: Decoder,
+    skipped: usize,
+}
+
+impl<'a> Frames<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            decoder: Decoder::new(),
+            skipped: 0,
+        }
+    }
+
+    /// Number of bytes skipped so far because they couldn't be decoded
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        while self.pos < self.buf.len() {
+            let rest = &self.buf[self.pos..];
+            if rest[0] == PADDING || rest[0] == FILL {
+                self.pos += 1;
+                continue;
+            }
+
+            match self.decoder.decode(rest) {
+                Ok((record, len)) => {
+                    self.pos += len;
+                    return Some(record);
+                }
+                Err(_) => {
+                    // Deltas after a lost frame would be relative to the
+                    // wrong time, so wait for the next absolute frame
+                    self.decoder = Decoder::new();
+                    self.pos += 1;
+                    self.skipped += 1;
+                }
+            }
+        }
+        None
+    }
+}
+
+fn write_varint(mut val: u32, buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    loop {
+        let byte = (val & 0x7F) as u8;
+        val >>= 7;
+        if val == 0 {
+            buf[len] = byte;
+            return len + 1;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+}
+
+fn read_varint(buf: &[u8]) -> Option<(u32, usize)> {
+    let mut val = 0u32;
+    for (i, byte) in buf.iter().take(5).enumerate() {
+        val |= ((byte & 0x7F) as u32) << (7 * i);
+        if (byte & 0x80) == 0 {
+            return Some((val, i + 1));
+        }
+    }
+    None
+}
+
+/// CSV header matching [`Csv`]. The comfort metrics are derived from the
+/// temperature and humidity, see `comfort`, and come last so the logged
+/// columns keep their places.
+pub const CSV_HEADER: &str = "timestamp,kind,co2_ppm,temp_c,rh_pct,event,arg,\
+                              dew_point_c,abs_humidity_g_m3,heat_index_c";
+
+/// Formats a record as a CSV line (without the line ending)
+pub struct Csv<'a>(pub &'a Record);
+
+impl fmt::Display for Csv<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Record::Sample(s) => {
+                write!(
+                    f,
+                    "{},sample,{},{},{},,",
+                    s.timestamp,
+                    s.co2_ppm,
+                    Hundredths(s.temp_cdeg as i32),
+                    Hundredths(s.rh_cpct as i32),
+                )?;
+                match Comfort::of(s) {
+                    Some(c) => write!(
+                        f,
+                        ",{},{},{}",
+                        Hundredths(c.dew_point_cdeg as i32),
+                        Hundredths(c.abs_humidity_cg_m3 as i32),
+                        Hundredths(c.heat_index_cdeg as i32),
+                    ),
+                    None => write!(f, ",,,"),
+                }
+            }
+            Record::Event {
+                timestamp,
+                kind,
+                arg,
+            } => write!(f, "{},event,,,,{},{},,,", timestamp, kind.name(), arg),
+        }
+    }
+}
+
+/// Fixed point value in hundredths, shown with two decimal places
+struct Hundredths(i32);
+
+impl fmt::Display for Hundredths {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        write!(f, "{}{}.{:02}", sign, abs / 100, abs % 100)
+    }
+}
+
+/// Exporters for decoded records
+#[cfg(feature = "std")]
+pub mod export {
+    use std::io::{self, Write};
+
+    use super::{Comfort, Csv, Hundredths, Record};
+
+    /// Write a single record as a CSV line
+    pub fn write_csv<W: Write>(w: &mut W, record: &Record) -> io::Result<()> {
+        writeln!(w, "{}", Csv(record))
+    }
+
+    /// Write a single record as a line of JSON (i.e. "JSON lines")
+    pub fn write_json<W: Write>(w: &mut W, record: &Record) -> io::Result<()> {
+        match record {
+            Record::Sample(s) => {
+                write!(
+                    w,
+                    r#"{{"timestamp":{},"kind":"sample","co2_ppm":{},"temp_c":{},"rh_pct":{}"#,
+                    s.timestamp,
+                    s.co2_ppm,
+                    Hundredths(s.temp_cdeg as i32),
+                    Hundredths(s.rh_cpct as i32),
+                )?;
+                if let Some(c) = Comfort::of(s) {
+                    write!(
+                        w,
+                        r#","dew_point_c":{},"abs_humidity_g_m3":{},"heat_index_c":{}"#,
+                        Hundredths(c.dew_point_cdeg as i32),
+                        Hundredths(c.abs_humidity_cg_m3 as i32),
+                        Hundredths(c.heat_index_cdeg as i32),
+                    )?;
+                }
+                writeln!(w, "}}")
+            }
+            Record::Event {
+                timestamp,
+                kind,
+                arg,
+            } => writeln!(
+                w,
+                r#"{{"timestamp":{},"kind":"event","event":"{}","arg":{}}}"#,
+                timestamp,
+                kind.name(),
+                arg
+            ),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn arb_record(timestamp: u32) -> impl Strategy<Value = Record> {
+        let sample = (any::<u16>(), any::<i16>(), 0..=10_000u16).prop_map(
+            move |(co2_ppm, temp_cdeg, rh_cpct)| {
+                Record::Sample(Sample {
+                    timestamp,
+                    co2_ppm,
+                    temp_cdeg,
+                    rh_cpct,
+                })
+            },
+        );
+        let event =
+            (0..EventKind::ALL.len(), any::<u16>()).prop_map(move |(k, arg)| Record::Event {
+                timestamp,
+                kind: EventKind::ALL[k],
+                arg,
+            });
+        prop_oneof![sample, event]
+    }
+
+    /// A run of records with non-decreasing timestamps, with the odd jump
+    /// backwards (e.g. the clock being set)
+    fn arb_records() -> impl Strategy<Value = Vec<Record>> {
+        (
+            any::<u32>(),
+            prop::collection::vec(any::<(u8, u32)>(), 0..64),
+        )
+            .prop_flat_map(|(start, steps)| {
+                let mut t = start;
+                let records: Vec<_> = steps
+                    .iter()
+                    .map(|(kind, step)| {
+                        t = match kind % 8 {
+                            0 => *step,
+                            1 => t.wrapping_add(*step),
+                            _ => t.wrapping_add(step % 120),
+                        };
+                        arb_record(t)
+                    })
+                    .collect();
+                records
+            })
+    }
+
+    fn quantized(record: &Record) -> Record {
+        match *record {
+            Record::Sample(s) => Record::Sample(Sample {
+                rh_cpct: quantize_rh(s.rh_cpct),
+                ..s
+            }),
+            other => other,
+        }
+    }
+
+    fn encode_all(records: &[Record]) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        let mut out = Vec::new();
+        for rec in records {
+            let mut buf = [0u8; MAX_FRAME_LEN];
+            let len = enc.encode(rec, &mut buf);
+            out.extend_from_slice(&buf[..len]);
+        }
+        out
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(records in arb_records()) {
+            let bytes = encode_all(&records);
+            let decoded: Vec<_> = Frames::new(&bytes).collect();
+            let expected: Vec<_> = records.iter().map(quantized).collect();
+            prop_assert_eq!(decoded, expected);
+        }
+
+        #[test]
+        fn round_trip_with_padding(records in arb_records(), pad in 0..4usize) {
+            let mut enc = Encoder::new();
+            let mut bytes = Vec::new();
+            for rec in &records {
+                let mut buf = [0u8; MAX_FRAME_LEN];
+                let len = enc.encode(rec, &mut buf);
+                bytes.extend_from_slice(&buf[..len]);
+                bytes.resize(bytes.len() + pad, PADDING);
+            }
+            let decoded: Vec<_> = Frames::new(&bytes).collect();
+            let expected: Vec<_> = records.iter().map(quantized).collect();
+            prop_assert_eq!(decoded, expected);
+        }
+
+        #[test]
+        fn varint_round_trip(val in any::<u32>()) {
+            let mut buf = [0u8; 5];
+            let len = write_varint(val, &mut buf);
+            prop_assert_eq!(read_varint(&buf[..len]), Some((val, len)));
+        }
+
+        #[test]
+        fn decoder_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+            for _ in Frames::new(&bytes) {}
+        }
+    }
+
+    #[test]
+    fn delta_sample_is_eight_bytes() {
+        let mut enc = Encoder::new();
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let sample = |timestamp| {
+            Record::Sample(Sample {
+                timestamp,
+                co2_ppm: 650,
+                temp_cdeg: 2150,
+                rh_cpct: 4000,
+            })
+        };
+        assert_eq!(enc.encode(&sample(1_614_000_000), &mut buf), 11);
+        assert_eq!(enc.encode(&sample(1_614_000_060), &mut buf), 8);
+    }
+
+    #[test]
+    fn delta_without_base_is_rejected() {
+        let mut enc = Encoder::new();
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let event = |timestamp| Record::Event {
+            timestamp,
+            kind: EventKind::Boot,
+            arg: 0,
+        };
+        enc.encode(&event(100), &mut buf);
+        let len = enc.encode(&event(160), &mut buf);
+        assert_eq!(Decoder::new().decode(&buf[..len]), Err(DecodeError::NoBase));
+    }
+
+    #[test]
+    fn exports() {
+        let sample = Sample {
+            timestamp: 1_614_000_000,
+            co2_ppm: 812,
+            temp_cdeg: -125,
+            rh_cpct: 4550,
+        };
+        let rec = Record::Sample(sample);
+
+        let mut csv = Vec::new();
+        export::write_csv(&mut csv, &rec).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "1614000000,sample,812,-1.25,45.50,,,-11.55,2.02,-4.13\n"
+        );
+
+        let mut json = Vec::new();
+        export::write_json(&mut json, &rec).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"timestamp\":1614000000,\"kind\":\"sample\",\
+             \"co2_ppm\":812,\"temp_c\":-1.25,\"rh_pct\":45.50,\
+             \"dew_point_c\":-11.55,\"abs_humidity_g_m3\":2.02,\"heat_index_c\":-4.13}\n"
+        );
+
+        // Bone dry air has no dew point
+        let dry = Record::Sample(Sample {
+            rh_cpct: 0,
+            ..sample
+        });
+        let mut csv = Vec::new();
+        export::write_csv(&mut csv, &dry).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "1614000000,sample,812,-1.25,0.00,,,,,\n"
+        );
+
+        let event = Record::Event {
+            timestamp: 1_614_000_000,
+            kind: EventKind::Boot,
+            arg: 1,
+        };
+        let mut csv = Vec::new();
+        export::write_csv(&mut csv, &event).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "1614000000,event,,,,boot,1,,,\n"
+        );
+        assert_eq!(CSV_HEADER.split(',').count(), 10);
+    }
+}
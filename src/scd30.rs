@@ -0,0 +1,235 @@
+//! SCD30 configuration
+//!
+//! The `sensor-scd30` driver only covers taking measurements, so this
+//! speaks the SCD30's I2C command set directly for everything else. It
+//! can share the bus with the driver through a separate bus proxy.
+
+use embedded_hal::blocking::{
+    delay::DelayMs,
+    i2c::{Read, Write},
+};
+
+use crate::{
+    config::{Scd30Settings, FRC_RANGE},
+    crc::crc8_sensirion,
+};
+
+/// 7-bit I2C address of the SCD30
+pub const ADDRESS: u8 = 0x61;
+
+mod cmd {
+    pub const START_CONTINUOUS: u16 = 0x0010;
+    pub const MEASUREMENT_INTERVAL: u16 = 0x4600;
+    pub const ALTITUDE: u16 = 0x5102;
+    pub const TEMPERATURE_OFFSET: u16 = 0x5403;
+    pub const AUTO_SELF_CALIBRATION: u16 = 0x5306;
+    pub const FORCED_RECALIBRATION: u16 = 0x5204;
+}
+
+/// Time the sensor needs between a command and reading its response
+const RESPONSE_DELAY_MS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error<E> {
+    I2c(E),
+    /// The response from the sensor failed its CRC check
+    Crc,
+    /// A setting was outside of the range accepted by the sensor
+    OutOfRange,
+    /// The sensor did not take on the value we gave it
+    Rejected,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::I2c(e)
+    }
+}
+
+pub struct Scd30Config<I> {
+    i2c: I,
+}
+
+impl<I, E> Scd30Config<I>
+where
+    I: Write<Error = E> + Read<Error = E>,
+{
+    pub fn new(i2c: I) -> Self {
+        Self { i2c }
+    }
+
+    pub fn free(self) -> I {
+        self.i2c
+    }
+
+    /// Apply the given settings, and (re)start continuous measurement.
+    ///
+    /// The interval, altitude, temperature offset and ASC state are stored
+    /// in the sensor's own non-volatile memory, so they are read back first and
+    /// only written if they differ, sparing the sensor a write every boot.
+    pub fn apply<D: DelayMs<u32>>(
+        &mut self,
+        settings: &Scd30Settings,
+        delay: &mut D,
+    ) -> Result<(), Error<E>> {
+        if !settings.is_valid() {
+            return Err(Error::OutOfRange);
+        }
+
+        let fields = [
+            (cmd::MEASUREMENT_INTERVAL, settings.interval_s),
+            (cmd::ALTITUDE, settings.altitude_m),
+            (cmd::TEMPERATURE_OFFSET, settings.temp_offset_cdeg),
+            (cmd::AUTO_SELF_CALIBRATION, settings.asc_enabled as u16),
+        ];
+
+        for (command, value) in fields.iter() {
+            if self.read_register(*command, delay)? != *value {
+                defmt::info!("SCD30: setting 0x{:x} to {:?}", command, value);
+                self.write_command(*command, Some(*value))?;
+                delay.delay_ms(RESPONSE_DELAY_MS);
+            }
+        }
+
+        // The pressure can only be given as the argument to "start
+        // continuous measurement", so there is nothing to read back.
+        self.write_command(cmd::START_CONTINUOUS, Some(settings.pressure_mbar))?;
+        delay.delay_ms(RESPONSE_DELAY_MS);
+
+        Ok(())
+    }
+
+    /// Read back the settings the sensor is currently using, and check
+    /// they match what we expect. Mismatches are logged.
+    pub fn verify<D: DelayMs<u32>>(
+        &mut self,
+        expected: &Scd30Settings,
+        delay: &mut D,
+    ) -> Result<bool, Error<E>> {
+        let interval_s = self.interval(delay)?;
+        let altitude_m = self.altitude(delay)?;
+        let temp_offset_cdeg = self.temp_offset(delay)?;
+        let asc_enabled = self.asc_enabled(delay)?;
+
+        let mut ok = true;
+        if interval_s != expected.interval_s {
+            defmt::warn!(
+                "SCD30 interval: {:?}s, expected {:?}s",
+                interval_s,
+                expected.interval_s
+            );
+            ok = false;
+        }
+        if altitude_m != expected.altitude_m {
+            defmt::warn!(
+                "SCD30 altitude: {:?}m, expected {:?}m",
+                altitude_m,
+                expected.altitude_m
+            );
+            ok = false;
+        }
+        if temp_offset_cdeg != expected.temp_offset_cdeg {
+            defmt::warn!(
+                "SCD30 temp offset: {:?}, expected {:?}",
+                temp_offset_cdeg,
+                expected.temp_offset_cdeg
+            );
+            ok = false;
+        }
+        if asc_enabled != expected.asc_enabled {
+            defmt::warn!(
+                "SCD30 ASC: {:?}, expected {:?}",
+                asc_enabled,
+                expected.asc_enabled
+            );
+            ok = false;
+        }
+
+        Ok(ok)
+    }
+
+    /// Enable or disable automatic self-calibration
+    pub fn set_asc<D: DelayMs<u32>>(
+        &mut self,
+        enabled: bool,
+        delay: &mut D,
+    ) -> Result<(), Error<E>> {
+        self.write_command(cmd::AUTO_SELF_CALIBRATION, Some(enabled as u16))?;
+        delay.delay_ms(RESPONSE_DELAY_MS);
+        Ok(())
+    }
+
+    /// Calibrate the sensor against a known CO2 concentration, in ppm.
+    ///
+    /// The sensor must have been measuring continuously for at least two
+    /// minutes in a stable environment at the reference concentration
+    /// (e.g. outside, for 420ppm), or the calibration will be off.
+    pub fn force_recalibration<D: DelayMs<u32>>(
+        &mut self,
+        reference_ppm: u16,
+        delay: &mut D,
+    ) -> Result<(), Error<E>> {
+        if !FRC_RANGE.contains(&reference_ppm) {
+            return Err(Error::OutOfRange);
+        }
+
+        self.write_command(cmd::FORCED_RECALIBRATION, Some(reference_ppm))?;
+        delay.delay_ms(RESPONSE_DELAY_MS);
+
+        // The sensor reports back the reference value it was given
+        if self.read_register(cmd::FORCED_RECALIBRATION, delay)? != reference_ppm {
+            return Err(Error::Rejected);
+        }
+        Ok(())
+    }
+
+    /// Continuous measurement interval, in seconds
+    pub fn interval<D: DelayMs<u32>>(&mut self, delay: &mut D) -> Result<u16, Error<E>> {
+        self.read_register(cmd::MEASUREMENT_INTERVAL, delay)
+    }
+
+    /// Altitude compensation, in meters above sea level
+    pub fn altitude<D: DelayMs<u32>>(&mut self, delay: &mut D) -> Result<u16, Error<E>> {
+        self.read_register(cmd::ALTITUDE, delay)
+    }
+
+    /// Is automatic self-calibration enabled?
+    pub fn asc_enabled<D: DelayMs<u32>>(&mut self, delay: &mut D) -> Result<bool, Error<E>> {
+        Ok(self.read_register(cmd::AUTO_SELF_CALIBRATION, delay)? != 0)
+    }
+
+    /// Temperature offset, in 0.01 degrees C
+    pub fn temp_offset<D: DelayMs<u32>>(&mut self, delay: &mut D) -> Result<u16, Error<E>> {
+        self.read_register(cmd::TEMPERATURE_OFFSET, delay)
+    }
+
+    fn write_command(&mut self, command: u16, arg: Option<u16>) -> Result<(), Error<E>> {
+        let [ch, cl] = command.to_be_bytes();
+        match arg {
+            Some(arg) => {
+                let [ah, al] = arg.to_be_bytes();
+                let crc = crc8_sensirion(&[ah, al]);
+                self.i2c.write(ADDRESS, &[ch, cl, ah, al, crc])?;
+            }
+            None => self.i2c.write(ADDRESS, &[ch, cl])?,
+        }
+        Ok(())
+    }
+
+    fn read_register<D: DelayMs<u32>>(
+        &mut self,
+        command: u16,
+        delay: &mut D,
+    ) -> Result<u16, Error<E>> {
+        self.write_command(command, None)?;
+        delay.delay_ms(RESPONSE_DELAY_MS);
+
+        let mut buf = [0u8; 3];
+        self.i2c.read(ADDRESS, &mut buf)?;
+
+        if crc8_sensirion(&buf[..2]) != buf[2] {
+            return Err(Error::Crc);
+        }
+        Ok(u16::from_be_bytes([buf[0], buf[1]]))
+    }
+}
@@ -0,0 +1,222 @@
+//! Whether CO2 is rising or falling, and how soon it will need attention
+//!
+//! The rate of change is the slope of a least squares line through the
+//! last [`WINDOW_S`] of samples. With the SCD30's noise of about 30 ppm,
+//! this is much steadier than comparing the first and last readings. While
+//! CO2 is rising, the line is extended to the next alert threshold, to
+//! give a chance to open a window before the alert goes off.
+
+use crate::{alert::AlertConfig, history::History};
+
+/// How far back the line is fitted, in seconds
+pub const WINDOW_S: u32 = 15 * 60;
+
+/// Fewer samples than this in the window give no trend at all
+pub const MIN_SAMPLES: i64 = 5;
+
+/// Rates closer to zero than this count as steady, in 0.01 ppm/min
+pub const STEADY_CPPM_MIN: i32 = 200;
+
+/// Projections further out than this are too uncertain to act on, in
+/// minutes. It also keeps them to two digits on the display.
+pub const HORIZON_MIN: u32 = 99;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum Direction {
+    Rising,
+    Steady,
+    Falling,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Trend {
+    /// Rate of change, in 0.01 ppm/min
+    pub rate_cppm_min: i32,
+    /// Where the line is now, in ppm. Less noisy than the latest reading.
+    pub level_ppm: i32,
+}
+
+/// When CO2 will reach the next alert threshold, if it keeps rising
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Projection {
+    pub threshold_ppm: u16,
+    /// At least one
+    pub minutes: u32,
+}
+
+impl Trend {
+    /// Fit the samples from the last [`WINDOW_S`] before `now`
+    pub fn analyse(history: &History, now: u32) -> Option<Self> {
+        let from = now.saturating_sub(WINDOW_S);
+
+        // Times are relative to the start of the window, to keep the sums
+        // small
+        let (mut n, mut sx, mut sy, mut sxx, mut sxy) = (0i64, 0i64, 0i64, 0i64, 0i64);
+        for sample in history.samples(from, now.saturating_add(1)) {
+            let x = (sample.timestamp - from) as i64;
+            let y = sample.co2_ppm as i64;
+            n += 1;
+            sx += x;
+            sy += y;
+            sxx += x * x;
+            sxy += x * y;
+        }
+
+        let denom = n * sxx - sx * sx;
+        if n < MIN_SAMPLES || denom == 0 {
+            return None;
+        }
+
+        // The slope is num / denom in ppm/s
+        let num = n * sxy - sx * sy;
+        let x_now = (now - from) as i64;
+        let rate = round_div(num * 60 * 100, denom);
+        let level = round_div(sy * denom + num * (n * x_now - sx), n * denom);
+
+        let clamp = |v: i64| v.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+        Some(Self {
+            rate_cppm_min: clamp(rate),
+            level_ppm: clamp(level),
+        })
+    }
+
+    pub fn direction(&self) -> Direction {
+        if self.rate_cppm_min >= STEADY_CPPM_MIN {
+            Direction::Rising
+        } else if self.rate_cppm_min <= -STEADY_CPPM_MIN {
+            Direction::Falling
+        } else {
+            Direction::Steady
+        }
+    }
+
+    /// When the next threshold above the current level will be crossed.
+    /// `None` if not rising, already past the last threshold, or further
+    /// out than [`HORIZON_MIN`].
+    pub fn projection(&self, cfg: &AlertConfig) -> Option<Projection> {
+        if self.direction() != Direction::Rising {
+            return None;
+        }
+        let threshold_ppm = cfg
+            .thresholds_ppm
+            .iter()
+            .copied()
+            .find(|t| *t as i32 > self.level_ppm)?;
+
+        let rise_cppm = (threshold_ppm as i64 - self.level_ppm as i64) * 100;
+        let minutes = round_div(rise_cppm, self.rate_cppm_min as i64).max(1) as u32;
+        if minutes > HORIZON_MIN {
+            return None;
+        }
+        Some(Projection {
+            threshold_ppm,
+            minutes,
+        })
+    }
+}
+
+/// `n / d` for positive `d`, rounded half away from zero
+fn round_div(n: i64, d: i64) -> i64 {
+    if n < 0 {
+        (n - d / 2) / d
+    } else {
+        (n + d / 2) / d
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::history::Sample;
+
+    const START: u32 = 1_614_000_000;
+
+    /// One sample a minute, ending at the returned time
+    fn history(co2: &[u16]) -> (History, u32) {
+        let mut history = History::new();
+        let mut now = START;
+        for (i, co2_ppm) in co2.iter().enumerate() {
+            now = START + i as u32 * 60;
+            history.push(Sample {
+                timestamp: now,
+                co2_ppm: *co2_ppm,
+                temp_cdeg: 2100,
+                rh_cpct: 4000,
+            });
+        }
+        (history, now)
+    }
+
+    #[test]
+    fn rates() {
+        // 20 ppm/min, the last sample is the level
+        let rising: Vec<u16> = (0..20).map(|i| 600 + i * 20).collect();
+        let (h, now) = history(&rising);
+        let trend = Trend::analyse(&h, now).unwrap();
+        assert_eq!(trend.rate_cppm_min, 2000);
+        assert_eq!(trend.level_ppm, 980);
+        assert_eq!(trend.direction(), Direction::Rising);
+
+        // A minute later, with no new sample, the line carries on
+        let trend = Trend::analyse(&h, now + 60).unwrap();
+        assert_eq!(trend.level_ppm, 1000);
+
+        let (h, now) = history(&[900, 880, 860, 840, 820, 800]);
+        let trend = Trend::analyse(&h, now).unwrap();
+        assert_eq!(trend.rate_cppm_min, -2000);
+        assert_eq!(trend.direction(), Direction::Falling);
+
+        // Noise around a steady level
+        let (h, now) = history(&[700, 730, 690, 720, 680, 710, 700, 690, 720]);
+        let trend = Trend::analyse(&h, now).unwrap();
+        assert_eq!(trend.direction(), Direction::Steady);
+        assert!((trend.level_ppm - 705).abs() < 15, "{:?}", trend);
+
+        let (h, now) = history(&[700, 710, 720, 730]);
+        assert_eq!(Trend::analyse(&h, now), None);
+        assert_eq!(Trend::analyse(&History::new(), START), None);
+    }
+
+    #[test]
+    fn projections() {
+        let cfg = AlertConfig::default();
+        let trend = |rate_cppm_min, level_ppm| Trend {
+            rate_cppm_min,
+            level_ppm,
+        };
+
+        // 10 ppm/min from 880 reaches 1000 in 12 minutes
+        assert_eq!(
+            trend(1000, 880).projection(&cfg),
+            Some(Projection {
+                threshold_ppm: 1000,
+                minutes: 12,
+            })
+        );
+        // Past the first threshold, on to the next
+        assert_eq!(
+            trend(2500, 1200).projection(&cfg),
+            Some(Projection {
+                threshold_ppm: 1400,
+                minutes: 8,
+            })
+        );
+        // About to cross
+        assert_eq!(trend(5000, 999).projection(&cfg).unwrap().minutes, 1);
+
+        // Too slow, not rising or nothing left to cross
+        assert_eq!(trend(300, 600).projection(&cfg), None);
+        assert_eq!(trend(100, 990).projection(&cfg), None);
+        assert_eq!(trend(-1000, 900).projection(&cfg), None);
+        assert_eq!(trend(5000, 2100).projection(&cfg), None);
+
+        // From the samples
+        let rising: Vec<u16> = (0..10).map(|i| 700 + i * 10).collect();
+        let (h, now) = history(&rising);
+        let projection = Trend::analyse(&h, now).unwrap().projection(&cfg);
+        assert_eq!(projection.map(|p| p.minutes), Some(21));
+    }
+}
@@ -0,0 +1,188 @@
+//! How temperatures and the time of day are shown
+//!
+//! The preferences are kept in the config and applied by the screens. The
+//! data log, the console and BLE always use Celsius and 24 hour time, as
+//! they are mostly read by programs.
+
+/// Unit for showing temperatures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+#[repr(u8)]
+pub enum TemperatureUnit {
+    Celsius = 0,
+    Fahrenheit = 1,
+}
+
+impl TemperatureUnit {
+    pub fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            0 => Some(TemperatureUnit::Celsius),
+            1 => Some(TemperatureUnit::Fahrenheit),
+            _ => None,
+        }
+    }
+
+    /// Convert hundredths of a degree Celsius to hundredths of a degree in
+    /// this unit, rounded half away from zero
+    pub fn from_centi_celsius(self, centi: i32) -> i32 {
+        match self {
+            TemperatureUnit::Celsius => centi,
+            TemperatureUnit::Fahrenheit => {
+                let scaled = centi as i64 * 9;
+                let rounded = if scaled < 0 {
+                    (scaled - 2) / 5
+                } else {
+                    (scaled + 2) / 5
+                };
+                (rounded + 3200).clamp(i32::MIN as i64, i32::MAX as i64) as i32
+            }
+        }
+    }
+
+    pub fn symbol(self) -> &'static [u8] {
+        match self {
+            TemperatureUnit::Celsius => b"C",
+            TemperatureUnit::Fahrenheit => b"F",
+        }
+    }
+}
+
+/// Format for showing the time of day
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+#[repr(u8)]
+pub enum ClockFormat {
+    /// 00:00 to 23:59
+    H24 = 0,
+    /// 12:00 AM to 11:59 PM
+    H12 = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum Meridiem {
+    Am,
+    Pm,
+}
+
+impl ClockFormat {
+    pub fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            0 => Some(ClockFormat::H24),
+            1 => Some(ClockFormat::H12),
+            _ => None,
+        }
+    }
+
+    /// The hour as shown, with AM or PM in 12 hour format
+    pub fn hour(self, hour24: u8) -> (u8, Option<Meridiem>) {
+        match self {
+            ClockFormat::H24 => (hour24, None),
+            ClockFormat::H12 => {
+                let meridiem = if hour24 < 12 {
+                    Meridiem::Am
+                } else {
+                    Meridiem::Pm
+                };
+                let hour = match hour24 % 12 {
+                    0 => 12,
+                    hour => hour,
+                };
+                (hour, Some(meridiem))
+            }
+        }
+    }
+
+    /// Four ASCII digits, "0930" in 24 hour format and " 930" in 12 hour
+    /// format
+    pub fn digits(self, hour24: u8, minute: u8) -> [u8; 4] {
+        let (hour, _) = self.hour(hour24);
+        let tens = match (self, hour / 10) {
+            (ClockFormat::H12, 0) => b' ',
+            (_, tens) => b'0' + tens,
+        };
+        [
+            tens,
+            b'0' + hour % 10,
+            b'0' + minute / 10,
+            b'0' + minute % 10,
+        ]
+    }
+
+    /// For displays that can show text: "09:30" in 24 hour format and
+    /// " 9:30 PM" in 12 hour format, padded with spaces
+    pub fn text(self, hour24: u8, minute: u8) -> [u8; 8] {
+        let [h0, h1, m0, m1] = self.digits(hour24, minute);
+        let mut text = *b"        ";
+        text[..5].copy_from_slice(&[h0, h1, b':', m0, m1]);
+        match self.hour(hour24) {
+            (_, Some(Meridiem::Am)) => text[5..].copy_from_slice(b" AM"),
+            (_, Some(Meridiem::Pm)) => text[5..].copy_from_slice(b" PM"),
+            (_, None) => {}
+        }
+        text
+    }
+}
+
+/// The display preferences, kept in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct UnitsConfig {
+    pub temperature: TemperatureUnit,
+    pub clock: ClockFormat,
+}
+
+impl Default for UnitsConfig {
+    fn default() -> Self {
+        Self {
+            temperature: TemperatureUnit::Celsius,
+            clock: ClockFormat::H24,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temperatures() {
+        let f = |centi| TemperatureUnit::Fahrenheit.from_centi_celsius(centi);
+        assert_eq!(f(0), 3200);
+        assert_eq!(f(10_000), 21_200);
+        assert_eq!(f(-4000), -4000);
+        assert_eq!(f(7000), 15_800);
+        // 22.22C is 71.996F
+        assert_eq!(f(2222), 7200);
+        // -17.79C is -0.022F
+        assert_eq!(f(-1779), -2);
+        assert_eq!(f(i32::MAX), i32::MAX);
+
+        assert_eq!(TemperatureUnit::Celsius.from_centi_celsius(-1234), -1234);
+        assert_eq!(
+            TemperatureUnit::from_u8(1),
+            Some(TemperatureUnit::Fahrenheit)
+        );
+        assert_eq!(TemperatureUnit::from_u8(2), None);
+    }
+
+    #[test]
+    fn clock() {
+        let h12 = ClockFormat::H12;
+        assert_eq!(h12.hour(0), (12, Some(Meridiem::Am)));
+        assert_eq!(h12.hour(9), (9, Some(Meridiem::Am)));
+        assert_eq!(h12.hour(12), (12, Some(Meridiem::Pm)));
+        assert_eq!(h12.hour(23), (11, Some(Meridiem::Pm)));
+        assert_eq!(ClockFormat::H24.hour(23), (23, None));
+
+        assert_eq!(&h12.digits(0, 5), b"1205");
+        assert_eq!(&h12.digits(9, 30), b" 930");
+        assert_eq!(&h12.digits(21, 30), b" 930");
+        assert_eq!(&ClockFormat::H24.digits(9, 30), b"0930");
+        assert_eq!(&ClockFormat::H24.digits(21, 30), b"2130");
+
+        assert_eq!(&h12.text(0, 5), b"12:05 AM");
+        assert_eq!(&h12.text(21, 30), b" 9:30 PM");
+        assert_eq!(&ClockFormat::H24.text(21, 30), b"21:30   ");
+    }
+}
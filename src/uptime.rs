@@ -0,0 +1,246 @@
+//! How long the clock has been running, since boot and in total
+//!
+//! Uptime since boot comes from `monotonic`. The total operating time is
+//! the sum over all boots, kept in a page of internal flash. It is stored
+//! every [`CHECKPOINT_MINUTES`] of uptime, using the same append-only
+//! scheme as the boot counters in `boot`, so the page is erased once
+//! every [`SLOTS`] checkpoints: about every 85 hours, or about a hundred
+//! times a year, well within the endurance of the flash. Up to one
+//! checkpoint interval is lost on every reset.
+
+use crate::{crc::crc32, flash::Flash};
+
+/// How often the total is stored, in minutes of uptime
+pub const CHECKPOINT_MINUTES: u32 = 15;
+
+/// "OPHR", little endian
+const MAGIC: u32 = 0x5248_504F;
+
+/// Magic, total minutes, CRC
+const SLOT_LEN: usize = 12;
+
+/// Checkpoints that fit in the page
+pub const SLOTS: usize = 4096 / SLOT_LEN;
+
+/// Total time spent running, over all boots
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct OperatingTime {
+    /// Stored total at boot, in minutes
+    base_min: u32,
+    /// Uptime of the last checkpoint this boot, in minutes
+    checkpoint_min: u32,
+}
+
+impl OperatingTime {
+    /// Address of the flash page holding the checkpoints
+    pub const PAGE: u32 = 0x000F_C000;
+
+    /// Total minutes, given the uptime of this boot
+    pub fn total_min(&self, uptime_min: u32) -> u32 {
+        self.base_min.saturating_add(uptime_min)
+    }
+
+    fn to_bytes(total_min: u32) -> [u8; SLOT_LEN] {
+        let mut buf = [0; SLOT_LEN];
+        buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&total_min.to_le_bytes());
+        let crc = crc32(&buf[..8]);
+        buf[8..12].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; SLOT_LEN]) -> Option<u32> {
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+
+        if u32_at(0) != MAGIC || u32_at(8) != crc32(&buf[..8]) {
+            return None;
+        }
+        Some(u32_at(4))
+    }
+
+    /// The newest stored total, if any, and the first free slot
+    fn find<F: Flash>(flash: &mut F) -> Result<(Option<u32>, Option<usize>), F::Error> {
+        let mut newest = None;
+        let mut buf = [0; SLOT_LEN];
+        for slot in 0..SLOTS {
+            flash.read(Self::PAGE + (slot * SLOT_LEN) as u32, &mut buf)?;
+            if buf.iter().all(|b| *b == 0xFF) {
+                return Ok((newest, Some(slot)));
+            }
+            // A torn write is skipped, the checkpoint before it still counts
+            if let Some(total_min) = Self::from_bytes(&buf) {
+                newest = Some(total_min);
+            }
+        }
+        Ok((newest, None))
+    }
+
+    /// Carry on from the stored total, or from zero if there isn't one
+    pub fn load<F: Flash>(flash: &mut F) -> Result<Self, F::Error> {
+        let (newest, _) = Self::find(flash)?;
+        Ok(Self {
+            base_min: newest.unwrap_or(0),
+            checkpoint_min: 0,
+        })
+    }
+
+    /// Store the total now
+    pub fn store<F: Flash>(&mut self, flash: &mut F, uptime_min: u32) -> Result<(), F::Error> {
+        let (_, free) = Self::find(flash)?;
+        let slot = match free {
+            Some(slot) => slot,
+            None => {
+                flash.erase(Self::PAGE)?;
+                0
+            }
+        };
+        let buf = Self::to_bytes(self.total_min(uptime_min));
+        flash.write(Self::PAGE + (slot * SLOT_LEN) as u32, &buf)?;
+        self.checkpoint_min = uptime_min;
+        Ok(())
+    }
+
+    /// Store the total if a checkpoint is due. Returns whether it was
+    /// stored. Call this every minute or so.
+    pub fn checkpoint<F: Flash>(
+        &mut self,
+        flash: &mut F,
+        uptime_min: u32,
+    ) -> Result<bool, F::Error> {
+        if uptime_min < self.checkpoint_min.saturating_add(CHECKPOINT_MINUTES) {
+            return Ok(false);
+        }
+        self.store(flash, uptime_min)?;
+        Ok(true)
+    }
+}
+
+/// Unit of a [`Readout`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum Unit {
+    Hours,
+    Days,
+}
+
+/// A duration scaled to fit three digits and a unit on the four digit
+/// display, e.g. "12.3h" or "456d"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Readout {
+    /// Four digits, the last of which is covered by the unit
+    pub digits: u16,
+    /// Whether there is a decimal point after the second digit
+    pub dot: bool,
+    pub unit: Unit,
+}
+
+/// Hours up to 999, then days up to 999, with a decimal place below 100
+/// of either. `None` beyond that.
+pub fn readout(minutes: u32) -> Option<Readout> {
+    let readout = |digits: u32, dot, unit| Readout {
+        digits: digits as u16,
+        dot,
+        unit,
+    };
+
+    // The limits are where the shown value would need another digit: up
+    // to 99.9, then up to 999
+    if minutes < 100 * 60 {
+        // Hundredths of an hour
+        Some(readout(minutes * 100 / 60, true, Unit::Hours))
+    } else if minutes < 1000 * 60 {
+        // Tenths of an hour
+        Some(readout(minutes / 6, false, Unit::Hours))
+    } else if minutes < 100 * 1440 {
+        Some(readout(minutes * 100 / 1440, true, Unit::Days))
+    } else if minutes < 1000 * 1440 {
+        Some(readout(minutes / 144, false, Unit::Days))
+    } else {
+        None
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::flash::MemFlash;
+
+    fn blank() -> MemFlash {
+        MemFlash::new(vec![0xFFu8; 0x0010_0000])
+    }
+
+    #[test]
+    fn readouts() {
+        let hours = |digits, dot| {
+            Some(Readout {
+                digits,
+                dot,
+                unit: Unit::Hours,
+            })
+        };
+        let days = |digits, dot| {
+            Some(Readout {
+                digits,
+                dot,
+                unit: Unit::Days,
+            })
+        };
+
+        assert_eq!(readout(0), hours(0, true));
+        // 0.1h is six minutes
+        assert_eq!(readout(6), hours(10, true));
+        assert_eq!(readout(90), hours(150, true));
+        assert_eq!(readout(100 * 60 - 1), hours(9998, true));
+        assert_eq!(readout(100 * 60), hours(1000, false));
+        assert_eq!(readout(1000 * 60 - 1), hours(9999, false));
+        assert_eq!(readout(1000 * 60), days(4166, true));
+        assert_eq!(readout(100 * 1440 - 1), days(9999, true));
+        assert_eq!(readout(100 * 1440), days(1000, false));
+        assert_eq!(readout(1000 * 1440 - 1), days(9999, false));
+        assert_eq!(readout(1000 * 1440), None);
+        assert_eq!(readout(u32::MAX), None);
+    }
+
+    #[test]
+    fn checkpoints() {
+        let mut flash = blank();
+        let mut time = OperatingTime::load(&mut flash).unwrap();
+        assert_eq!(time.total_min(5), 5);
+
+        assert!(!time.checkpoint(&mut flash, 14).unwrap());
+        assert!(time.checkpoint(&mut flash, 15).unwrap());
+        assert!(!time.checkpoint(&mut flash, 29).unwrap());
+        assert!(time.checkpoint(&mut flash, 31).unwrap());
+
+        // Reboot, losing the minutes since the last checkpoint
+        let mut time = OperatingTime::load(&mut flash).unwrap();
+        assert_eq!(time.total_min(0), 31);
+        assert_eq!(time.total_min(10), 41);
+
+        time.store(&mut flash, 3).unwrap();
+        assert_eq!(OperatingTime::load(&mut flash).unwrap().total_min(0), 34);
+    }
+
+    #[test]
+    fn wraps_around_the_page() {
+        let mut flash = blank();
+        let mut time = OperatingTime::load(&mut flash).unwrap();
+        for i in 1..=SLOTS as u32 {
+            time.store(&mut flash, i).unwrap();
+        }
+        let (newest, free) = OperatingTime::find(&mut flash).unwrap();
+        assert_eq!((newest, free), (Some(SLOTS as u32), None));
+
+        time.store(&mut flash, 1000).unwrap();
+        let (newest, free) = OperatingTime::find(&mut flash).unwrap();
+        assert_eq!((newest, free), (Some(1000), Some(1)));
+
+        // Power lost part way through the next checkpoint
+        flash
+            .write(OperatingTime::PAGE + SLOT_LEN as u32, &[0x4F, 0x50])
+            .unwrap();
+        assert_eq!(OperatingTime::load(&mut flash).unwrap().total_min(0), 1000);
+    }
+}
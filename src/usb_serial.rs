@@ -0,0 +1,142 @@
+//! USB CDC-ACM serial port
+//!
+//! The USB stack has to answer the host within a few milliseconds, which
+//! the main loop can't promise while it is showing screens, so the device
+//! is polled from the USBD interrupt. Received bytes are buffered until the
+//! main loop asks for them.
+//!
+//! Writes are best effort: if no terminal has the port open, or the host
+//! hasn't yet read enough of the earlier output to make room, output is
+//! dropped rather than stalling the clock.
+
+use core::{cell::RefCell, fmt};
+
+use cortex_m::interrupt::{self, Mutex};
+use nrf52840_hal::{
+    clocks::{Clocks, ExternalOscillator, LfOscStarted},
+    pac::USBD,
+    usbd::Usbd,
+};
+use usb_device::{
+    bus::UsbBusAllocator,
+    device::{UsbDevice, UsbDeviceBuilder, UsbVidPid},
+};
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
+
+use crate::identity::{DeviceId, DEVICE_ID_LEN};
+
+/// Shared VID/PID for CDC-ACM devices
+const VID_PID: UsbVidPid = UsbVidPid(0x16c0, 0x27dd);
+
+/// Bytes received from the host that the main loop hasn't read yet
+const RX_SIZE: usize = 64;
+
+type Bus = Usbd<'static>;
+
+/// The clocks the USB peripheral needs, external HFXO and a running LFCLK
+pub type UsbClocks = Clocks<ExternalOscillator, ExternalOscillator, LfOscStarted>;
+
+struct State {
+    device: UsbDevice<'static, Bus>,
+    serial: SerialPort<'static, Bus>,
+    rx: [u8; RX_SIZE],
+    rx_len: usize,
+}
+
+static STATE: Mutex<RefCell<Option<State>>> = Mutex::new(RefCell::new(None));
+
+/// Set up the USB device, reporting the device ID as its serial number.
+/// The caller must unmask the USBD interrupt, and call [`poll`] from it.
+pub fn init(usbd: USBD, clocks: &'static UsbClocks, device_id: DeviceId) {
+    let alloc: &'static UsbBusAllocator<Bus> =
+        cortex_m::singleton!(: UsbBusAllocator<Bus> = Usbd::new(usbd, clocks)).unwrap();
+    let serial_number: &'static [u8; DEVICE_ID_LEN] =
+        cortex_m::singleton!(: [u8; DEVICE_ID_LEN] = device_id.to_hex()).unwrap();
+
+    let serial = SerialPort::new(alloc);
+    let device = UsbDeviceBuilder::new(alloc, VID_PID)
+        .manufacturer("Ferrous Systems")
+        .product("fleet-clock")
+        .serial_number(core::str::from_utf8(serial_number).unwrap_or("unknown"))
+        .device_class(USB_CLASS_CDC)
+        .max_packet_size_0(64)
+        .build();
+
+    interrupt::free(|cs| {
+        STATE.borrow(cs).replace(Some(State {
+            device,
+            serial,
+            rx: [0; RX_SIZE],
+            rx_len: 0,
+        }));
+    });
+}
+
+/// Service the USB device. Call this from the USBD interrupt.
+pub fn poll() {
+    interrupt::free(|cs| {
+        if let Some(state) = STATE.borrow(cs).borrow_mut().as_mut() {
+            if !state.device.poll(&mut [&mut state.serial]) {
+                return;
+            }
+
+            // Leave anything that doesn't fit with the serial port, the
+            // host will be NAKed until there is room
+            let free = &mut state.rx[state.rx_len..];
+            if !free.is_empty() {
+                if let Ok(n) = state.serial.read(free) {
+                    state.rx_len += n;
+                }
+            }
+        }
+    });
+}
+
+/// Take bytes received from the host, returning how many were copied
+pub fn read(buf: &mut [u8]) -> usize {
+    interrupt::free(|cs| match STATE.borrow(cs).borrow_mut().as_mut() {
+        Some(state) => {
+            let n = buf.len().min(state.rx_len);
+            buf[..n].copy_from_slice(&state.rx[..n]);
+            state.rx.copy_within(n..state.rx_len, 0);
+            state.rx_len -= n;
+            n
+        }
+        None => 0,
+    })
+}
+
+/// Is a terminal attached? i.e. has the host set DTR
+pub fn is_open() -> bool {
+    interrupt::free(|cs| match STATE.borrow(cs).borrow().as_ref() {
+        Some(state) => state.serial.dtr(),
+        None => false,
+    })
+}
+
+/// Send bytes to the host, best effort. Whatever doesn't fit in the serial
+/// port's buffer is dropped; the USBD interrupt sends the buffer on as the
+/// host reads it.
+pub fn write(mut data: &[u8]) {
+    interrupt::free(|cs| {
+        if let Some(state) = STATE.borrow(cs).borrow_mut().as_mut() {
+            while !data.is_empty() && state.serial.dtr() {
+                match state.serial.write(data) {
+                    Ok(n) if n > 0 => data = &data[n..],
+                    // Full, or the endpoint is busy
+                    _ => return,
+                }
+            }
+        }
+    });
+}
+
+/// Formatted output to the USB serial port
+pub struct UsbWriter;
+
+impl fmt::Write for UsbWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write(s.as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+//! Watchdog supervision of the main loop's tasks
+//!
+//! The WDT has eight reload request channels, and only reloads once every
+//! enabled channel has been written. Each [`Task`] gets a channel of its
+//! own and must check in regularly, so a task that stops making progress
+//! resets the chip even while the main loop keeps spinning. The
+//! [`Supervisor`] keeps track of when each task last checked in, and a
+//! channel is only written while its task is alive.
+//!
+//! Most tasks check in on every pass through the main loop, so they are
+//! allowed one watchdog timeout between check-ins. The sensor is only
+//! polled once a minute, so it gets that much longer. The configuration
+//! makes sure the timeout covers the longest pass, screens and all, see
+//! `SystemConfig::longest_pass_ms`. Work that would take longer, like
+//! dumping the whole data log, is spread over several passes.
+
+use crate::monotonic::{Duration, Instant};
+
+/// Number of supervised tasks, one WDT channel each
+pub const TASKS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub enum Task {
+    /// Reading the time from the RTC
+    Clock = 0,
+    /// Polling the SCD30
+    Sensor = 1,
+    /// Updating the display
+    Display = 2,
+    /// Servicing the console
+    Comms = 3,
+}
+
+impl Task {
+    pub const ALL: [Task; TASKS] = [Task::Clock, Task::Sensor, Task::Display, Task::Comms];
+
+    /// How often the task checks in when all is well, on top of the time
+    /// a pass through the main loop takes
+    pub fn period(self) -> Duration {
+        match self {
+            Task::Sensor => Duration::from_secs(60),
+            Task::Clock | Task::Display | Task::Comms => Duration::ZERO,
+        }
+    }
+}
+
+/// When each task last checked in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "firmware", derive(defmt::Format))]
+pub struct Supervisor {
+    last: [Instant; TASKS],
+    timeout: Duration,
+}
+
+impl Supervisor {
+    /// All tasks start out alive
+    pub fn new(now: Instant, timeout: Duration) -> Self {
+        Self {
+            last: [now; TASKS],
+            timeout,
+        }
+    }
+
+    pub fn check_in(&mut self, task: Task, now: Instant) {
+        self.last[task as usize] = now;
+    }
+
+    /// Whether the task has checked in recently enough
+    pub fn is_alive(&self, task: Task, now: Instant) -> bool {
+        now - self.last[task as usize] <= self.timeout + task.period()
+    }
+}
+
+#[cfg(feature = "firmware")]
+mod hardware {
+    use nrf52840_hal::{
+        pac::WDT,
+        wdt::{count, handles::HdlN, Watchdog as Wdt, WatchdogHandle},
+    };
+
+    use super::{Supervisor, Task, TASKS};
+    use crate::{
+        config::SystemConfig,
+        monotonic::{self, Duration},
+    };
+
+    /// The WDT, with a channel per task
+    pub struct Watchdog {
+        handles: [WatchdogHandle<HdlN>; TASKS],
+        supervisor: Supervisor,
+        /// Tasks already reported as stalled
+        stalled: [bool; TASKS],
+    }
+
+    impl Watchdog {
+        /// Start the WDT, or take over if it is already running (e.g. after
+        /// a soft reset). If it is running with a different number of
+        /// channels it can't be taken over, so this waits for it to bite.
+        pub fn start(wdt: WDT, config: &SystemConfig) -> Self {
+            let (clock, sensor, display, comms) = Wdt::try_new(wdt)
+                .map(|mut wdt| {
+                    wdt.set_lfosc_ticks(config.watchdog_ticks());
+                    wdt.activate::<count::Four>()
+                })
+                .or_else(Wdt::try_recover::<count::Four>)
+                .unwrap_or_else(|_| loop {
+                    cortex_m::asm::wfi()
+                })
+                .handles;
+
+            let mut watchdog = Self {
+                handles: [
+                    clock.degrade(),
+                    sensor.degrade(),
+                    display.degrade(),
+                    comms.degrade(),
+                ],
+                supervisor: Supervisor::new(
+                    monotonic::now(),
+                    Duration::from_secs(config.watchdog_timeout_s as u32),
+                ),
+                stalled: [false; TASKS],
+            };
+            watchdog.feed();
+            watchdog
+        }
+
+        pub fn check_in(&mut self, task: Task) {
+            self.supervisor.check_in(task, monotonic::now());
+            self.stalled[task as usize] = false;
+        }
+
+        /// Reload the channel of every task that is alive. Call this once
+        /// per pass through the main loop.
+        pub fn feed(&mut self) {
+            let now = monotonic::now();
+            for task in Task::ALL.iter().copied() {
+                let i = task as usize;
+                if self.supervisor.is_alive(task, now) {
+                    self.handles[i].pet();
+                } else if !self.stalled[i] {
+                    defmt::error!("{:?} task stalled, the watchdog will reset", task);
+                    self.stalled[i] = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "firmware")]
+pub use hardware::Watchdog;
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supervision() {
+        let timeout = Duration::from_secs(30);
+        let start = Instant::from_ticks(0);
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut supervisor = Supervisor::new(start, timeout);
+
+        for task in Task::ALL.iter().copied() {
+            assert!(supervisor.is_alive(task, at(30)));
+        }
+        assert!(!supervisor.is_alive(Task::Clock, at(31)));
+        assert!(supervisor.is_alive(Task::Sensor, at(90)));
+        assert!(!supervisor.is_alive(Task::Sensor, at(91)));
+
+        // Checking in keeps a task alive, and only that task
+        supervisor.check_in(Task::Display, at(20));
+        assert!(supervisor.is_alive(Task::Display, at(50)));
+        assert!(!supervisor.is_alive(Task::Comms, at(50)));
+
+        for (i, task) in Task::ALL.iter().enumerate() {
+            assert_eq!(*task as usize, i);
+        }
+    }
+}
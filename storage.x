@@ -0,0 +1,6 @@
+/* The top four pages of internal flash hold the operating time, boot
+   counts and config, see `nvmc::Nvmc::STORAGE_START`. The hal's memory.x
+   gives FLASH the whole 1MB, so check here that the image ends below them,
+   or storing a setting would erase part of the application. */
+ASSERT(__sidata + SIZEOF(.data) <= 0x000FC000,
+       "ERROR(fleet-clock): the image overlaps the storage pages at 0xFC000");